@@ -0,0 +1,327 @@
+#![cfg(feature = "python")]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::os::raw::c_char;
+use std::ptr;
+
+use cpython::{
+	Python, PyObject, PyResult, PyErr, PyDict, PyClone, NoArgs, ObjectProtocol,
+	exc, py_class, py_exception, py_fn, py_module_initializer,
+};
+use num_complex::Complex64;
+use python3_sys as ffi;
+use tch::{Device, Kind, Tensor};
+
+use torch_compute::expression::{
+	self,
+	Token,
+	shunter,
+	evaluator,
+	varnum::{Variable, Parameter, Transform, Constant},
+};
+
+// Built with `cargo build --release -p torch_compute_python --features python`, the library
+// is then imported as `torch_compute` once renamed from `libtorch_compute_python.so` to
+// `torch_compute.so`, python/tests has the smoke tests. Tensors cross without copies,
+// arguments are wrapped in place and results are handed to torch through DLPack. Evaluation
+// is not recorded by autograd on the Python side.
+py_module_initializer!(torch_compute, |py, m| {
+	m.add(py, "__doc__", "Parses, shunts and evaluates expressions on torch tensors.")?;
+	m.add(py, "ExpressionError", py.get_type::<ExpressionError>())?;
+	m.add_class::<Context>(py)?;
+	m.add_class::<Rpn>(py)?;
+	m.add(py, "shunt", py_fn!(py, shunt(expr: &str, context: &Context)))?;
+	m.add(py, "stringify_rpn", py_fn!(py, stringify_rpn(rpn: &Rpn)))?;
+	m.add(py, "evaluate", py_fn!(py, evaluate(rpn: &Rpn, context: &Context, variables: &PyDict)))?;
+	Ok(())
+});
+
+py_exception!(torch_compute, ExpressionError);
+
+py_class!(class Context |py| {
+	data context: RefCell<expression::Context>;
+
+	def __new__(_cls) -> PyResult<Context> {
+		Context::create_instance(py, RefCell::new(expression::Context::new()))
+	}
+
+	/// A context without any operators or functions.
+	@staticmethod def empty() -> PyResult<Context> {
+		Context::create_instance(py, RefCell::new(expression::Context::empty()))
+	}
+
+	/// A context that reads the output of sympy's str.
+	@staticmethod def sympy() -> PyResult<Context> {
+		Context::create_instance(py, RefCell::new(expression::Context::sympy()))
+	}
+
+	def add_variable(&self, name: &str) -> PyResult<PyObject> {
+		let result = self.context(py).borrow_mut().add_variable(Variable::new(name));
+		result.map_err(|err| expression_error(py, err))?;
+		Ok(py.None())
+	}
+
+	/// `transform` is one of identity, log, softplus, logit and box.
+	def add_parameter(&self, name: &str, lower: Option<f64> = None, upper: Option<f64> = None, transform: &str = "identity") -> PyResult<PyObject> {
+		let transform = Transform::from_name(transform).map_err(|err| expression_error(py, err))?;
+		let param = Parameter::new(name).with_bounds(lower, upper).with_transform(transform);
+		let result = self.context(py).borrow_mut().add_parameter(param);
+		result.map_err(|err| expression_error(py, err))?;
+		Ok(py.None())
+	}
+
+	/// A number, complex number or torch.Tensor. Tensors are copied.
+	def add_constant(&self, name: &str, value: PyObject) -> PyResult<PyObject> {
+		let constant = if is_tensor(py, &value)? {
+			let value = resolved(py, &value)?;
+			let view = unsafe { tensor_view(py, &value)? };
+			Constant::from_tensor(name, copy(&view).map_err(|err| expression_error(py, err.into()))?)
+		} else {
+			let re: f64 = value.getattr(py, "real")?.extract(py)?;
+			let im: f64 = value.getattr(py, "imag")?.extract(py)?;
+			Constant::new(name, Complex64::new(re, im))
+		};
+		let result = self.context(py).borrow_mut().add_constant(constant);
+		result.map_err(|err| expression_error(py, err))?;
+		Ok(py.None())
+	}
+
+	def define_function(&self, definition: &str) -> PyResult<PyObject> {
+		let result = self.context(py).borrow_mut().define_function(definition);
+		result.map_err(|err| expression_error(py, err))?;
+		Ok(py.None())
+	}
+
+	def set_imaginary_units(&self, units: Vec<String>) -> PyResult<PyObject> {
+		let units: Vec<&str> = units.iter().map(|unit| unit.as_str()).collect();
+		let result = self.context(py).borrow_mut().set_imaginary_units(&units);
+		result.map_err(|err| expression_error(py, err))?;
+		Ok(py.None())
+	}
+
+	@property def variables(&self) -> PyResult<Vec<String>> {
+		let context = self.context(py).borrow();
+		Ok(context.get_variables().iter().map(|var| var.get_token().to_string()).collect())
+	}
+});
+
+py_class!(class Rpn |py| {
+	data tokens: Vec<Token>;
+
+	def __str__(&self) -> PyResult<String> {
+		Ok(shunter::stringify_rpn(self.tokens(py)))
+	}
+
+	def __len__(&self) -> PyResult<usize> {
+		Ok(self.tokens(py).len())
+	}
+});
+
+fn shunt(py: Python, expr: &str, context: &Context) -> PyResult<Rpn> {
+	let rpn = shunter::shunt(expr, &context.context(py).borrow()).map_err(|err| expression_error(py, err))?;
+	return Rpn::create_instance(py, rpn);
+}
+
+fn stringify_rpn(py: Python, rpn: &Rpn) -> PyResult<String> {
+	return Ok(shunter::stringify_rpn(rpn.tokens(py)));
+}
+
+/// `variables` maps variable names to torch.Tensors.
+fn evaluate(py: Python, rpn: &Rpn, context: &Context, variables: &PyDict) -> PyResult<PyObject> {
+	let mut inputs: Vec<PyObject> = vec![];
+	let mut bound: HashMap<String, Tensor> = HashMap::new();
+	for (name, value) in variables.items(py) {
+		let name: String = name.extract(py)?;
+		if !is_tensor(py, &value)? {
+			return Err(PyErr::new::<exc::TypeError, _>(py, format!("variable '{}' is not bound to a torch.Tensor", name)));
+		}
+		let value = resolved(py, &value)?;
+		bound.insert(name, unsafe { tensor_view(py, &value)? });
+		inputs.push(value);
+	}
+	let result = evaluator::evaluate(rpn.tokens(py), &context.context(py).borrow(), &bound)
+		.map_err(|err| expression_error(py, err))?;
+	return to_torch(py, result, inputs);
+}
+
+fn expression_error(py: Python, err: anyhow::Error) -> PyErr {
+	return PyErr::new::<ExpressionError, _>(py, format!("{:#}", err));
+}
+
+// Without torch imported there are no tensors, numbers do not need it
+fn is_tensor(py: Python, obj: &PyObject) -> PyResult<bool> {
+	let modules: PyDict = py.import("sys")?.get(py, "modules")?.extract(py)?;
+	match modules.get_item(py, "torch") {
+		Some(torch) => return torch.call_method(py, "is_tensor", (obj,), None)?.extract(py),
+		None => return Ok(false),
+	}
+}
+
+fn copy(tensor: &Tensor) -> Result<Tensor, tch::TchError> {
+	let mut copied = tensor.f_zeros_like()?;
+	copied.f_copy_(tensor)?;
+	return Ok(copied);
+}
+
+// Conjugated and negated views only flag the tensor, its storage holds the values before the
+// conjugation or negation, these are applied before the storage is read. Older torch has
+// neither flag.
+fn resolved(py: Python, obj: &PyObject) -> PyResult<PyObject> {
+	if !obj.hasattr(py, "resolve_conj")? {
+		return Ok(obj.clone_ref(py));
+	}
+	let obj = obj.call_method(py, "resolve_conj", NoArgs, None)?;
+	return obj.call_method(py, "resolve_neg", NoArgs, None);
+}
+
+// A tch view of the storage of a torch.Tensor. It does not keep the storage alive, the caller
+// holds on to the Python tensor for as long as the view is used.
+unsafe fn tensor_view(py: Python, obj: &PyObject) -> PyResult<Tensor> {
+	let data_ptr: usize = obj.call_method(py, "data_ptr", NoArgs, None)?.extract(py)?;
+	let size: Vec<i64> = obj.call_method(py, "size", NoArgs, None)?.extract(py)?;
+	let strides: Vec<i64> = obj.call_method(py, "stride", NoArgs, None)?.extract(py)?;
+	let dtype = obj.getattr(py, "dtype")?.str(py)?.to_string(py)?.into_owned();
+	let device = obj.getattr(py, "device")?;
+	let device_type: String = device.getattr(py, "type")?.extract(py)?;
+	let index: Option<usize> = device.getattr(py, "index")?.extract(py)?;
+
+	let kind = match dtype.as_str() {
+		"torch.uint8" => Kind::Uint8,
+		"torch.int8" => Kind::Int8,
+		"torch.int16" => Kind::Int16,
+		"torch.int32" => Kind::Int,
+		"torch.int64" => Kind::Int64,
+		"torch.float16" => Kind::Half,
+		"torch.bfloat16" => Kind::BFloat16,
+		"torch.float32" => Kind::Float,
+		"torch.float64" => Kind::Double,
+		"torch.complex64" => Kind::ComplexFloat,
+		"torch.complex128" => Kind::ComplexDouble,
+		"torch.bool" => Kind::Bool,
+		_ => return Err(PyErr::new::<exc::TypeError, _>(py, format!("unsupported dtype {}", dtype))),
+	};
+	let device = match device_type.as_str() {
+		"cpu" => Device::Cpu,
+		"cuda" => Device::Cuda(index.unwrap_or(0)),
+		_ => return Err(PyErr::new::<exc::TypeError, _>(py, format!("unsupported device {}", device_type))),
+	};
+	return Tensor::f_of_blob(data_ptr as *const u8, &size, &strides, kind, device)
+		.map_err(|err| expression_error(py, err.into()));
+}
+
+// DLPack structs, laid out as torch.utils.dlpack.from_dlpack reads them
+#[repr(C)]
+struct DLDevice {
+	device_type: i32,
+	device_id: i32,
+}
+
+#[repr(C)]
+struct DLDataType {
+	code: u8,
+	bits: u8,
+	lanes: u16,
+}
+
+#[repr(C)]
+struct DLTensor {
+	data: *mut c_void,
+	device: DLDevice,
+	ndim: i32,
+	dtype: DLDataType,
+	shape: *mut i64,
+	strides: *mut i64,
+	byte_offset: u64,
+}
+
+#[repr(C)]
+struct DLManagedTensor {
+	dl_tensor: DLTensor,
+	manager_ctx: *mut c_void,
+	deleter: Option<unsafe extern "C" fn(*mut DLManagedTensor)>,
+}
+
+// Owns everything the DLPack tensor points into. The inputs are kept alive as well since the
+// result may be a view of one of them.
+struct Exported {
+	managed: DLManagedTensor,
+	shape: Vec<i64>,
+	strides: Vec<i64>,
+	_tensor: Tensor,
+	_inputs: Vec<PyObject>,
+}
+
+const DLTENSOR: &[u8] = b"dltensor\0";
+
+unsafe extern "C" fn delete_exported(managed: *mut DLManagedTensor) {
+	drop(Box::from_raw((*managed).manager_ctx as *mut Exported));
+}
+
+// torch renames the capsule once it owns the tensor, only an unconsumed one is deleted here
+unsafe extern "C" fn delete_capsule(capsule: *mut ffi::PyObject) {
+	let name = DLTENSOR.as_ptr() as *const c_char;
+	if ffi::PyCapsule_IsValid(capsule, name) == 1 {
+		delete_exported(ffi::PyCapsule_GetPointer(capsule, name) as *mut DLManagedTensor);
+	}
+}
+
+fn dl_data_type(py: Python, kind: Kind) -> PyResult<DLDataType> {
+	// Type codes of DLPack, kDLInt, kDLUInt, kDLFloat, kDLBfloat and kDLComplex. Booleans
+	// go as bytes, older torch does not read kDLBool.
+	let code = match kind {
+		Kind::Int8 | Kind::Int16 | Kind::Int | Kind::Int64 => 0,
+		Kind::Uint8 | Kind::Bool => 1,
+		Kind::Half | Kind::Float | Kind::Double => 2,
+		Kind::BFloat16 => 4,
+		Kind::ComplexHalf | Kind::ComplexFloat | Kind::ComplexDouble => 5,
+		_ => return Err(PyErr::new::<exc::TypeError, _>(py, format!("{:?} tensors can not be passed to torch", kind))),
+	};
+	return Ok(DLDataType {code: code, bits: (kind.elt_size_in_bytes() * 8) as u8, lanes: 1});
+}
+
+fn to_torch(py: Python, tensor: Tensor, inputs: Vec<PyObject>) -> PyResult<PyObject> {
+	// kDLCPU and kDLCUDA
+	let device = match tensor.device() {
+		Device::Cpu => DLDevice {device_type: 1, device_id: 0},
+		Device::Cuda(index) => DLDevice {device_type: 2, device_id: index as i32},
+	};
+	let is_bool = tensor.kind() == Kind::Bool;
+	let dl_tensor = DLTensor {
+		data: tensor.data_ptr(),
+		device: device,
+		ndim: tensor.dim() as i32,
+		dtype: dl_data_type(py, tensor.kind())?,
+		shape: ptr::null_mut(),
+		strides: ptr::null_mut(),
+		byte_offset: 0,
+	};
+	let mut exported = Box::new(Exported {
+		managed: DLManagedTensor {dl_tensor: dl_tensor, manager_ctx: ptr::null_mut(), deleter: Some(delete_exported)},
+		shape: tensor.size(),
+		strides: tensor.stride(),
+		_tensor: tensor,
+		_inputs: inputs,
+	});
+	exported.managed.dl_tensor.shape = exported.shape.as_mut_ptr();
+	exported.managed.dl_tensor.strides = exported.strides.as_mut_ptr();
+
+	let exported = Box::into_raw(exported);
+	let capsule = unsafe {
+		(*exported).managed.manager_ctx = exported as *mut c_void;
+		let managed = ptr::addr_of_mut!((*exported).managed) as *mut c_void;
+		ffi::PyCapsule_New(managed, DLTENSOR.as_ptr() as *const c_char, Some(delete_capsule))
+	};
+	if capsule.is_null() {
+		unsafe { drop(Box::from_raw(exported)) };
+		return Err(PyErr::fetch(py));
+	}
+	let capsule = unsafe { PyObject::from_owned_ptr(py, capsule) };
+	let dlpack = py.import("torch.utils.dlpack")?;
+	let result = dlpack.call(py, "from_dlpack", (capsule,), None)?;
+	if is_bool {
+		return result.call_method(py, "bool", NoArgs, None);
+	}
+	return Ok(result);
+}
@@ -0,0 +1,54 @@
+
+# Smoke tests of the extension module, run with pytest once torch_compute.so is importable,
+# see python/src/lib.rs for how it is built.
+
+import pytest
+import torch
+
+import torch_compute as tc
+
+def context():
+    ctx = tc.Context()
+    ctx.add_variable("X")
+    ctx.add_variable("Y")
+    return ctx
+
+def test_shunt():
+    ctx = context()
+    rpn = tc.shunt("sin(X)*2 + Y", ctx)
+    assert tc.stringify_rpn(rpn) == "X,sin,2,*,Y,+,"
+    assert str(rpn) == "X,sin,2,*,Y,+,"
+    assert len(rpn) == 6
+
+def test_errors_are_exceptions():
+    with pytest.raises(tc.ExpressionError, match="missing operand"):
+        tc.shunt("X +", context())
+    with pytest.raises(TypeError):
+        tc.evaluate(tc.shunt("X", context()), context(), {"X": 1.0})
+
+def test_evaluate():
+    ctx = context()
+    x = torch.linspace(0, 1, 5, dtype=torch.float64)
+    y = torch.full((5,), 2.0, dtype=torch.float64)
+    result = tc.evaluate(tc.shunt("X*Y + 1", ctx), ctx, {"X": x, "Y": y})
+    assert result.dtype == torch.float64
+    assert torch.allclose(result, x*y + 1)
+
+def test_inputs_share_storage():
+    ctx = context()
+    x = torch.arange(4, dtype=torch.float32)
+    result = tc.evaluate(tc.shunt("X", ctx), ctx, {"X": x})
+    x[0] = 10.0
+    assert result[0].item() == 10.0
+
+def test_bool_round_trip():
+    ctx = context()
+    x = torch.tensor([True, False, True, False])
+    y = torch.tensor([True, True, False, False])
+    result = tc.evaluate(tc.shunt("X && !Y", ctx), ctx, {"X": x, "Y": y})
+    assert result.dtype == torch.bool
+    assert result.tolist() == [False, False, True, False]
+
+    result = tc.evaluate(tc.shunt("X < Y", ctx), ctx, {"X": torch.tensor([1.0, 3.0]), "Y": torch.tensor([2.0, 2.0])})
+    assert result.dtype == torch.bool
+    assert result.tolist() == [True, False]
@@ -0,0 +1,234 @@
+use crate::expression::{
+	Token,
+	Context,
+	shunter,
+	functions::Function,
+	operators::{Operator, UnaryOperator, BinaryOperator},
+	varnum::{Variable, Number, Constant},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+	Number(Number),
+	Constant(Constant),
+	Zero,
+	Unity,
+	Variable(Variable),
+	UnaryOperator(UnaryOperator, Box<Expr>),
+	BinaryOperator(BinaryOperator, Box<Expr>, Box<Expr>),
+	Function(Function, Vec<Expr>),
+}
+
+impl Expr {
+
+	pub fn from_rpn(rpn: &Vec<Token>) -> anyhow::Result<Expr> {
+		let mut stack: Vec<Expr> = vec![];
+
+		for token in rpn.iter() {
+			match token {
+				Token::NoToken => {},
+				Token::Number(num) => stack.push(Expr::Number(num.clone())),
+				Token::Constant(constant) => stack.push(Expr::Constant(constant.clone())),
+				Token::Zero => stack.push(Expr::Zero),
+				Token::Unity => stack.push(Expr::Unity),
+				Token::Variable(var) => stack.push(Expr::Variable(var.clone())),
+				Token::Operator(Operator::UnaryOperator(uop)) => {
+					let mut args = pop_args(&mut stack, 1, token)?;
+					let arg = args.pop().unwrap();
+					stack.push(Expr::UnaryOperator(uop.clone(), Box::new(arg)));
+				},
+				Token::Operator(Operator::BinaryOperator(bop)) => {
+					let mut args = pop_args(&mut stack, 2, token)?;
+					let rhs = args.pop().unwrap();
+					let lhs = args.pop().unwrap();
+					stack.push(Expr::BinaryOperator(bop.clone(), Box::new(lhs), Box::new(rhs)));
+				},
+				Token::Function(func) => {
+					let args = pop_args(&mut stack, func.get_n_inputs() as usize, token)?;
+					stack.push(Expr::Function(func.clone(), args));
+				},
+				Token::LeftParen | Token::RightParen | Token::Comma => {
+					return Err(anyhow::anyhow!("{:?} must not be in a shunted expression", token.stringify()));
+				},
+			}
+		}
+
+		let root = stack.pop();
+		if !stack.is_empty() {
+			return Err(anyhow::anyhow!("expression left {} unused values on the stack", stack.len()));
+		}
+		match root {
+			Some(root) => return Ok(root),
+			None => return Err(anyhow::anyhow!("stack underflow, empty expression")),
+		}
+	}
+
+	pub fn unary(context: &Context, token: &str, arg: Expr) -> anyhow::Result<Expr> {
+		match context.get_unary_operator(token) {
+			Some(uop) => return Ok(Expr::UnaryOperator(uop.clone(), Box::new(arg))),
+			None => return Err(anyhow::anyhow!("unary operator '{}' is not part of the context", token)),
+		}
+	}
+
+	pub fn binary(context: &Context, token: &str, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+		match context.get_binary_operator(token) {
+			Some(bop) => return Ok(Expr::BinaryOperator(bop.clone(), Box::new(lhs), Box::new(rhs))),
+			None => return Err(anyhow::anyhow!("binary operator '{}' is not part of the context", token)),
+		}
+	}
+
+	pub fn call(context: &Context, token: &str, args: Vec<Expr>) -> anyhow::Result<Expr> {
+		match context.get_function(token) {
+			Some(func) => {
+				if !func.accepts_n_inputs(args.len()) {
+					return Err(anyhow::anyhow!("function '{}' does not take {} arguments", token, args.len()));
+				}
+				return Ok(Expr::Function(func.called_with(args.len() as u8), args));
+			},
+			None => return Err(anyhow::anyhow!("function '{}' is not part of the context", token)),
+		}
+	}
+
+	pub fn to_rpn(&self) -> Vec<Token> {
+		let mut rpn: Vec<Token> = vec![];
+		self.push_rpn(&mut rpn);
+		return rpn;
+	}
+
+	fn push_rpn(&self, rpn: &mut Vec<Token>) {
+		for child in self.children() {
+			child.push_rpn(rpn);
+		}
+		rpn.push(self.to_token());
+	}
+
+	/// The token this node occupies in RPN, children excluded.
+	pub fn to_token(&self) -> Token {
+		match self {
+			Expr::Number(num) => return Token::Number(num.clone()),
+			Expr::Constant(constant) => return Token::Constant(constant.clone()),
+			Expr::Zero => return Token::Zero,
+			Expr::Unity => return Token::Unity,
+			Expr::Variable(var) => return Token::Variable(var.clone()),
+			Expr::UnaryOperator(uop, _) => return Token::Operator(Operator::UnaryOperator(uop.clone())),
+			Expr::BinaryOperator(bop, _, _) => return Token::Operator(Operator::BinaryOperator(bop.clone())),
+			Expr::Function(func, _) => return Token::Function(func.clone()),
+		}
+	}
+
+	pub fn children(&self) -> Vec<&Expr> {
+		match self {
+			Expr::Number(_) | Expr::Constant(_) | Expr::Zero | Expr::Unity | Expr::Variable(_) => return vec![],
+			Expr::UnaryOperator(_, arg) => return vec![arg],
+			Expr::BinaryOperator(_, lhs, rhs) => return vec![lhs, rhs],
+			Expr::Function(_, args) => return args.iter().collect(),
+		}
+	}
+
+	pub fn is_leaf(&self) -> bool {
+		return self.children().is_empty();
+	}
+
+	/// Visits every node in pre-order, parents before their children.
+	pub fn walk<'a>(&'a self, visitor: &mut dyn FnMut(&'a Expr)) {
+		visitor(self);
+		for child in self.children() {
+			child.walk(visitor);
+		}
+	}
+
+	/// All nodes that have `child` as one of their direct children.
+	pub fn parents_of<'a>(&'a self, child: &Expr) -> Vec<&'a Expr> {
+		let mut parents: Vec<&'a Expr> = vec![];
+		self.walk(&mut |node| {
+			if node.children().iter().any(|c| (*c).eq(child)) {
+				parents.push(node);
+			}
+		});
+		return parents;
+	}
+
+}
+
+pub fn build(expr: &str, context: &Context) -> anyhow::Result<Expr> {
+	let rpn = shunter::shunt(expr, context)?;
+	return Expr::from_rpn(&rpn);
+}
+
+fn pop_args(stack: &mut Vec<Expr>, n: usize, token: &Token) -> anyhow::Result<Vec<Expr>> {
+	if stack.len() < n {
+		return Err(anyhow::anyhow!("stack underflow, '{}' takes {} arguments but only {} were available", token.stringify(), n, stack.len()));
+	}
+	return Ok(stack.split_off(stack.len() - n));
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, Token, shunter, functions::Function, varnum::Variable};
+	use super::{Expr, build};
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		return context;
+	}
+
+	fn tokens(nodes: &[&Expr]) -> Vec<String> {
+		return nodes.iter().map(|node| node.to_token().stringify().to_string()).collect();
+	}
+
+	#[test]
+	fn rpn_round_trip() {
+		let mut context = context();
+		context.add_function(Function::new("now", 0)).unwrap();
+		for expr in ["x", "-x^2", "sin(x)*max(x, y, 2) + 1i", "!(x < y) && y", "now()", "atan2(y, -x)/sum(x)"] {
+			let rpn = shunter::shunt(expr, &context).unwrap();
+			let tree = Expr::from_rpn(&rpn).unwrap();
+			assert_eq!(tree.to_rpn(), rpn.into_iter().filter(|token| *token != Token::NoToken).collect::<Vec<Token>>(), "{}", expr);
+		}
+	}
+
+	#[test]
+	fn from_rpn_errors() {
+		let context = context();
+		let plus = shunter::shunt("x + y", &context).unwrap();
+		let err = Expr::from_rpn(&plus[1..].to_vec()).unwrap_err();
+		assert_eq!(err.to_string(), "stack underflow, '+' takes 2 arguments but only 1 were available");
+		let err = Expr::from_rpn(&plus[..2].to_vec()).unwrap_err();
+		assert_eq!(err.to_string(), "expression left 1 unused values on the stack");
+		assert_eq!(Expr::from_rpn(&vec![]).unwrap_err().to_string(), "stack underflow, empty expression");
+		assert!(Expr::from_rpn(&vec![Token::LeftParen]).is_err());
+	}
+
+	#[test]
+	fn walk_is_pre_order() {
+		let tree = build("sin(x)*(y + 2) - x", &context()).unwrap();
+		let mut nodes: Vec<&Expr> = vec![];
+		tree.walk(&mut |node| nodes.push(node));
+		assert_eq!(tokens(&nodes), vec!["-", "*", "sin", "x", "+", "y", "2", "x"]);
+		assert_eq!(nodes.iter().filter(|node| node.is_leaf()).count(), 4);
+	}
+
+	#[test]
+	fn parents() {
+		let tree = build("sin(x) + x*sin(x)", &context()).unwrap();
+		let x = build("x", &context()).unwrap();
+		assert_eq!(tokens(&tree.parents_of(&x)), vec!["sin", "*", "sin"]);
+		let sin = build("sin(x)", &context()).unwrap();
+		assert_eq!(tokens(&tree.parents_of(&sin)), vec!["+", "*"]);
+		assert!(tree.parents_of(&tree).is_empty());
+		assert!(tree.parents_of(&build("y", &context()).unwrap()).is_empty());
+	}
+
+	#[test]
+	fn constructors() {
+		let context = context();
+		let x = || Expr::Variable(Variable::new("x"));
+		let expr = Expr::binary(&context, "+", Expr::unary(&context, "-", x()).unwrap(), Expr::call(&context, "max", vec![x(), Expr::Unity, Expr::Zero]).unwrap()).unwrap();
+		assert_eq!(shunter::stringify_rpn(&expr.to_rpn()), "x,-,x,Unity,Zero,max,+,");
+		assert!(Expr::call(&context, "atan2", vec![x()]).is_err());
+		assert!(Expr::unary(&context, "~", x()).is_err());
+		assert!(Expr::binary(&context, "%", x(), x()).is_err());
+	}
+}
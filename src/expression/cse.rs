@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use crate::expression::{
+	Context,
+	shunter,
+	ast::Expr,
+	varnum::Variable,
+};
+
+/// An expression split into temporaries and a root that refers to them. Temporaries are
+/// ordered so that each one only refers to variables and temporaries defined before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cse {
+	pub temporaries: Vec<(Variable, Expr)>,
+	pub root: Expr,
+}
+
+pub fn cse(expr: &Expr, context: &Context) -> Cse {
+	let mut counts: HashMap<String, usize> = HashMap::new();
+	count_subexpressions(expr, &mut counts);
+
+	let mut eliminator = Eliminator {
+		context: context,
+		counts: counts,
+		assigned: HashMap::new(),
+		temporaries: vec![],
+	};
+	let root = eliminator.rewrite(expr);
+
+	return Cse { temporaries: eliminator.temporaries, root: root };
+}
+
+fn key(expr: &Expr) -> String {
+	return shunter::stringify_rpn(&expr.to_rpn());
+}
+
+// A repeated subtree is only descended into the first time it is seen, so subexpressions
+// that only ever occur inside one repeated subtree are not counted as repeated themselves.
+fn count_subexpressions(expr: &Expr, counts: &mut HashMap<String, usize>) {
+	if expr.is_leaf() {
+		return;
+	}
+	let count = counts.entry(key(expr)).or_insert(0);
+	*count += 1;
+	if *count > 1 {
+		return;
+	}
+	for child in expr.children() {
+		count_subexpressions(child, counts);
+	}
+}
+
+struct Eliminator<'a> {
+	context: &'a Context,
+	counts: HashMap<String, usize>,
+	assigned: HashMap<String, Variable>,
+	temporaries: Vec<(Variable, Expr)>,
+}
+
+impl<'a> Eliminator<'a> {
+
+	fn rewrite(&mut self, expr: &Expr) -> Expr {
+		if expr.is_leaf() {
+			return expr.clone();
+		}
+		let expr_key = key(expr);
+		if let Some(var) = self.assigned.get(&expr_key) {
+			return Expr::Variable(var.clone());
+		}
+
+		let rewritten = match expr {
+			Expr::UnaryOperator(uop, arg) => {
+				Expr::UnaryOperator(uop.clone(), Box::new(self.rewrite(arg)))
+			},
+			Expr::BinaryOperator(bop, lhs, rhs) => {
+				Expr::BinaryOperator(bop.clone(), Box::new(self.rewrite(lhs)), Box::new(self.rewrite(rhs)))
+			},
+			Expr::Function(func, args) => {
+				Expr::Function(func.clone(), args.iter().map(|arg| self.rewrite(arg)).collect())
+			},
+			_ => expr.clone(),
+		};
+
+		if self.counts.get(&expr_key).copied().unwrap_or(0) < 2 {
+			return rewritten;
+		}
+
+		let var = self.next_temporary();
+		self.assigned.insert(expr_key, var.clone());
+		self.temporaries.push((var.clone(), rewritten));
+		return Expr::Variable(var);
+	}
+
+	// Temporaries are named x0, x1, ... skipping names already used by the context
+	fn next_temporary(&self) -> Variable {
+		let mut i = self.temporaries.len();
+		loop {
+			let name = format!("x{}", i);
+			if self.context.get_variable(&name).is_none() && !self.temporaries.iter().any(|(var, _)| var.get_token() == name) {
+				return Variable::new(&name);
+			}
+			i += 1;
+		}
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, shunter, ast, varnum::Variable};
+	use super::{cse, Cse};
+
+	fn eliminate(expr: &str, variables: &[&str]) -> Cse {
+		let mut context = Context::new();
+		for name in variables {
+			context.add_variable(Variable::new(name)).unwrap();
+		}
+		return cse(&ast::build(expr, &context).unwrap(), &context);
+	}
+
+	fn temporaries(cse: &Cse) -> Vec<(String, String)> {
+		return cse.temporaries.iter()
+			.map(|(var, expr)| (var.get_token().to_string(), shunter::stringify_rpn(&expr.to_rpn())))
+			.collect();
+	}
+
+	fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+		return expected.iter().map(|(name, rpn)| (name.to_string(), rpn.to_string())).collect();
+	}
+
+	#[test]
+	fn temporaries_are_numbered() {
+		let eliminated = eliminate("sin(x)*sin(x) + cos(y)*cos(y)", &["x", "y"]);
+		assert_eq!(temporaries(&eliminated), pairs(&[("x0", "x,sin,"), ("x1", "y,cos,")]));
+		assert_eq!(shunter::stringify_rpn(&eliminated.root.to_rpn()), "x0,x0,*,x1,x1,*,+,");
+	}
+
+	#[test]
+	fn nothing_shared() {
+		let eliminated = eliminate("sin(x) + cos(x)*x", &["x"]);
+		assert!(eliminated.temporaries.is_empty());
+		assert_eq!(shunter::stringify_rpn(&eliminated.root.to_rpn()), "x,sin,x,cos,x,*,+,");
+	}
+
+	#[test]
+	fn nested_shared_subexpressions() {
+		// sin(x) is shared on its own as well, it comes first since exp refers to it
+		let eliminated = eliminate("exp(sin(x)) + exp(sin(x))*sin(x)", &["x"]);
+		assert_eq!(temporaries(&eliminated), pairs(&[("x0", "x,sin,"), ("x1", "x0,exp,")]));
+		assert_eq!(shunter::stringify_rpn(&eliminated.root.to_rpn()), "x1,x1,x0,*,+,");
+
+		// Only ever inside the shared subtree, so x*y stays part of it
+		let eliminated = eliminate("(x*y + 1)^2 - (x*y + 1)", &["x", "y"]);
+		assert_eq!(temporaries(&eliminated), pairs(&[("x0", "x,y,*,1,+,")]));
+		assert_eq!(shunter::stringify_rpn(&eliminated.root.to_rpn()), "x0,2,^,x0,-,");
+	}
+
+	#[test]
+	fn names_of_the_context_are_skipped() {
+		let eliminated = eliminate("sin(x0)*sin(x0) + cos(x2)*cos(x2) + x*x*tan(x)/tan(x)", &["x", "x0", "x2"]);
+		let names: Vec<String> = temporaries(&eliminated).into_iter().map(|(name, _)| name).collect();
+		assert_eq!(names, vec!["x1", "x3", "x4"]);
+		assert_eq!(temporaries(&eliminated)[0], (String::from("x1"), String::from("x0,sin,")));
+	}
+}
@@ -0,0 +1,298 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	derivative,
+	evaluator,
+	shunter,
+	functions::Function,
+	varnum::Variable,
+	diagnostics::{Span, SpanError},
+};
+
+/// A function written in the expression language, as in `lorentz(x,w) = w^2/(x^2+w^2)`.
+/// The body may only refer to the parameters and to the constants, operators and functions
+/// of the context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+	name: String,
+	params: Vec<Variable>,
+	body: Expr,
+	rpn: Vec<Token>,
+}
+
+impl Definition {
+
+	/// Parses `name(params) = body`, the body with the lexer and shunter of `context` extended
+	/// by the parameters. Parameters shadow context variables of the same name, other variables
+	/// are rejected while constants keep their value. Functions called in the body are the ones
+	/// registered at this point, so a definition can not call itself or a function defined later.
+	pub fn parse(definition: &str, context: &Context) -> anyhow::Result<Definition> {
+		let eq = match find_assignment(definition) {
+			Some(eq) => eq,
+			None => return Err(SpanError::new("expected 'name(params) = body'", Span::new(0, definition.len()), definition).into()),
+		};
+		let (name, params) = parse_header(definition, eq)?;
+
+		let mut scope = context.clone();
+		for param in params.iter() {
+			scope.remove_variable(param.get_token());
+			if let Err(err) = scope.add_variable(param.clone()) {
+				return Err(SpanError::new(&err.to_string(), Span::new(0, eq), definition).into());
+			}
+		}
+		// A stand-in for the function itself, so that a recursive call is recognized as such
+		if let Err(err) = scope.override_function(Function::new(&name, params.len() as u8)) {
+			return Err(SpanError::new(&err.to_string(), Span::new(0, eq), definition).into());
+		}
+
+		let rpn = shunter::shunt_range(definition, eq + 1, definition.len(), &scope)?;
+		let body = Expr::from_rpn(&rpn)?;
+
+		let mut err: Option<anyhow::Error> = None;
+		body.walk(&mut |node| {
+			if err.is_some() {
+				return;
+			}
+			match node {
+				Expr::Function(func, _) if func.get_token() == name => {
+					err = Some(anyhow::anyhow!("function '{}' calls itself, recursive definitions are not supported", name));
+				},
+				Expr::Variable(var) if !params.contains(var) => {
+					err = Some(anyhow::anyhow!("'{}' in the body of '{}' is not one of its parameters", var.get_token(), name));
+				},
+				_ => {},
+			}
+		});
+		if let Some(err) = err {
+			return Err(err);
+		}
+
+		Ok(Definition {name, params, body, rpn})
+	}
+
+	// For definitions that were checked when they were parsed, e.g. before being saved
+	pub (super) fn from_parts(name: &str, params: Vec<Variable>, body: Expr) -> Definition {
+		let rpn = body.to_rpn();
+		Definition {name: name.to_string(), params, body, rpn}
+	}
+
+	pub fn get_name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn get_params(&self) -> &[Variable] {
+		&self.params
+	}
+
+	pub fn get_body(&self) -> &Expr {
+		&self.body
+	}
+
+	pub fn get_rpn(&self) -> &Vec<Token> {
+		&self.rpn
+	}
+
+	/// The body with every parameter replaced by the matching argument.
+	pub fn substitute(&self, args: &[Expr]) -> anyhow::Result<Expr> {
+		if args.len() != self.params.len() {
+			return Err(anyhow::anyhow!("function '{}' takes {} arguments but {} were given", self.name, self.params.len(), args.len()));
+		}
+		return Ok(substitute(&self.body, &self.params, args));
+	}
+
+	/// A function that evaluates the body as a call frame and is differentiated through it.
+	pub fn to_function(self) -> Function {
+		let definition = Arc::new(self);
+		let eval_definition = definition.clone();
+		let derivative_definition = definition.clone();
+		Function::new(&definition.name, definition.params.len() as u8)
+			.with_eval(move |args| evaluator::evaluate_definition(&eval_definition, args))
+			.with_derivative(move |args, i, context| {
+				let partial = derivative::diff(&derivative_definition.body, &derivative_definition.params[i], context)?;
+				return Ok(substitute(&partial, &derivative_definition.params, args));
+			})
+			.with_definition(definition)
+	}
+
+}
+
+/// Replaces every call of a defined function by its body, so later passes such as
+/// simplification and CSE see through it.
+pub fn inline(expr: &Expr) -> anyhow::Result<Expr> {
+	match expr {
+		Expr::Number(_) | Expr::Constant(_) | Expr::Zero | Expr::Unity | Expr::Variable(_) => return Ok(expr.clone()),
+		Expr::UnaryOperator(uop, arg) => return Ok(Expr::UnaryOperator(uop.clone(), Box::new(inline(arg)?))),
+		Expr::BinaryOperator(bop, lhs, rhs) => {
+			return Ok(Expr::BinaryOperator(bop.clone(), Box::new(inline(lhs)?), Box::new(inline(rhs)?)));
+		},
+		Expr::Function(func, args) => {
+			let args = args.iter().map(inline).collect::<anyhow::Result<Vec<Expr>>>()?;
+			match func.get_definition() {
+				// Bodies only call functions defined before them, so this terminates
+				Some(definition) => return Ok(substitute(&inline(definition.get_body())?, definition.get_params(), &args)),
+				None => return Ok(Expr::Function(func.clone(), args)),
+			}
+		},
+	}
+}
+
+fn substitute(expr: &Expr, params: &[Variable], args: &[Expr]) -> Expr {
+	match expr {
+		Expr::Variable(var) => {
+			match params.iter().position(|param| param.eq(var)) {
+				Some(i) => return args[i].clone(),
+				None => return expr.clone(),
+			}
+		},
+		Expr::Number(_) | Expr::Constant(_) | Expr::Zero | Expr::Unity => return expr.clone(),
+		Expr::UnaryOperator(uop, arg) => return Expr::UnaryOperator(uop.clone(), Box::new(substitute(arg, params, args))),
+		Expr::BinaryOperator(bop, lhs, rhs) => {
+			return Expr::BinaryOperator(bop.clone(), Box::new(substitute(lhs, params, args)), Box::new(substitute(rhs, params, args)));
+		},
+		Expr::Function(func, fargs) => {
+			return Expr::Function(func.clone(), fargs.iter().map(|arg| substitute(arg, params, args)).collect());
+		},
+	}
+}
+
+// The first '=' that is not part of a comparison operator
+pub (super) fn find_assignment(definition: &str) -> Option<usize> {
+	let bytes = definition.as_bytes();
+	for (i, &c) in bytes.iter().enumerate() {
+		if c != b'=' {
+			continue;
+		}
+		let before = if i > 0 { bytes[i - 1] } else { b' ' };
+		let after = if i + 1 < bytes.len() { bytes[i + 1] } else { b' ' };
+		if !matches!(before, b'=' | b'<' | b'>' | b'!') && after != b'=' {
+			return Some(i);
+		}
+	}
+	return None;
+}
+
+// Parses `name(a, b, ...)` in front of the '=' at byte eq
+fn parse_header(definition: &str, eq: usize) -> anyhow::Result<(String, Vec<Variable>)> {
+	let header = &definition[..eq];
+	let error = |message: &str, start: usize, end: usize| -> anyhow::Error {
+		SpanError::new(message, Span::new(start, end), definition).into()
+	};
+
+	let open = match header.find('(') {
+		Some(open) => open,
+		None => return Err(error("expected '(' after the function name", 0, eq)),
+	};
+	let close = match header.rfind(')') {
+		Some(close) if close > open => close,
+		_ => return Err(error("expected ')' after the parameters", open, eq)),
+	};
+	if !header[close + 1..].trim().is_empty() {
+		return Err(error("expected '=' after the parameters", close + 1, eq));
+	}
+
+	let name = header[..open].trim();
+	let name_start = header.len() - header.trim_start().len();
+	if let Err(err) = super::check_identifier("function", name) {
+		return Err(error(&err.to_string(), name_start, open));
+	}
+
+	let mut params: Vec<Variable> = vec![];
+	let mut seen: HashSet<&str> = HashSet::new();
+	let list = &header[open + 1..close];
+	if !list.trim().is_empty() {
+		let mut start = open + 1;
+		for param in list.split(',') {
+			let end = start + param.len();
+			let token = param.trim();
+			let token_start = start + (param.len() - param.trim_start().len());
+			let token_span = (token_start, token_start + token.len().max(1));
+			if let Err(err) = super::check_identifier("parameter", token) {
+				return Err(error(&err.to_string(), token_span.0, token_span.1));
+			}
+			if !seen.insert(token) {
+				return Err(error(&format!("duplicate parameter '{}'", token), token_span.0, token_span.1));
+			}
+			params.push(Variable::new(token));
+			start = end + 1;
+		}
+	}
+	if params.len() > u8::MAX as usize {
+		return Err(error("too many parameters", open, close + 1));
+	}
+
+	return Ok((name.to_string(), params));
+}
+
+#[cfg(test)]
+mod tests {
+	use num_complex::Complex64;
+
+	use crate::expression::{Context, ast::{self, Expr}, shunter, varnum::{Variable, Constant}};
+	use super::{Definition, inline};
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_variable(Variable::new("w")).unwrap();
+		context.add_constant(Constant::new("g", Complex64::new(9.81, 0.0))).unwrap();
+		return context;
+	}
+
+	#[test]
+	fn parse() {
+		let definition = Definition::parse("lorentz(x, w) = w^2/(x^2 + w^2)", &context()).unwrap();
+		assert_eq!(definition.get_name(), "lorentz");
+		assert_eq!(definition.get_params(), &[Variable::new("x"), Variable::new("w")]);
+		assert_eq!(shunter::stringify_rpn(definition.get_rpn()), "w,2,^,x,2,^,w,2,^,+,/,");
+
+		let definition = Definition::parse("fall(t) = g*t^2/2", &context()).unwrap();
+		assert_eq!(shunter::stringify_rpn(definition.get_rpn()), "g,t,2,^,*,2,/,");
+		let definition = Definition::parse("now() = 1", &context()).unwrap();
+		assert!(definition.get_params().is_empty());
+	}
+
+	#[test]
+	fn rejected() {
+		let err = Definition::parse("f(x) = f(x - 1)", &context()).unwrap_err();
+		assert_eq!(err.to_string(), "function 'f' calls itself, recursive definitions are not supported");
+		let err = Definition::parse("f(x) = x*X", &context()).unwrap_err();
+		assert_eq!(err.to_string(), "'X' in the body of 'f' is not one of its parameters");
+		let err = Definition::parse("f(x, x) = x", &context()).unwrap_err();
+		assert!(err.to_string().starts_with("duplicate parameter 'x'"));
+		let err = Definition::parse("f(x) == x", &context()).unwrap_err();
+		assert!(err.to_string().starts_with("expected 'name(params) = body'"));
+	}
+
+	#[test]
+	fn substitute() {
+		let mut context = context();
+		context.add_variable(Variable::new("Y")).unwrap();
+		let definition = Definition::parse("f(x, y) = x*y + x", &context).unwrap();
+		let args = [ast::build("X + 1", &context).unwrap(), ast::build("Y", &context).unwrap()];
+		let expr = definition.substitute(&args).unwrap();
+		assert_eq!(shunter::stringify_rpn(&expr.to_rpn()), "X,1,+,Y,*,X,1,+,+,");
+		let err = definition.substitute(&args[..1]).unwrap_err();
+		assert_eq!(err.to_string(), "function 'f' takes 2 arguments but 1 were given");
+	}
+
+	#[test]
+	fn inline_nested_definitions() {
+		let mut context = context();
+		context.define_function("sq(x) = x*x").unwrap();
+		context.define_function("norm(x, y) = sqrt(sq(x) + sq(y))").unwrap();
+		let expr = ast::build("norm(X, 2) + sin(X)", &context).unwrap();
+		let inlined = inline(&expr).unwrap();
+		assert_eq!(shunter::stringify_rpn(&inlined.to_rpn()), "X,X,*,2,2,*,+,sqrt,X,sin,+,");
+		let mut calls = vec![];
+		inlined.walk(&mut |node| {
+			if let Expr::Function(func, _) = node {
+				calls.push(func.get_token().to_string());
+			}
+		});
+		assert_eq!(calls, vec!["sqrt", "sin"]);
+	}
+}
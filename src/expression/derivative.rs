@@ -0,0 +1,229 @@
+use num_complex::Complex64;
+
+use crate::expression::{
+	Context,
+	ast::Expr,
+	functions,
+	operators::Op,
+	varnum::{Variable, Number},
+};
+
+/// Differentiates `expr` with respect to `var`. Terms that are known to vanish are pruned
+/// while the derivative is built, using `Expr::Zero` and `Expr::Unity` as markers.
+pub fn diff(expr: &Expr, var: &Variable, context: &Context) -> anyhow::Result<Expr> {
+	match expr {
+		Expr::Number(_) | Expr::Constant(_) | Expr::Zero | Expr::Unity => return Ok(Expr::Zero),
+		Expr::Variable(v) => {
+			if v.eq(var) {
+				return Ok(Expr::Unity);
+			}
+			return Ok(Expr::Zero);
+		},
+		Expr::UnaryOperator(uop, arg) => {
+			let args = vec![arg.as_ref().clone()];
+			return chain_rule(&args, var, context, |i| uop.partial_derivative(&args, i, context));
+		},
+		Expr::BinaryOperator(bop, lhs, rhs) => {
+			let args = vec![lhs.as_ref().clone(), rhs.as_ref().clone()];
+			return chain_rule(&args, var, context, |i| bop.partial_derivative(&args, i, context));
+		},
+		Expr::Function(func, args) => {
+			return chain_rule(args, var, context, |i| func.partial_derivative(args, i, context));
+		},
+	}
+}
+
+// Sums partial(i)*d(args[i]) over the arguments that depend on var, partials are only
+// built for those so an operator or function without rule only fails when it matters
+fn chain_rule<F>(args: &[Expr], var: &Variable, context: &Context, partial: F) -> anyhow::Result<Expr>
+	where F: Fn(usize) -> anyhow::Result<Expr>
+{
+	let mut result = Expr::Zero;
+	for (i, arg) in args.iter().enumerate() {
+		let darg = diff(arg, var, context)?;
+		if is_zero(&darg) {
+			continue;
+		}
+		let partial = partial(i)?;
+		// A negated partial is subtracted, giving da-db rather than da+-1*db
+		result = match negated(&partial) {
+			Some(partial) => sub(context, result, mul(context, partial, darg)?)?,
+			None => add(context, result, mul(context, partial, darg)?)?,
+		};
+	}
+	return Ok(result);
+}
+
+fn negated(expr: &Expr) -> Option<Expr> {
+	match expr {
+		Expr::Number(num) if num.get_value().im == 0.0 && num.get_value().re < 0.0 => {
+			return Some(Expr::Number(Number::from_real(-num.get_value().re)));
+		},
+		Expr::UnaryOperator(uop, inner) if uop.get_token() == "-" => return Some(inner.as_ref().clone()),
+		_ => return None,
+	}
+}
+
+pub fn neg(context: &Context, arg: Expr) -> anyhow::Result<Expr> {
+	match arg {
+		Expr::Zero => return Ok(Expr::Zero),
+		Expr::Unity => return Ok(Expr::Number(Number::from_real(-1.0))),
+		Expr::Number(num) => return Ok(Expr::Number(Number::from_value(-num.get_value(), context.get_imaginary_units()))),
+		Expr::UnaryOperator(uop, inner) if uop.get_token() == "-" => return Ok(*inner),
+		_ => return Expr::unary(context, "-", arg),
+	}
+}
+
+pub fn add(context: &Context, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+	if is_zero(&lhs) {
+		return Ok(rhs);
+	}
+	if is_zero(&rhs) {
+		return Ok(lhs);
+	}
+	return Expr::binary(context, "+", lhs, rhs);
+}
+
+pub fn sub(context: &Context, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+	if is_zero(&rhs) {
+		return Ok(lhs);
+	}
+	if is_zero(&lhs) {
+		return neg(context, rhs);
+	}
+	if let (Expr::Number(num), Expr::Unity) = (&lhs, &rhs) {
+		return Ok(Expr::Number(Number::from_value(num.get_value() - 1.0, context.get_imaginary_units())));
+	}
+	return Expr::binary(context, "-", lhs, rhs);
+}
+
+pub fn mul(context: &Context, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+	if is_zero(&lhs) || is_zero(&rhs) {
+		return Ok(Expr::Zero);
+	}
+	if is_unity(&lhs) {
+		return Ok(rhs);
+	}
+	if is_unity(&rhs) {
+		return Ok(lhs);
+	}
+	return Expr::binary(context, "*", lhs, rhs);
+}
+
+pub fn div(context: &Context, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+	if is_zero(&lhs) {
+		return Ok(Expr::Zero);
+	}
+	if is_unity(&rhs) {
+		return Ok(lhs);
+	}
+	return Expr::binary(context, "/", lhs, rhs);
+}
+
+pub fn pow(context: &Context, lhs: Expr, rhs: Expr) -> anyhow::Result<Expr> {
+	if is_zero(&rhs) {
+		return Ok(Expr::Unity);
+	}
+	if is_unity(&rhs) {
+		return Ok(lhs);
+	}
+	return Expr::binary(context, "^", lhs, rhs);
+}
+
+/// The natural log of `arg` for the derivatives of powers. A context without `log` gets the
+/// default one, differentiating `2^X` does not depend on which functions are registered.
+pub fn log(context: &Context, arg: Expr) -> anyhow::Result<Expr> {
+	if context.get_function("log").is_some() {
+		return Expr::call(context, "log", vec![arg]);
+	}
+	let log = functions::default_functions().into_iter().find(|func| func.get_token() == "log").unwrap();
+	return Ok(Expr::Function(log, vec![arg]));
+}
+
+pub fn is_zero(expr: &Expr) -> bool {
+	return expr.eq(&Expr::Zero) || is_number(expr, 0.0);
+}
+
+pub fn is_unity(expr: &Expr) -> bool {
+	return expr.eq(&Expr::Unity) || is_number(expr, 1.0);
+}
+
+// A complex literal such as 0i is kept, dropping it would make the result real
+fn is_number(expr: &Expr, value: f64) -> bool {
+	match expr {
+		Expr::Number(num) => return !num.is_complex() && num.get_value() == Complex64::new(value, 0.0),
+		_ => return false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, shunter, ast::Expr, varnum::Variable};
+	use super::diff;
+
+	fn derivative(expr: &str, context: &Context) -> String {
+		let expr = Expr::from_rpn(&shunter::shunt(expr, context).unwrap()).unwrap();
+		let derivative = diff(&expr, &Variable::new("X"), context).unwrap();
+		return shunter::stringify_rpn(&derivative.to_rpn());
+	}
+
+	#[test]
+	fn power_with_variable_exponent() {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		assert_eq!(derivative("2^X", &context), "2,X,^,2,log,*,");
+		context.remove_function("log").unwrap();
+		assert_eq!(derivative("2^X", &context), "2,X,^,2,log,*,");
+		assert_eq!(derivative("X^2", &context), "2,X,*,");
+	}
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_variable(Variable::new("Y")).unwrap();
+		return context;
+	}
+
+	#[test]
+	fn chain_rule() {
+		let context = context();
+		assert_eq!(derivative("sin(X^2)", &context), "X,2,^,cos,2,X,*,*,");
+		assert_eq!(derivative("exp(sin(2*X))", &context), "2,X,*,sin,exp,2,X,*,cos,2,*,*,");
+		assert_eq!(derivative("-X", &context), "-1,");
+	}
+
+	#[test]
+	fn product_rule() {
+		let context = context();
+		assert_eq!(derivative("X*sin(X)", &context), "X,sin,X,X,cos,*,+,");
+		assert_eq!(derivative("Y*X*X", &context), "X,Y,*,Y,X,*,+,");
+	}
+
+	#[test]
+	fn quotient_rule() {
+		let context = context();
+		assert_eq!(derivative("X/Y", &context), "1,Y,/,");
+		assert_eq!(derivative("Y/X", &context), "Y,X,2,^,/,-,");
+		// 1/cos(X)*cos(X) - sin(X)/cos(X)^2*(-sin(X)), which is 1/cos(X)^2
+		assert_eq!(derivative("sin(X)/cos(X)", &context), "1,X,cos,/,X,cos,*,X,sin,X,cos,2,^,/,X,sin,-,*,-,");
+	}
+
+	#[test]
+	fn log_rule() {
+		let context = context();
+		assert_eq!(derivative("log(X)", &context), "1,X,/,");
+		assert_eq!(derivative("log(X^2 + 1)", &context), "1,X,2,^,1,+,/,2,X,*,*,");
+	}
+
+	#[test]
+	fn independent_terms_are_pruned() {
+		let context = context();
+		assert_eq!(derivative("Y", &context), "Zero,");
+		assert_eq!(derivative("3", &context), "Zero,");
+		assert_eq!(derivative("Y*log(Y)", &context), "Zero,");
+		assert_eq!(derivative("sin(Y)*X + Y^2", &context), "Y,sin,");
+		assert_eq!(derivative("max(Y, 2)*X", &context), "Y,2,max,");
+		assert_eq!(derivative("log(Y)*X^3", &context), "Y,log,3,X,2,^,*,*,");
+		assert_eq!(derivative("X - Y*X", &context), "Unity,Y,-,");
+	}
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+/// Byte range `start..end` of a token in the source expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+	pub start: usize,
+	pub end: usize,
+}
+
+impl Span {
+
+	pub fn new(start: usize, end: usize) -> Self {
+		Self {start, end}
+	}
+
+	/// The same range counted in chars instead of bytes.
+	pub fn to_char_span(&self, source: &str) -> Span {
+		let start = source[..self.start].chars().count();
+		let end = start + source[self.start..self.end].chars().count();
+		Span::new(start, end)
+	}
+
+}
+
+/// An error that points into the source expression. Its Display includes the offending
+/// line with the span underlined by carets.
+#[derive(Debug, Clone)]
+pub struct SpanError {
+	message: String,
+	span: Span,
+	line: usize,
+	column: usize,
+	rendered: String,
+}
+
+impl SpanError {
+
+	pub fn new(message: &str, span: Span, source: &str) -> Self {
+		let span = Span::new(span.start.min(source.len()), span.end.min(source.len()).max(span.start.min(source.len())));
+
+		let line_start = source[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+		let line_end = source[span.start..].find('\n').map(|i| span.start + i).unwrap_or(source.len());
+		let column = source[line_start..span.start].chars().count();
+		let width = source[span.start..span.end.min(line_end)].chars().count().max(1);
+
+		let rendered = format!("{}\n{}{}", &source[line_start..line_end], " ".repeat(column), "^".repeat(width));
+
+		Self {
+			message: message.to_string(),
+			span: span,
+			line: source[..span.start].matches('\n').count() + 1,
+			column: column + 1,
+			rendered: rendered,
+		}
+	}
+
+	pub fn get_message(&self) -> &str {
+		&self.message
+	}
+
+	pub fn get_span(&self) -> Span {
+		self.span
+	}
+
+	/// 1-based line and char column of the start of the span.
+	pub fn get_position(&self) -> (usize, usize) {
+		(self.line, self.column)
+	}
+
+	pub fn get_rendered(&self) -> &str {
+		&self.rendered
+	}
+
+}
+
+impl fmt::Display for SpanError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} at {}:{}\n{}", self.message, self.line, self.column, self.rendered)
+	}
+}
+
+impl std::error::Error for SpanError {}
+
+#[cfg(test)]
+mod tests {
+	use super::{Span, SpanError};
+
+	#[test]
+	fn caret_under_the_span() {
+		let err = SpanError::new("bad", Span::new(4, 7), "a + foo * b");
+		assert_eq!(err.get_rendered(), "a + foo * b\n    ^^^");
+		assert_eq!(err.to_string(), "bad at 1:5\na + foo * b\n    ^^^");
+	}
+
+	#[test]
+	fn only_the_line_of_the_span() {
+		let source = "x = 1\ny = x +\nz = y";
+		let err = SpanError::new("missing operand after '+'", Span::new(12, 13), source);
+		assert_eq!(err.get_position(), (2, 7));
+		assert_eq!(err.get_rendered(), "y = x +\n      ^");
+	}
+
+	// Columns count chars, an empty span at the end still gets a caret
+	#[test]
+	fn chars_and_empty_spans() {
+		let source = "\u{3B1} + \u{3B2}";
+		let err = SpanError::new("bad", Span::new(5, 7), source);
+		assert_eq!(err.get_position(), (1, 5));
+		assert_eq!(err.get_rendered(), "\u{3B1} + \u{3B2}\n    ^");
+		assert_eq!(Span::new(5, 7).to_char_span(source), Span::new(4, 5));
+		assert_eq!(SpanError::new("end", Span::new(7, 7), source).get_rendered(), "\u{3B1} + \u{3B2}\n     ^");
+	}
+}
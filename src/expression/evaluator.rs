@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+
+use num_complex::Complex64;
+use tch::{Kind, Tensor};
+
+use crate::expression::{
+	Token,
+	Context,
+	operators::{Op, Operator},
+	varnum::Variable,
+	cse::Cse,
+	definition::Definition,
+	program::Program,
+};
+
+pub fn evaluate(rpn: &Vec<Token>, context: &Context, variables: &HashMap<String, Tensor>) -> anyhow::Result<Tensor> {
+	let complex_kind = complex_kind(rpn, variables);
+	return eval_rpn(rpn, context.get_variables(), variables, &HashMap::new(), complex_kind);
+}
+
+/// Evaluates every temporary once, in order, and then the root that refers to them.
+pub fn evaluate_cse(cse: &Cse, context: &Context, variables: &HashMap<String, Tensor>) -> anyhow::Result<Tensor> {
+	let rpns: Vec<(&Variable, Vec<Token>)> = cse.temporaries.iter().map(|(var, expr)| (var, expr.to_rpn())).collect();
+	let root_rpn = cse.root.to_rpn();
+
+	let mut complex_kind = self::complex_kind(&root_rpn, variables);
+	for (_, rpn) in rpns.iter() {
+		if self::complex_kind(rpn, variables) == Kind::ComplexFloat {
+			complex_kind = Kind::ComplexFloat;
+		}
+	}
+
+	let mut temporaries: HashMap<String, Tensor> = HashMap::new();
+	for (var, rpn) in rpns.iter() {
+		let value = eval_rpn(rpn, context.get_variables(), variables, &temporaries, complex_kind)?;
+		temporaries.insert(var.get_token().to_owned(), value);
+	}
+	return eval_rpn(&root_rpn, context.get_variables(), variables, &temporaries, complex_kind);
+}
+
+/// Evaluates the statements in dependency order and returns the values of the outputs, see
+/// `Program::get_outputs`.
+pub fn evaluate_program(program: &Program, context: &Context, variables: &HashMap<String, Tensor>) -> anyhow::Result<HashMap<String, Tensor>> {
+	let mut complex_kind = Kind::ComplexDouble;
+	for statement in program.get_statements() {
+		if self::complex_kind(statement.get_rpn(), variables) == Kind::ComplexFloat {
+			complex_kind = Kind::ComplexFloat;
+		}
+	}
+
+	let mut values: HashMap<String, Tensor> = HashMap::new();
+	for statement in program.get_statements() {
+		let value = eval_rpn(statement.get_rpn(), context.get_variables(), variables, &values, complex_kind)?;
+		values.insert(statement.get_target().get_token().to_owned(), value);
+	}
+	let outputs = program.get_outputs();
+	values.retain(|name, _| outputs.iter().any(|output| output.get_token() == name));
+	return Ok(values);
+}
+
+/// Evaluates the body of a defined function as a call frame, its parameters bound to `args`.
+pub fn evaluate_definition(definition: &Definition, args: &[Tensor]) -> anyhow::Result<Tensor> {
+	let params = definition.get_params();
+	if args.len() != params.len() {
+		return Err(anyhow::anyhow!("function '{}' takes {} arguments but {} were given", definition.get_name(), params.len(), args.len()));
+	}
+	let mut bindings: HashMap<String, Tensor> = HashMap::new();
+	let mut complex_kind = Kind::ComplexDouble;
+	for (param, arg) in params.iter().zip(args.iter()) {
+		bindings.insert(param.get_token().to_owned(), arg.shallow_clone());
+		if is_single_precision(arg.kind()) {
+			complex_kind = Kind::ComplexFloat;
+		}
+	}
+	return eval_rpn(definition.get_rpn(), params, &bindings, &HashMap::new(), complex_kind);
+}
+
+// Variables must be one of `known`, the context variables or the parameters of a definition
+fn eval_rpn(rpn: &Vec<Token>, known: &[Variable], variables: &HashMap<String, Tensor>,
+	temporaries: &HashMap<String, Tensor>, complex_kind: Kind) -> anyhow::Result<Tensor>
+{
+	let mut stack: Vec<Tensor> = vec![];
+
+	for token in rpn.iter() {
+		match token {
+			Token::NoToken => {},
+			Token::Number(num) => stack.push(scalar_tensor(num.get_value(), num.is_complex(), complex_kind)),
+			Token::Constant(constant) => {
+				match constant.get_tensor() {
+					Some(tensor) => stack.push(tensor),
+					None => {
+						let value = constant.get_scalar().unwrap();
+						stack.push(scalar_tensor(value, value.im != 0.0, complex_kind));
+					},
+				}
+			},
+			Token::Zero => stack.push(Tensor::from(0.0f64)),
+			Token::Unity => stack.push(Tensor::from(1.0f64)),
+			Token::Variable(var) => {
+				if let Some(tensor) = temporaries.get(var.get_token()) {
+					stack.push(tensor.shallow_clone());
+					continue;
+				}
+				if !known.contains(var) {
+					return Err(anyhow::anyhow!("variable '{}' is not part of the context", var.get_token()));
+				}
+				match variables.get(var.get_token()) {
+					Some(tensor) => stack.push(tensor.shallow_clone()),
+					None => return Err(anyhow::anyhow!("unbound variable '{}'", var.get_token())),
+				}
+			},
+			Token::Operator(op) => {
+				let result = match op {
+					Operator::UnaryOperator(uop) => uop.evaluate(&pop_args(&mut stack, 1, op.get_token())?)?,
+					Operator::BinaryOperator(bop) => bop.evaluate(&pop_args(&mut stack, 2, op.get_token())?)?,
+				};
+				stack.push(result);
+			},
+			Token::Function(func) => {
+				let args = pop_args(&mut stack, func.get_n_inputs() as usize, func.get_token())?;
+				stack.push(func.evaluate(&args)?);
+			},
+			Token::LeftParen | Token::RightParen | Token::Comma => {
+				return Err(anyhow::anyhow!("{:?} must not be in a shunted expression", token.stringify()));
+			},
+		}
+	}
+
+	let result = stack.pop();
+	if !stack.is_empty() {
+		return Err(anyhow::anyhow!("expression left {} unused values on the stack", stack.len()));
+	}
+	match result {
+		Some(result) => return Ok(result),
+		None => return Err(anyhow::anyhow!("stack underflow, empty expression")),
+	}
+}
+
+fn pop_args(stack: &mut Vec<Tensor>, n: usize, token: &str) -> anyhow::Result<Vec<Tensor>> {
+	if stack.len() < n {
+		return Err(anyhow::anyhow!("stack underflow, '{}' takes {} arguments but only {} were available", token, n, stack.len()));
+	}
+	// split_off keeps the arguments in the order they were pushed
+	return Ok(stack.split_off(stack.len() - n));
+}
+
+fn scalar_tensor(value: Complex64, is_complex: bool, complex_kind: Kind) -> Tensor {
+	if is_complex {
+		return Tensor::complex(&Tensor::from(value.re), &Tensor::from(value.im)).to_kind(complex_kind);
+	}
+	return Tensor::from(value.re);
+}
+
+// Imaginary literals are promoted to complex64 when the bound variables are single precision
+// and to complex128 otherwise, so a literal never widens the precision of the result.
+fn complex_kind(rpn: &Vec<Token>, variables: &HashMap<String, Tensor>) -> Kind {
+	for token in rpn.iter() {
+		let tensor = match token {
+			Token::Variable(var) => variables.get(var.get_token()).map(|tensor| tensor.shallow_clone()),
+			Token::Constant(constant) => constant.get_tensor(),
+			_ => None,
+		};
+		if let Some(tensor) = tensor {
+			if is_single_precision(tensor.kind()) {
+				return Kind::ComplexFloat;
+			}
+		}
+	}
+	return Kind::ComplexDouble;
+}
+
+fn is_single_precision(kind: Kind) -> bool {
+	return matches!(kind, Kind::Half | Kind::BFloat16 | Kind::Float | Kind::ComplexHalf | Kind::ComplexFloat);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tch::{Kind, Tensor};
+
+	use crate::expression::{Context, shunter, varnum::Variable};
+	use super::evaluate;
+
+	fn evaluate_with(expr: &str, x: Tensor) -> Tensor {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		let mut variables = HashMap::new();
+		variables.insert(String::from("X"), x);
+		return evaluate(&shunter::shunt(expr, &context).unwrap(), &context, &variables).unwrap();
+	}
+
+	fn parts(tensor: &Tensor) -> (f64, f64) {
+		return (tensor.real().double_value(&[]), tensor.imag().double_value(&[]));
+	}
+
+	#[test]
+	fn complex_literals() {
+		let result = evaluate_with("X + 2i", Tensor::from(1.0f64));
+		assert_eq!(result.kind(), Kind::ComplexDouble);
+		assert_eq!(parts(&result), (1.0, 2.0));
+
+		let result = evaluate_with("(X + 1.5i)*(X - 1.5i)", Tensor::from(2.0f64));
+		assert_eq!(parts(&result), (6.25, 0.0));
+
+		// 0i is zero but still makes the result complex
+		let result = evaluate_with("X + 0i", Tensor::from(3.0f64));
+		assert_eq!(result.kind(), Kind::ComplexDouble);
+		assert_eq!(parts(&result), (3.0, 0.0));
+
+		assert_eq!(evaluate_with("X + 2", Tensor::from(1.0f64)).kind(), Kind::Double);
+	}
+
+	#[test]
+	fn complex_precision_follows_the_variables() {
+		assert_eq!(evaluate_with("X*1i", Tensor::from(1.0f32)).kind(), Kind::ComplexFloat);
+		assert_eq!(evaluate_with("X*1i", Tensor::from(1.0f64)).kind(), Kind::ComplexDouble);
+		let x = Tensor::complex(&Tensor::from(1.0f32), &Tensor::from(0.0f32));
+		assert_eq!(evaluate_with("X*1i", x).kind(), Kind::ComplexFloat);
+		// Without variables the literal keeps double precision
+		let context = Context::new();
+		let result = evaluate(&shunter::shunt("2i", &context).unwrap(), &context, &HashMap::new()).unwrap();
+		assert_eq!(result.kind(), Kind::ComplexDouble);
+	}
+}
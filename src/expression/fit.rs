@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+
+use tch::{Kind, Tensor};
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	derivative,
+	evaluator,
+	simplify,
+	varnum::{Parameter, Transform},
+};
+
+/// How the Jacobian of the model with respect to the parameters is computed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jacobian {
+	/// Backpropagation through the evaluated model.
+	Autograd,
+	/// Evaluating the simplified symbolic derivatives of the model.
+	Symbolic,
+}
+
+#[derive(Debug, Clone)]
+pub struct FitOptions {
+	pub max_iterations: usize,
+	/// A voxel has converged when an accepted step lowers its cost by less than this
+	/// fraction, or when its step is this small relative to its parameters.
+	pub tolerance: f64,
+	/// The initial Levenberg-Marquardt damping.
+	pub damping: f64,
+	pub jacobian: Jacobian,
+}
+
+impl Default for FitOptions {
+
+	fn default() -> Self {
+		FitOptions {
+			max_iterations: 100,
+			tolerance: 1e-8,
+			damping: 1e-3,
+			jacobian: Jacobian::Symbolic,
+		}
+	}
+
+}
+
+/// Per voxel results, the batch dimension comes first.
+#[derive(Debug)]
+pub struct FitResult {
+	/// Fitted value of every parameter, shape `[batch]`.
+	pub parameters: HashMap<String, Tensor>,
+	/// Standard error of every parameter from the curvature at the fit, shape `[batch]`. It is
+	/// estimated for the unconstrained value and mapped through the transform's derivative.
+	/// NaN for voxels whose curvature is singular.
+	pub uncertainties: HashMap<String, Tensor>,
+	/// Model minus data at the fitted parameters, shape `[batch, points]`.
+	pub residuals: Tensor,
+	/// Sum of squared residuals, shape `[batch]`.
+	pub cost: Tensor,
+	/// Whether the voxel met the tolerance, boolean of shape `[batch]`.
+	pub converged: Tensor,
+	pub iterations: usize,
+}
+
+/// Fits the parameters of the context to `data` of shape `[batch, points]`, all voxels at once
+/// with Levenberg-Marquardt. `independent` binds the other variables to tensors that broadcast
+/// against `data`, e.g. echo times of shape `[points]`. `initial` gives every parameter a
+/// starting value of shape `[batch]` or a scalar, inside its bounds. Bounded parameters are
+/// fitted in the unconstrained space of their transform.
+pub fn fit(expr: &Expr, context: &Context, data: &Tensor, independent: &HashMap<String, Tensor>,
+	initial: &HashMap<String, Tensor>, options: &FitOptions) -> anyhow::Result<FitResult>
+{
+	let params = context.get_parameters();
+	if params.is_empty() {
+		return Err(anyhow::anyhow!("the context has no parameters to fit"));
+	}
+	let size = data.size();
+	if size.len() != 2 {
+		return Err(anyhow::anyhow!("data must have shape [batch, points], not {:?}", size));
+	}
+	let batch = size[0];
+	let data = data.f_to_kind(Kind::Double)?;
+
+	let mut columns: Vec<Tensor> = vec![];
+	for param in params.iter() {
+		let value = match initial.get(param.get_token()) {
+			Some(value) => value,
+			None => return Err(anyhow::anyhow!("no initial value for parameter '{}'", param.get_token())),
+		};
+		let value = value.f_to_kind(Kind::Double)?.f_to_device(data.device())?.f_expand(&[batch], false)?;
+		let u = to_unconstrained(param, &value)?;
+		if u.f_isfinite()?.f_all()?.int64_value(&[]) == 0 {
+			return Err(anyhow::anyhow!("the initial value of parameter '{}' is not inside its bounds", param.get_token()));
+		}
+		columns.push(u);
+	}
+
+	let model = Model {
+		rpn: expr.to_rpn(),
+		derivatives: match options.jacobian {
+			Jacobian::Symbolic => params.iter()
+				.map(|param| Ok(simplify::simplify(&derivative::diff(expr, param.get_variable(), context)?, context)?.to_rpn()))
+				.collect::<anyhow::Result<Vec<Vec<Token>>>>()?,
+			Jacobian::Autograd => vec![],
+		},
+		context: context,
+		independent: independent,
+		data: &data,
+	};
+
+	let mut p = Tensor::f_stack(&columns, 1)?.f_contiguous()?;
+	let mut r = model.residuals(&p)?;
+	let mut cost = squared_sum(&r)?;
+	let mut damping = data.f_new_full(&[batch], options.damping, (Kind::Double, data.device()))?;
+	let mut converged = data.f_new_zeros(&[batch], (Kind::Bool, data.device()))?;
+	let mut active = data.f_new_ones(&[batch], (Kind::Bool, data.device()))?;
+
+	let mut iterations = 0;
+	while iterations < options.max_iterations && active.f_any()?.int64_value(&[]) != 0 {
+		iterations += 1;
+
+		// Solve (JtJ + damping*diag(JtJ)) step = -Jt r for every voxel
+		let j = match options.jacobian {
+			Jacobian::Symbolic => model.symbolic_jacobian(&p)?,
+			Jacobian::Autograd => model.autograd_jacobian(&p)?,
+		};
+		let jt = j.f_transpose(1, 2)?;
+		let jtj = jt.f_matmul(&j)?;
+		let g = jt.f_matmul(&r.f_unsqueeze(2)?)?;
+		let scale = jtj.f_diagonal(0, 1, 2)?.f_clamp_min(f64::MIN_POSITIVE)?;
+		let a = jtj.f_add(&scale.f_mul(&damping.f_unsqueeze(1)?)?.f_diag_embed(0, 1, 2)?)?;
+		// A voxel with a singular system takes no step, so its damping grows until it stalls,
+		// the other voxels are unaffected
+		let (inverse, info) = a.f_linalg_inv_ex(false)?;
+		let solvable = info.f_eq(0)?.f_logical_and(&active)?.f_unsqueeze(1)?;
+		let step = inverse.f_matmul(&g.f_neg()?)?.f_squeeze_dim(2)?;
+		let step = step.f_where_self(&solvable, &step.f_zeros_like()?)?;
+
+		let trial = p.f_add(&step)?;
+		let trial_r = model.residuals(&trial)?;
+		let trial_cost = squared_sum(&trial_r)?;
+
+		let better = trial_cost.f_lt_tensor(&cost)?.f_logical_and(&active)?;
+		let decrease = cost.f_sub(&trial_cost)?.f_div(&cost.f_clamp_min(f64::MIN_POSITIVE)?)?;
+		let step_norm = step.f_mul(&step)?.f_sum_dim_intlist(&[1], false, Kind::Double)?.f_sqrt()?;
+		let p_norm = p.f_mul(&p)?.f_sum_dim_intlist(&[1], false, Kind::Double)?.f_sqrt()?;
+		let small_step = step_norm.f_le_tensor(&p_norm.f_add_scalar(options.tolerance)?.f_mul_scalar(options.tolerance)?)?;
+		let small_decrease = better.f_logical_and(&decrease.f_lt(options.tolerance)?)?;
+
+		p = trial.f_where_self(&better.f_unsqueeze(1)?, &p)?;
+		r = trial_r.f_where_self(&better.f_unsqueeze(1)?, &r)?;
+		cost = trial_cost.f_where_self(&better, &cost)?;
+		damping = damping.f_mul_scalar(0.1)?.f_where_self(&better, &damping.f_mul_scalar(10.0)?)?;
+
+		let done = small_step.f_logical_or(&small_decrease)?.f_logical_and(&active)?;
+		converged = converged.f_logical_or(&done)?;
+		// Voxels whose damping keeps growing make no more progress
+		let stalled = damping.f_gt(1e16)?;
+		active = active.f_logical_and(&done.f_logical_not()?)?.f_logical_and(&stalled.f_logical_not()?)?;
+	}
+
+	// Standard errors from the covariance s^2 (JtJ)^-1 of the unconstrained values
+	let n_points = size[1] as f64;
+	let n_params = params.len() as f64;
+	let variance = if n_points > n_params {
+		cost.f_div_scalar(n_points - n_params)?
+	} else {
+		cost.f_full_like(f64::NAN)?
+	};
+	let j = match options.jacobian {
+		Jacobian::Symbolic => model.symbolic_jacobian(&p)?,
+		Jacobian::Autograd => model.autograd_jacobian(&p)?,
+	};
+	// Voxels whose JtJ is singular get NaN uncertainties
+	let (covariance, info) = j.f_transpose(1, 2)?.f_matmul(&j)?.f_linalg_inv_ex(false)?;
+	let sigma = covariance.f_diagonal(0, 1, 2)?.f_mul(&variance.f_unsqueeze(1)?)?.f_sqrt()?;
+	let sigma = sigma.f_where_self(&info.f_eq(0)?.f_unsqueeze(1)?, &sigma.f_full_like(f64::NAN)?)?;
+
+	let mut parameters: HashMap<String, Tensor> = HashMap::new();
+	let mut uncertainties: HashMap<String, Tensor> = HashMap::new();
+	for (i, param) in params.iter().enumerate() {
+		let u = p.f_select(1, i as i64)?;
+		parameters.insert(param.get_token().to_owned(), to_constrained(param, &u)?);
+		let slope = constrained_slope(param, &u)?.f_abs()?;
+		uncertainties.insert(param.get_token().to_owned(), sigma.f_select(1, i as i64)?.f_mul(&slope)?);
+	}
+	return Ok(FitResult {parameters, uncertainties, residuals: r, cost, converged, iterations});
+}
+
+struct Model<'a> {
+	rpn: Vec<Token>,
+	derivatives: Vec<Vec<Token>>,
+	context: &'a Context,
+	independent: &'a HashMap<String, Tensor>,
+	data: &'a Tensor,
+}
+
+impl<'a> Model<'a> {
+
+	// Column i of p, of shape [batch, n_params] or [batch, points, n_params], holds the
+	// unconstrained value of parameter i, it is bound so that it broadcasts against the data
+	fn bind(&self, p: &Tensor) -> anyhow::Result<HashMap<String, Tensor>> {
+		let mut variables: HashMap<String, Tensor> = self.independent.iter()
+			.map(|(name, tensor)| (name.clone(), tensor.shallow_clone()))
+			.collect();
+		let last = p.dim() as i64 - 1;
+		for (i, param) in self.context.get_parameters().iter().enumerate() {
+			let mut column = to_constrained(param, &p.f_select(last, i as i64)?)?;
+			if last == 1 {
+				column = column.f_unsqueeze(1)?;
+			}
+			variables.insert(param.get_token().to_owned(), column);
+		}
+		return Ok(variables);
+	}
+
+	fn evaluate(&self, rpn: &Vec<Token>, p: &Tensor) -> anyhow::Result<Tensor> {
+		let value = evaluator::evaluate(rpn, self.context, &self.bind(p)?)?;
+		return Ok(value.f_to_kind(Kind::Double)?.f_expand_as(self.data)?);
+	}
+
+	fn residuals(&self, p: &Tensor) -> anyhow::Result<Tensor> {
+		return Ok(self.evaluate(&self.rpn, p)?.f_sub(self.data)?);
+	}
+
+	// The chain rule through the transforms, the symbolic derivatives are taken with respect
+	// to the constrained values
+	fn symbolic_jacobian(&self, p: &Tensor) -> anyhow::Result<Tensor> {
+		let params = self.context.get_parameters();
+		let mut columns: Vec<Tensor> = vec![];
+		for (i, rpn) in self.derivatives.iter().enumerate() {
+			let slope = constrained_slope(&params[i], &p.f_select(1, i as i64)?)?.f_unsqueeze(1)?;
+			columns.push(self.evaluate(rpn, p)?.f_mul(&slope)?);
+		}
+		return Ok(Tensor::f_stack(&columns, 2)?);
+	}
+
+	// Every point gets its own copy of the parameters, so a single backward pass of the
+	// summed model gives the derivative of each point with respect to its copy
+	fn autograd_jacobian(&self, p: &Tensor) -> anyhow::Result<Tensor> {
+		let size = self.data.size();
+		let n_params = p.size()[1];
+		let copies = p.f_detach()?.f_unsqueeze(1)?.f_expand(&[size[0], size[1], n_params], false)?
+			.f_contiguous()?.f_set_requires_grad(true)?;
+		let value = self.evaluate(&self.rpn, &copies)?;
+		// Parameters that do not appear in the expression get a zero column
+		if !value.requires_grad() {
+			return Ok(copies.f_zeros_like()?);
+		}
+		let grads = Tensor::f_run_backward(&[value.f_sum(Kind::Double)?], &[&copies], false, false)?;
+		if !grads[0].defined() {
+			return Ok(copies.f_zeros_like()?);
+		}
+		return Ok(grads[0].shallow_clone());
+	}
+
+}
+
+fn squared_sum(r: &Tensor) -> anyhow::Result<Tensor> {
+	return Ok(r.f_mul(r)?.f_sum_dim_intlist(&[1], false, Kind::Double)?);
+}
+
+// (lower, upper - lower) of a parameter with a transform that needs them
+fn bounds(param: &Parameter) -> (f64, f64) {
+	let (lower, upper) = param.get_bounds();
+	let lower = lower.unwrap_or(0.0);
+	return (lower, upper.unwrap_or(lower + 1.0) - lower);
+}
+
+fn to_constrained(param: &Parameter, u: &Tensor) -> anyhow::Result<Tensor> {
+	let (lower, width) = bounds(param);
+	let x = match param.get_transform() {
+		Transform::Identity => return Ok(u.shallow_clone()),
+		Transform::Log => u.f_exp()?,
+		Transform::Softplus => u.f_softplus()?,
+		Transform::Logit => u.f_sigmoid()?.f_mul_scalar(width)?,
+		Transform::Box => u.f_sin()?.f_add_scalar(1.0)?.f_mul_scalar(width / 2.0)?,
+	};
+	return Ok(x.f_add_scalar(lower)?);
+}
+
+fn to_unconstrained(param: &Parameter, x: &Tensor) -> anyhow::Result<Tensor> {
+	let (lower, width) = bounds(param);
+	let y = x.f_sub_scalar(lower)?;
+	match param.get_transform() {
+		Transform::Identity => return Ok(x.shallow_clone()),
+		Transform::Log => return Ok(y.f_log()?),
+		// log(exp(y) - 1) written as y + log(-expm1(-y)) to stay finite for large y
+		Transform::Softplus => return Ok(y.f_add(&y.f_neg()?.f_expm1()?.f_neg()?.f_log()?)?),
+		Transform::Logit => return Ok(y.f_div_scalar(width)?.f_logit(None)?),
+		Transform::Box => return Ok(y.f_mul_scalar(2.0 / width)?.f_sub_scalar(1.0)?.f_asin()?),
+	}
+}
+
+// dx/du of the transform
+fn constrained_slope(param: &Parameter, u: &Tensor) -> anyhow::Result<Tensor> {
+	let (_, width) = bounds(param);
+	match param.get_transform() {
+		Transform::Identity => return Ok(u.f_ones_like()?),
+		Transform::Log => return Ok(u.f_exp()?),
+		Transform::Softplus => return Ok(u.f_sigmoid()?),
+		Transform::Logit => {
+			let s = u.f_sigmoid()?;
+			return Ok(s.f_mul(&s.f_neg()?.f_add_scalar(1.0)?)?.f_mul_scalar(width)?);
+		},
+		Transform::Box => return Ok(u.f_cos()?.f_mul_scalar(width / 2.0)?),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use tch::Tensor;
+	use crate::expression::{Context, ast, varnum::{Variable, Parameter, Transform}};
+	use super::{fit, FitOptions, Jacobian};
+
+	fn echo_times() -> Vec<f64> {
+		return (0..21).map(|i| 5.0 * i as f64).collect();
+	}
+
+	fn decay_context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_parameter(Parameter::new("A")).unwrap();
+		context.add_parameter(Parameter::new("T")).unwrap();
+		return context;
+	}
+
+	// A*exp(-X/T) for every (A, T), one voxel per row
+	fn decay_data(voxels: &[(f64, f64)]) -> Tensor {
+		let rows: Vec<Tensor> = voxels.iter()
+			.map(|(a, t)| Tensor::of_slice(&echo_times().iter().map(|x| a * (-x / t).exp()).collect::<Vec<f64>>()))
+			.collect();
+		return Tensor::stack(&rows, 0);
+	}
+
+	fn scalars(values: &[(&str, f64)]) -> HashMap<String, Tensor> {
+		return values.iter().map(|(name, value)| (name.to_string(), Tensor::from(*value))).collect();
+	}
+
+	fn independent() -> HashMap<String, Tensor> {
+		let mut independent = HashMap::new();
+		independent.insert(String::from("X"), Tensor::of_slice(&echo_times()));
+		return independent;
+	}
+
+	fn recovers_decay(jacobian: Jacobian) {
+		let context = decay_context();
+		let expr = ast::build("A*exp(-X/T)", &context).unwrap();
+		let voxels = [(1.0, 10.0), (2.0, 20.0), (3.0, 40.0)];
+		let options = FitOptions {jacobian: jacobian, ..Default::default()};
+		let result = fit(&expr, &context, &decay_data(&voxels), &independent(), &scalars(&[("A", 1.5), ("T", 15.0)]), &options).unwrap();
+		for (i, (a, t)) in voxels.iter().enumerate() {
+			assert!((result.parameters["A"].double_value(&[i as i64]) - a).abs() < 1e-6);
+			assert!((result.parameters["T"].double_value(&[i as i64]) - t).abs() < 1e-5);
+		}
+	}
+
+	#[test]
+	fn recovers_decay_with_symbolic_jacobian() {
+		recovers_decay(Jacobian::Symbolic);
+	}
+
+	#[test]
+	fn recovers_decay_with_autograd() {
+		recovers_decay(Jacobian::Autograd);
+	}
+
+	// The data pull c below the lower bound in one voxel and above the upper one in the other
+	#[test]
+	fn transformed_parameters_stay_inside_their_bounds() {
+		let cases = [
+			(Transform::Log, Some(0.0), None),
+			(Transform::Softplus, Some(1.0), None),
+			(Transform::Logit, Some(1.0), Some(2.0)),
+			(Transform::Box, Some(1.0), Some(2.0)),
+		];
+		for (transform, lower, upper) in cases {
+			let mut context = Context::new();
+			context.add_variable(Variable::new("X")).unwrap();
+			context.add_parameter(Parameter::new("c").with_bounds(lower, upper).with_transform(transform)).unwrap();
+			let expr = ast::build("c*X", &context).unwrap();
+			let data = Tensor::of_slice(&[-5.0, -5.0, -5.0, 5.0, 5.0, 5.0]).reshape(&[2, 3]);
+			let mut independent = HashMap::new();
+			independent.insert(String::from("X"), Tensor::of_slice(&[1.0, 1.0, 1.0]));
+			let result = fit(&expr, &context, &data, &independent, &scalars(&[("c", 1.5)]), &FitOptions::default()).unwrap();
+			for i in 0..2 {
+				let c = result.parameters["c"].double_value(&[i]);
+				assert!(c >= lower.unwrap(), "{:?} gave {}", transform, c);
+				assert!(c <= upper.unwrap_or(f64::INFINITY), "{:?} gave {}", transform, c);
+			}
+		}
+	}
+
+	// With A = 0 the model does not depend on T, which makes the system of that voxel singular
+	#[test]
+	fn singular_voxel_does_not_stop_the_others() {
+		let context = decay_context();
+		let expr = ast::build("A*exp(-X/T)", &context).unwrap();
+		let data = decay_data(&[(0.0, 10.0), (2.0, 20.0)]);
+		let mut initial = scalars(&[("T", 15.0)]);
+		initial.insert(String::from("A"), Tensor::of_slice(&[0.0, 1.5]));
+		for jacobian in [Jacobian::Symbolic, Jacobian::Autograd] {
+			let options = FitOptions {jacobian: jacobian, ..Default::default()};
+			let result = fit(&expr, &context, &data, &independent(), &initial, &options).unwrap();
+			assert!((result.parameters["A"].double_value(&[1]) - 2.0).abs() < 1e-6);
+			assert!((result.parameters["T"].double_value(&[1]) - 20.0).abs() < 1e-5);
+		}
+	}
+
+	// The standard errors of a voxel are sqrt(s^2 (JtJ)^-1) in the constrained space, whatever
+	// the transform. With A = 0 the second voxel can not identify B.
+	#[test]
+	fn uncertainties() {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_parameter(Parameter::new("A")).unwrap();
+		context.add_parameter(Parameter::new("B").with_bounds(Some(0.0), None).with_transform(Transform::Log)).unwrap();
+		let expr = ast::build("A*exp(-B*X)", &context).unwrap();
+		let xs = echo_times();
+		let noisy: Vec<f64> = xs.iter().enumerate()
+			.map(|(i, x)| 2.0 * (-0.05 * x).exp() + if i % 2 == 0 { 0.01 } else { -0.01 })
+			.collect();
+		let data = Tensor::stack(&[Tensor::of_slice(&noisy), Tensor::zeros(&[xs.len() as i64], (tch::Kind::Double, tch::Device::Cpu))], 0);
+		let mut initial = scalars(&[("B", 0.1)]);
+		initial.insert(String::from("A"), Tensor::of_slice(&[1.5, 0.0]));
+		let result = fit(&expr, &context, &data, &independent(), &initial, &FitOptions::default()).unwrap();
+
+		let (a, b) = (result.parameters["A"].double_value(&[0]), result.parameters["B"].double_value(&[0]));
+		let (mut saa, mut sab, mut sbb) = (0.0, 0.0, 0.0);
+		for x in xs.iter() {
+			let (da, db) = ((-b * x).exp(), -a * x * (-b * x).exp());
+			saa += da * da;
+			sab += da * db;
+			sbb += db * db;
+		}
+		let det = saa * sbb - sab * sab;
+		let variance = result.cost.double_value(&[0]) / (xs.len() as f64 - 2.0);
+		let sigma_a = (variance * sbb / det).sqrt();
+		let sigma_b = (variance * saa / det).sqrt();
+		assert!((result.uncertainties["A"].double_value(&[0]) / sigma_a - 1.0).abs() < 1e-6);
+		assert!((result.uncertainties["B"].double_value(&[0]) / sigma_b - 1.0).abs() < 1e-6);
+
+		assert!(result.uncertainties["A"].double_value(&[1]).is_nan());
+		assert!(result.uncertainties["B"].double_value(&[1]).is_nan());
+	}
+}
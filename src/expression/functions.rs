@@ -1,58 +1,406 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tch::{Kind, Tensor};
 
 use crate::expression::{
     Context,
-    Token,
+    ast::Expr,
+    definition::Definition,
+    derivative::{add, sub, mul, div, pow, neg, log},
+    varnum::Number,
 };
 
-#[derive(Debug, Clone, PartialEq)]
+/// Evaluates a function on its arguments.
+pub type TensorFn = Arc<dyn Fn(&[Tensor]) -> anyhow::Result<Tensor> + Send + Sync>;
+
+/// Builds the partial derivative of a function with respect to argument `i`.
+pub type DerivativeFn = Arc<dyn Fn(&[Expr], usize, &Context) -> anyhow::Result<Expr> + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Function {
     token: String,
     n_inputs: u8,
+    is_variadic: bool,
+    eval: Option<TensorFn>,
+    derivative: Option<DerivativeFn>,
+    definition: Option<Arc<Definition>>,
 }
 
 impl Function {
 
     pub fn new(token: &str, n_inputs: u8) -> Self {
-        Self {token: token.to_string(), n_inputs: n_inputs}
+        Self {token: token.to_string(), n_inputs: n_inputs, is_variadic: false, eval: None, derivative: None, definition: None}
+    }
+
+    /// A function taking `min_inputs` or more arguments.
+    pub fn new_variadic(token: &str, min_inputs: u8) -> Self {
+        Self {token: token.to_string(), n_inputs: min_inputs, is_variadic: true, eval: None, derivative: None, definition: None}
+    }
+
+    pub fn with_eval<F>(mut self, eval: F) -> Self
+        where F: Fn(&[Tensor]) -> anyhow::Result<Tensor> + Send + Sync + 'static
+    {
+        self.eval = Some(Arc::new(eval));
+        self
+    }
+
+    pub fn with_derivative<F>(mut self, derivative: F) -> Self
+        where F: Fn(&[Expr], usize, &Context) -> anyhow::Result<Expr> + Send + Sync + 'static
+    {
+        self.derivative = Some(Arc::new(derivative));
+        self
+    }
+
+    pub fn with_definition(mut self, definition: Arc<Definition>) -> Self {
+        self.definition = Some(definition);
+        self
     }
 
     pub fn get_token(&self) -> &str {
         &self.token
     }
 
+    /// The number of arguments, for variadic functions in the context this is the minimum
+    /// number of arguments and in shunted output it is the number the function was called with.
     pub fn get_n_inputs(&self) -> u8 {
         self.n_inputs
     }
 
-}
+    pub fn is_variadic(&self) -> bool {
+        self.is_variadic
+    }
 
-pub (super) fn begins_with_function<'a>(expr: &str, _tokens: &Vec<Token>, context: &'a Context) -> anyhow::Result<Option<&'a Function>> {
-    for func in context.functions.iter() {
-        if expr.starts_with(&func.token) {
-            let flen = func.token.len();
-            let lpar = expr[flen..].find("(");
-            let rpar = expr[flen..].find(")");
-            if lpar.is_some() && rpar.is_some() {
-                let llen = lpar.unwrap();
-                let rlen = rpar.unwrap();
-                let argsubstr = &expr[flen+llen..flen+rlen];
-                let commaocs = argsubstr.match_indices(',').count();
-                if commaocs == (func.get_n_inputs() - 1) as usize {
-                    return Ok(Some(func));
-                }
-                return Err(anyhow::anyhow!("Matched with function signature but the number of commas was inconsistent with number of arguments for function"));
-            }
-            return Err(anyhow::anyhow!("Matched with a function signature but opening and closing parentheses did not follow"));
+    pub fn get_eval(&self) -> Option<&TensorFn> {
+        self.eval.as_ref()
+    }
+
+    pub fn get_derivative(&self) -> Option<&DerivativeFn> {
+        self.derivative.as_ref()
+    }
+
+    /// The definition of a function written in the expression language.
+    pub fn get_definition(&self) -> Option<&Definition> {
+        self.definition.as_deref()
+    }
+
+    pub fn accepts_n_inputs(&self, n: usize) -> bool {
+        if self.is_variadic {
+            return n >= self.n_inputs as usize && n <= u8::MAX as usize;
+        }
+        n == self.n_inputs as usize
+    }
+
+    /// The function as it appears when called with `n` arguments.
+    pub fn called_with(&self, n: u8) -> Function {
+        let mut func = self.clone();
+        if self.is_variadic {
+            func.n_inputs = n;
+        }
+        func
+    }
+
+    pub fn evaluate(&self, args: &[Tensor]) -> anyhow::Result<Tensor> {
+        match &self.eval {
+            Some(eval) => eval(args),
+            None => Err(anyhow::anyhow!("no evaluation rule for function '{}'", self.token)),
+        }
+    }
+
+    pub fn partial_derivative(&self, args: &[Expr], i: usize, context: &Context) -> anyhow::Result<Expr> {
+        match &self.derivative {
+            Some(derivative) => derivative(args, i, context),
+            None => Err(anyhow::anyhow!("no derivative rule for function '{}'", self.token)),
         }
     }
-    Ok(None)
+
 }
 
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("token", &self.token)
+            .field("n_inputs", &self.n_inputs)
+            .field("is_variadic", &self.is_variadic)
+            .finish()
+    }
+}
+
+// Functions are identified by their signature, the rules they carry are not compared
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.token == other.token && self.n_inputs == other.n_inputs && self.is_variadic == other.is_variadic
+    }
+}
+
+fn num(value: f64) -> Expr {
+    Expr::Number(Number::from_real(value))
+}
+
+fn call1(context: &Context, token: &str, arg: &Expr) -> anyhow::Result<Expr> {
+    Expr::call(context, token, vec![arg.clone()])
+}
+
+// Heaviside step of x written with sign, (1+sign(x))/2. It is 1/2 at 0, so at a tie of max
+// or min each of the tied arguments gets half of the derivative.
+fn step(context: &Context, x: Expr) -> anyhow::Result<Expr> {
+    let sign = Expr::call(context, "sign", vec![x])?;
+    div(context, add(context, num(1.0), sign)?, num(2.0))
+}
+
+// The extremum of the other arguments of a variadic max or min
+fn others(context: &Context, token: &str, args: &[Expr], i: usize) -> anyhow::Result<Expr> {
+    let mut others: Vec<Expr> = args.to_vec();
+    others.remove(i);
+    if others.len() == 1 {
+        return Ok(others.pop().unwrap());
+    }
+    Expr::call(context, token, others)
+}
+
+fn unary(token: &str, eval: fn(&Tensor) -> Result<Tensor, tch::TchError>) -> Function {
+    Function::new(token, 1).with_eval(move |args| Ok(eval(&args[0])?))
+}
 
 pub fn default_functions() -> Vec<Function> {
     return vec![
-        Function::new("sin", 1),
-		Function::new("cos", 1),
-		Function::new("max", 2),
+        unary("sin", Tensor::f_sin)
+            .with_derivative(|args, _, context| call1(context, "cos", &args[0])),
+        unary("cos", Tensor::f_cos)
+            .with_derivative(|args, _, context| neg(context, call1(context, "sin", &args[0])?)),
+        unary("tan", Tensor::f_tan)
+            .with_derivative(|args, _, context| {
+                let cos = call1(context, "cos", &args[0])?;
+                div(context, num(1.0), pow(context, cos, num(2.0))?)
+            }),
+        unary("exp", Tensor::f_exp)
+            .with_derivative(|args, _, context| call1(context, "exp", &args[0])),
+        unary("log", Tensor::f_log)
+            .with_derivative(|args, _, context| div(context, num(1.0), args[0].clone())),
+        unary("log10", Tensor::f_log10)
+            .with_derivative(|args, _, context| {
+                div(context, num(1.0), mul(context, args[0].clone(), num(std::f64::consts::LN_10))?)
+            }),
+        unary("sqrt", Tensor::f_sqrt)
+            .with_derivative(|args, _, context| div(context, num(0.5), call1(context, "sqrt", &args[0])?)),
+        unary("abs", Tensor::f_abs)
+            .with_derivative(|args, _, context| call1(context, "sign", &args[0])),
+        unary("sinh", Tensor::f_sinh)
+            .with_derivative(|args, _, context| call1(context, "cosh", &args[0])),
+        unary("cosh", Tensor::f_cosh)
+            .with_derivative(|args, _, context| call1(context, "sinh", &args[0])),
+        unary("tanh", Tensor::f_tanh)
+            .with_derivative(|args, _, context| {
+                let tanh = call1(context, "tanh", &args[0])?;
+                sub(context, num(1.0), pow(context, tanh, num(2.0))?)
+            }),
+        unary("asin", Tensor::f_asin)
+            .with_derivative(|args, _, context| {
+                let root = call1(context, "sqrt", &sub(context, num(1.0), pow(context, args[0].clone(), num(2.0))?)?)?;
+                div(context, num(1.0), root)
+            }),
+        unary("acos", Tensor::f_acos)
+            .with_derivative(|args, _, context| {
+                let root = call1(context, "sqrt", &sub(context, num(1.0), pow(context, args[0].clone(), num(2.0))?)?)?;
+                div(context, num(-1.0), root)
+            }),
+        unary("atan", Tensor::f_atan)
+            .with_derivative(|args, _, context| {
+                div(context, num(1.0), add(context, num(1.0), pow(context, args[0].clone(), num(2.0))?)?)
+            }),
+        Function::new("atan2", 2)
+            .with_eval(|args| Ok(args[0].f_atan2(&args[1])?))
+            .with_derivative(|args, i, context| {
+                // atan2(y, x), d/dy = x/(x^2+y^2) and d/dx = -y/(x^2+y^2)
+                let (y, x) = (args[0].clone(), args[1].clone());
+                let norm = add(context, pow(context, x.clone(), num(2.0))?, pow(context, y.clone(), num(2.0))?)?;
+                if i == 0 {
+                    return div(context, x, norm);
+                }
+                div(context, neg(context, y)?, norm)
+            }),
+        Function::new_variadic("max", 2)
+            .with_eval(|args| {
+                let mut acc = args[0].shallow_clone();
+                for arg in args[1..].iter() {
+                    acc = acc.f_maximum(arg)?;
+                }
+                Ok(acc)
+            })
+            // d/da max(a, b) = (1 + sign(a - b))/2, which is 1/2 where a == b
+            .with_derivative(|args, i, context| {
+                let other = others(context, "max", args, i)?;
+                step(context, sub(context, args[i].clone(), other)?)
+            }),
+        Function::new_variadic("min", 2)
+            .with_eval(|args| {
+                let mut acc = args[0].shallow_clone();
+                for arg in args[1..].iter() {
+                    acc = acc.f_minimum(arg)?;
+                }
+                Ok(acc)
+            })
+            // d/da min(a, b) = (1 + sign(b - a))/2, which is 1/2 where a == b
+            .with_derivative(|args, i, context| {
+                let other = others(context, "min", args, i)?;
+                step(context, sub(context, other, args[i].clone())?)
+            }),
+        Function::new_variadic("sum", 1)
+            .with_eval(|args| {
+                let mut acc = args[0].shallow_clone();
+                for arg in args[1..].iter() {
+                    acc = acc.f_add(arg)?;
+                }
+                Ok(acc)
+            })
+            .with_derivative(|_, _, _| Ok(Expr::Unity)),
+        Function::new("pow", 2)
+            .with_eval(|args| Ok(args[0].f_pow(&args[1])?))
+            .with_derivative(|args, i, context| {
+                let (base, exponent) = (args[0].clone(), args[1].clone());
+                if i == 0 {
+                    let lowered = Expr::call(context, "pow", vec![base, sub(context, exponent.clone(), Expr::Unity)?])?;
+                    return mul(context, exponent, lowered);
+                }
+                let log = log(context, base.clone())?;
+                mul(context, Expr::call(context, "pow", vec![base, exponent])?, log)
+            }),
+        unary("sign", Tensor::f_sign)
+            .with_derivative(|_, _, _| Ok(Expr::Zero)),
+        unary("floor", Tensor::f_floor)
+            .with_derivative(|_, _, _| Ok(Expr::Zero)),
+        unary("ceil", Tensor::f_ceil)
+            .with_derivative(|_, _, _| Ok(Expr::Zero)),
+        unary("round", Tensor::f_round)
+            .with_derivative(|_, _, _| Ok(Expr::Zero)),
+        Function::new("clamp", 3)
+            .with_eval(|args| Ok(args[0].f_maximum(&args[1])?.f_minimum(&args[2])?))
+            .with_derivative(|args, i, context| {
+                // clamp(x, lo, hi) follows x between the bounds and the bound outside of them
+                let (x, lo, hi) = (args[0].clone(), args[1].clone(), args[2].clone());
+                match i {
+                    0 => mul(context, step(context, sub(context, x.clone(), lo)?)?, step(context, sub(context, hi, x)?)?),
+                    1 => step(context, sub(context, lo, x)?),
+                    _ => step(context, sub(context, x, hi)?),
+                }
+            }),
+        Function::new("where", 3)
+            .with_eval(|args| {
+                let condition = match args[0].kind() {
+                    Kind::Bool => args[0].shallow_clone(),
+                    _ => args[0].f_ne(0)?,
+                };
+                Ok(args[1].f_where_self(&condition, &args[2])?)
+            })
+            .with_derivative(|args, i, context| {
+                // where(c, a, b) follows a where c holds and b elsewhere
+                match i {
+                    0 => Ok(Expr::Zero),
+                    1 => Expr::call(context, "where", vec![args[0].clone(), Expr::Unity, Expr::Zero]),
+                    _ => Expr::call(context, "where", vec![args[0].clone(), Expr::Zero, Expr::Unity]),
+                }
+            }),
     ];
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tch::Tensor;
+
+    use crate::expression::{Context, shunter, ast, derivative, evaluator, scalar::ScalarProgram, varnum::Variable};
+
+    fn context() -> Context {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x")).unwrap();
+        context.add_variable(Variable::new("y")).unwrap();
+        return context;
+    }
+
+    fn scalar(expr: &str, x: f64, y: f64) -> f64 {
+        let context = context();
+        let rpn = shunter::shunt(expr, &context).unwrap();
+        return ScalarProgram::<f64>::compile(&rpn, &context).unwrap().evaluate(&[x, y]).unwrap();
+    }
+
+    // The derivative rule evaluated at (x, y)
+    fn partial(expr: &str, var: &str, x: f64, y: f64) -> f64 {
+        let context = context();
+        let expr = ast::build(expr, &context).unwrap();
+        let rpn = derivative::diff(&expr, &Variable::new(var), &context).unwrap().to_rpn();
+        return ScalarProgram::<f64>::compile(&rpn, &context).unwrap().evaluate(&[x, y]).unwrap();
+    }
+
+    fn tensor(expr: &str, x: f64, y: f64) -> f64 {
+        let context = context();
+        let mut variables = HashMap::new();
+        variables.insert(String::from("x"), Tensor::from(x));
+        variables.insert(String::from("y"), Tensor::from(y));
+        let rpn = shunter::shunt(expr, &context).unwrap();
+        return evaluator::evaluate(&rpn, &context, &variables).unwrap().double_value(&[]);
+    }
+
+    #[test]
+    fn evaluation() {
+        let cases: [(&str, fn(f64, f64) -> f64); 20] = [
+            ("tan(x)", |x, _| x.tan()),
+            ("exp(x)", |x, _| x.exp()),
+            ("log(y)", |_, y| y.ln()),
+            ("log10(y)", |_, y| y.log10()),
+            ("sqrt(y)", |_, y| y.sqrt()),
+            ("abs(x)", |x, _| x.abs()),
+            ("sinh(x)", |x, _| x.sinh()),
+            ("cosh(x)", |x, _| x.cosh()),
+            ("tanh(x)", |x, _| x.tanh()),
+            ("asin(x)", |x, _| x.asin()),
+            ("acos(x)", |x, _| x.acos()),
+            ("atan(y)", |_, y| y.atan()),
+            ("atan2(x, y)", |x, y| x.atan2(y)),
+            ("min(x, y, 0)", |x, y| x.min(y).min(0.0)),
+            ("pow(y, x)", |x, y| y.powf(x)),
+            ("sign(x)", |x, _| x.signum()),
+            ("floor(x) + ceil(x)", |x, _| x.floor() + x.ceil()),
+            ("round(x*3)", |x, _| (x*3.0).round_ties_even()),
+            ("clamp(y, x, 1)", |x, y| y.max(x).min(1.0)),
+            ("where(x < 0, x, y)", |x, y| if x < 0.0 { x } else { y }),
+        ];
+        for (expr, expected) in cases {
+            for (x, y) in [(-0.5, 0.75), (0.5, 2.5)] {
+                let value = tensor(expr, x, y);
+                assert!((value - expected(x, y)).abs() < 1e-12*(1.0 + value.abs()), "{} at ({}, {}): {} != {}", expr, x, y, value, expected(x, y));
+            }
+        }
+    }
+
+    // Against central differences, away from the kinks
+    #[test]
+    fn derivatives() {
+        let cases = [
+            "tan(x)", "exp(x)", "log(x)", "log10(x)", "sqrt(x)", "abs(x)", "abs(-x)",
+            "sinh(x)", "cosh(x)", "tanh(x)", "asin(x)", "acos(x)", "atan(x)",
+            "atan2(x, y)", "atan2(y, x)", "max(x, y)", "max(y, 2*x, 0.1)", "min(x, y)", "min(y, -x)",
+            "pow(x, y)", "pow(y, x)", "clamp(x, 0, 1)", "clamp(x, y, 2)", "clamp(0.5, x, y)",
+            "where(y > 1, x^2, x)", "sign(x) + floor(x) + ceil(x) + round(x)",
+        ];
+        let h = 1e-6;
+        for expr in cases {
+            for (x, y) in [(0.3, 0.6), (0.7, 1.5)] {
+                let expected = (scalar(expr, x + h, y) - scalar(expr, x - h, y))/(2.0*h);
+                let value = partial(expr, "x", x, y);
+                assert!((value - expected).abs() < 1e-6*(1.0 + expected.abs()), "{} at ({}, {}): {} != {}", expr, x, y, value, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn max_and_min_split_ties() {
+        assert_eq!(partial("max(x, y)", "x", 1.0, 1.0), 0.5);
+        assert_eq!(partial("max(x, y)", "y", 1.0, 1.0), 0.5);
+        assert_eq!(partial("min(x, y)", "x", 1.0, 1.0), 0.5);
+        assert_eq!(partial("max(x, y, 0)", "x", 1.0, 1.0), 0.5);
+        assert_eq!(partial("max(x, y, 0)", "x", 1.0, 0.5), 1.0);
+        assert_eq!(partial("min(x, y, 0)", "x", 1.0, 1.0), 0.0);
+        assert_eq!(partial("clamp(x, 0, 1)", "x", 1.0, 0.0), 0.5);
+    }
+}
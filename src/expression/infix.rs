@@ -0,0 +1,254 @@
+use crate::expression::{
+	Token,
+	ast::Expr,
+	operators::{Op, Operator, needs_parens},
+};
+
+/// Prints parsed expressions as infix with only the parentheses the precedence and
+/// associativity of the operators require, so shunting the printed text gives back the RPN.
+/// Zero and Unity, made by derivatives and simplification, are printed as `0` and `1` and
+/// read back as these literals.
+///
+/// With a width, expressions longer than it are broken before the operators of their
+/// loosest operation and between function arguments, nested groups are indented.
+pub struct InfixPrinter {
+	width: Option<usize>,
+	indent: usize,
+}
+
+impl InfixPrinter {
+
+	pub fn new() -> Self {
+		Self {..Default::default()}
+	}
+
+	pub fn with_width(mut self, width: usize) -> Self {
+		self.width = Some(width);
+		self
+	}
+
+	/// Spaces per nesting level of the line-wrapped layout.
+	pub fn with_indent(mut self, indent: usize) -> Self {
+		self.indent = indent;
+		self
+	}
+
+	pub fn print(&self, expr: &Expr) -> String {
+		match self.width {
+			Some(width) => return self.lines(expr, width).join("\n"),
+			None => return flat(expr),
+		}
+	}
+
+	pub fn print_rpn(&self, rpn: &Vec<Token>) -> anyhow::Result<String> {
+		return Ok(self.print(&Expr::from_rpn(rpn)?));
+	}
+
+	fn lines(&self, expr: &Expr, width: usize) -> Vec<String> {
+		let line = flat(expr);
+		if line.chars().count() <= width {
+			return vec![line];
+		}
+		match expr {
+			Expr::UnaryOperator(uop, arg) => {
+				let parent = Operator::UnaryOperator(uop.clone());
+				let symbol = uop.get_token();
+				let mut lines = self.operand(&parent, arg, true, width.saturating_sub(symbol.len()));
+				lines[0] = format!("{}{}", symbol, lines[0]);
+				return lines;
+			},
+			Expr::BinaryOperator(bop, _, _) => {
+				// a + b - c is broken as one chain of operands
+				let mut chain: Vec<(Operator, &Expr)> = vec![];
+				let mut head = expr;
+				while let Expr::BinaryOperator(op, lhs, rhs) = head {
+					let op = Operator::BinaryOperator(op.clone());
+					let is_chained = match operator(lhs) {
+						Some(next @ Operator::BinaryOperator(_)) =>
+							next.get_precedence() == bop.get_precedence() && !needs_parens(&op, &next, false),
+						_ => false,
+					};
+					chain.push((op, rhs));
+					head = lhs;
+					if !is_chained {
+						break;
+					}
+				}
+				// Continuation lines of an operand are indented past the operators of the chain
+				let first = &chain.last().unwrap().0;
+				let margin = first.get_token().len() + 1;
+				let mut lines = vec![];
+				for (i, line) in self.operand(first, head, false, width.saturating_sub(margin)).into_iter().enumerate() {
+					lines.push(if i == 0 { line } else { format!("{}{}", " ".repeat(margin), line) });
+				}
+				for (op, operand) in chain.iter().rev() {
+					let prefix = format!("{} ", op.get_token());
+					let operand = self.operand(op, operand, true, width.saturating_sub(prefix.len()));
+					for (i, line) in operand.into_iter().enumerate() {
+						lines.push(if i == 0 { format!("{}{}", prefix, line) } else { format!("{}{}", " ".repeat(prefix.len()), line) });
+					}
+				}
+				return lines;
+			},
+			Expr::Function(func, args) => {
+				let mut lines = vec![format!("{}(", func.get_token())];
+				for (i, arg) in args.iter().enumerate() {
+					let mut arg_lines = self.lines(arg, width.saturating_sub(self.indent));
+					if i + 1 < args.len() {
+						arg_lines.last_mut().unwrap().push(',');
+					}
+					lines.extend(arg_lines.into_iter().map(|line| self.indented(&line)));
+				}
+				lines.push(String::from(")"));
+				return lines;
+			},
+			_ => return vec![line],
+		}
+	}
+
+	fn operand(&self, parent: &Operator, child: &Expr, is_right: bool, width: usize) -> Vec<String> {
+		if !operand_needs_parens(parent, child, is_right) {
+			return self.lines(child, width);
+		}
+		let mut lines = self.lines(child, width.saturating_sub(2));
+		if lines.len() == 1 {
+			return vec![format!("({})", lines[0])];
+		}
+		lines = self.lines(child, width.saturating_sub(self.indent));
+		let mut wrapped = vec![String::from("(")];
+		wrapped.extend(lines.into_iter().map(|line| self.indented(&line)));
+		wrapped.push(String::from(")"));
+		return wrapped;
+	}
+
+	fn indented(&self, line: &str) -> String {
+		return format!("{}{}", " ".repeat(self.indent), line);
+	}
+
+}
+
+impl Default for InfixPrinter {
+
+	fn default() -> Self {
+		Self {width: None, indent: 4}
+	}
+
+}
+
+/// On one line, e.g. `-(a + b) * c ^ 2`.
+pub fn to_infix(expr: &Expr) -> String {
+	return flat(expr);
+}
+
+pub fn infix_rpn(rpn: &Vec<Token>) -> anyhow::Result<String> {
+	return Ok(flat(&Expr::from_rpn(rpn)?));
+}
+
+fn flat(expr: &Expr) -> String {
+	match expr {
+		Expr::Number(num) => return num.get_token().to_string(),
+		Expr::Constant(constant) => return constant.get_token().to_string(),
+		Expr::Zero => return String::from("0"),
+		Expr::Unity => return String::from("1"),
+		Expr::Variable(var) => return var.get_token().to_string(),
+		Expr::UnaryOperator(uop, arg) => {
+			let parent = Operator::UnaryOperator(uop.clone());
+			return format!("{}{}", uop.get_token(), flat_operand(&parent, arg, true));
+		},
+		Expr::BinaryOperator(bop, lhs, rhs) => {
+			let parent = Operator::BinaryOperator(bop.clone());
+			return format!("{} {} {}", flat_operand(&parent, lhs, false), bop.get_token(), flat_operand(&parent, rhs, true));
+		},
+		Expr::Function(func, args) => {
+			let args: Vec<String> = args.iter().map(flat).collect();
+			return format!("{}({})", func.get_token(), args.join(", "));
+		},
+	}
+}
+
+fn flat_operand(parent: &Operator, child: &Expr, is_right: bool) -> String {
+	if operand_needs_parens(parent, child, is_right) {
+		return format!("({})", flat(child));
+	}
+	return flat(child);
+}
+
+fn operator(expr: &Expr) -> Option<Operator> {
+	match expr {
+		Expr::UnaryOperator(uop, _) => return Some(Operator::UnaryOperator(uop.clone())),
+		Expr::BinaryOperator(bop, _, _) => return Some(Operator::BinaryOperator(bop.clone())),
+		_ => return None,
+	}
+}
+
+fn operand_needs_parens(parent: &Operator, child: &Expr, is_right: bool) -> bool {
+	if let Expr::Number(num) = child {
+		// Literals made by simplification, such as -1, are not read back as one token
+		let value = num.get_value();
+		return value.re < 0.0 || value.im < 0.0 || (value.re != 0.0 && value.im != 0.0);
+	}
+	match operator(child) {
+		Some(op) => return needs_parens(parent, &op, is_right),
+		None => return false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, ast, shunter, varnum::Variable};
+	use super::{InfixPrinter, to_infix};
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		return context;
+	}
+
+	// Printing and shunting again gives the RPN the expression was parsed from
+	fn assert_round_trip(expr: &str, printer: &InfixPrinter) {
+		let context = context();
+		let rpn = shunter::stringify_rpn(&shunter::shunt(expr, &context).unwrap());
+		let printed = printer.print(&ast::build(expr, &context).unwrap());
+		let reparsed = shunter::shunt(&printed, &context).unwrap();
+		assert_eq!(shunter::stringify_rpn(&reparsed), rpn, "{} printed as {}", expr, printed);
+	}
+
+	const EXPRESSIONS: [&str; 12] = [
+		"-(2^2)",
+		"(-2)^2",
+		"-(2)",
+		"-2^2",
+		"2*-3^2",
+		"x^-2^2",
+		"(x^y)^2",
+		"x - (y - 2)",
+		"x / (y * 2)",
+		"-(x + 1) * y",
+		"max(-(1), x ^ (y + 1), 3) < x + y",
+		"-.5 * x",
+	];
+
+	#[test]
+	fn flat_round_trip() {
+		for expr in EXPRESSIONS.iter() {
+			assert_round_trip(expr, &InfixPrinter::new());
+		}
+	}
+
+	#[test]
+	fn wrapped_round_trip() {
+		for expr in EXPRESSIONS.iter() {
+			assert_round_trip(expr, &InfixPrinter::new().with_width(8));
+		}
+	}
+
+	#[test]
+	fn unary_operand_starting_with_a_literal() {
+		let context = context();
+		assert_eq!(to_infix(&ast::build("-(2^2)", &context).unwrap()), "-2 ^ 2");
+		assert_eq!(to_infix(&ast::build("-(2)", &context).unwrap()), "-2");
+		assert_eq!(to_infix(&ast::build("2*-(3^2)", &context).unwrap()), "2 * -3 ^ 2");
+		assert_eq!(to_infix(&ast::build("-x^2", &context).unwrap()), "-x ^ 2");
+	}
+}
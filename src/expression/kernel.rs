@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	cse,
+	definition,
+	operators::Op,
+	torchscript::{Render, template},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+	/// A function looping over the elements on the CPU.
+	C,
+	/// A `__global__` function with one thread per element.
+	Cuda,
+}
+
+// Kernel parameters, locals and helpers besides the context variables and the CSE temporaries
+const RESERVED: [&str; 6] = ["result", "n_elements", "idx", "expr_sign", "expr_maximum", "expr_minimum"];
+
+// C keywords, the types and macros the kernel uses and the identifiers declared by <math.h>
+const C_RESERVED: &[&str] = &[
+	"auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum",
+	"extern", "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return",
+	"short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void",
+	"volatile", "while", "size_t", "NULL", "NAN", "INFINITY", "HUGE_VAL", "HUGE_VALF", "HUGE_VALL",
+	"M_E", "M_PI", "M_SQRT2", "M_LN2", "M_LN10", "M_LOG2E", "M_LOG10E", "M_PI_2", "M_PI_4", "M_1_PI",
+	"M_2_PI", "M_2_SQRTPI", "M_SQRT1_2", "acos", "acosh", "asin", "asinh", "atan", "atan2", "atanh",
+	"cbrt", "ceil", "copysign", "cos", "cosh", "erf", "erfc", "exp", "exp2", "expm1", "fabs", "fdim",
+	"floor", "fma", "fmax", "fmin", "fmod", "frexp", "hypot", "ilogb", "isfinite", "isinf", "isnan",
+	"isnormal", "signbit", "fpclassify", "j0", "j1", "jn", "ldexp", "lgamma", "llrint", "llround",
+	"log", "log10", "log1p", "log2", "logb", "lrint", "lround", "modf", "nan", "nearbyint", "nextafter",
+	"nexttoward", "pow", "remainder", "remquo", "rint", "round", "scalbln", "scalbn", "sin", "sinh",
+	"sqrt", "tan", "tanh", "tgamma", "trunc", "y0", "y1", "yn",
+];
+
+// CUDA is compiled as C++, so its keywords and the thread index built-ins are taken as well
+const CUDA_RESERVED: &[&str] = &[
+	"alignas", "alignof", "and", "and_eq", "asm", "bitand", "bitor", "bool", "catch", "class", "compl",
+	"constexpr", "const_cast", "decltype", "delete", "dynamic_cast", "explicit", "export", "false",
+	"friend", "mutable", "namespace", "new", "noexcept", "not", "not_eq", "nullptr", "operator", "or",
+	"or_eq", "private", "protected", "public", "reinterpret_cast", "static_assert", "static_cast",
+	"template", "this", "thread_local", "throw", "true", "try", "typeid", "typename", "using", "virtual",
+	"wchar_t", "xor", "xor_eq", "blockIdx", "blockDim", "threadIdx", "gridDim", "warpSize",
+];
+
+/// Emits the source of one fused elementwise kernel over `double` arrays, one input per
+/// context variable in context order and one output. Repeated subexpressions become locals.
+/// Tensor constants do not index like the inputs and are rejected, bind them as variables.
+pub struct KernelEmitter {
+	dialect: Dialect,
+	name: String,
+	unary_operators: HashMap<String, Render>,
+	binary_operators: HashMap<String, Render>,
+	functions: HashMap<String, Render>,
+}
+
+impl KernelEmitter {
+
+	pub fn new(dialect: Dialect) -> Self {
+		let mut emitter = KernelEmitter {
+			dialect: dialect,
+			name: String::from("kernel"),
+			unary_operators: HashMap::new(),
+			binary_operators: HashMap::new(),
+			functions: HashMap::new(),
+		};
+
+		emitter.set_unary_operator("-", template("(-{0})"));
+		emitter.set_unary_operator("!", template("(double)(!{0})"));
+
+		emitter.set_binary_operator("^", template("pow({0}, {1})"));
+		for token in ["*", "/", "+", "-"] {
+			emitter.set_binary_operator(token, template(&format!("({{0}} {} {{1}})", token)));
+		}
+		// Comparisons and logical operators give 0.0 or 1.0 as the tensors do after promotion
+		for token in ["<", "<=", ">", ">=", "==", "!=", "&&", "||"] {
+			emitter.set_binary_operator(token, template(&format!("(double)({{0}} {} {{1}})", token)));
+		}
+
+		for token in ["sin", "cos", "tan", "exp", "log", "log10", "sqrt", "sinh", "cosh", "tanh",
+			"asin", "acos", "atan", "floor", "ceil"]
+		{
+			emitter.set_function(token, template(&format!("{}({{0}})", token)));
+		}
+		emitter.set_function("abs", template("fabs({0})"));
+		// rint rounds half to even like torch.round
+		emitter.set_function("round", template("rint({0})"));
+		emitter.set_function("sign", template("expr_sign({0})"));
+		emitter.set_function("atan2", template("atan2({0}, {1})"));
+		emitter.set_function("pow", template("pow({0}, {1})"));
+		// fmax and fmin drop NaN, torch.maximum and torch.minimum propagate it
+		emitter.set_function("max", Arc::new(|args: &[String]| fold("expr_maximum", args)));
+		emitter.set_function("min", Arc::new(|args: &[String]| fold("expr_minimum", args)));
+		emitter.set_function("sum", Arc::new(|args: &[String]| format!("({})", args.join(" + "))));
+		emitter.set_function("clamp", template("expr_minimum(expr_maximum({0}, {1}), {2})"));
+		emitter.set_function("where", template("({0} != 0.0 ? {1} : {2})"));
+
+		return emitter;
+	}
+
+	pub fn with_name(mut self, name: &str) -> Self {
+		self.name = name.to_string();
+		self
+	}
+
+	pub fn set_unary_operator(&mut self, token: &str, render: Render) {
+		self.unary_operators.insert(token.to_string(), render);
+	}
+
+	pub fn set_binary_operator(&mut self, token: &str, render: Render) {
+		self.binary_operators.insert(token.to_string(), render);
+	}
+
+	pub fn set_function(&mut self, token: &str, render: Render) {
+		self.functions.insert(token.to_string(), render);
+	}
+
+	/// Defined functions are inlined. Complex numbers are not supported.
+	pub fn emit(&self, expr: &Expr, context: &Context) -> anyhow::Result<String> {
+		let expr = definition::inline(expr)?;
+		let mut unknown: Option<String> = None;
+		expr.walk(&mut |node| {
+			if let Expr::Variable(var) = node {
+				if unknown.is_none() && context.get_variable(var.get_token()).is_none() {
+					unknown = Some(var.get_token().to_string());
+				}
+			}
+		});
+		if let Some(unknown) = unknown {
+			return Err(anyhow::anyhow!("variable '{}' is not part of the context", unknown));
+		}
+		for var in context.get_variables() {
+			let token = var.get_token();
+			let reserved = RESERVED.contains(&token) || C_RESERVED.contains(&token)
+				|| (self.dialect == Dialect::Cuda && CUDA_RESERVED.contains(&token));
+			if reserved || token == self.name {
+				return Err(anyhow::anyhow!("variable '{}' clashes with a name used by the kernel", var.get_token()));
+			}
+		}
+
+		let eliminated = cse::cse(&expr, context);
+
+		let (qualifier, restrict) = match self.dialect {
+			Dialect::C => ("static inline", "restrict"),
+			Dialect::Cuda => ("__device__ inline", "__restrict__"),
+		};
+		let mut params: Vec<String> = context.get_variables().iter()
+			.map(|var| format!("const double* {} {}", restrict, var.get_token()))
+			.collect();
+		params.push(format!("double* {} result", restrict));
+		params.push(String::from("size_t n_elements"));
+
+		let mut source = String::new();
+		if self.dialect == Dialect::C {
+			source += "#include <math.h>\n#include <stddef.h>\n\n";
+		}
+		source += &format!("{} double expr_sign(double x) {{\n    return (double)((x > 0.0) - (x < 0.0));\n}}\n\n", qualifier);
+		source += &format!("{} double expr_maximum(double a, double b) {{\n    return (isnan(a) || isnan(b)) ? NAN : fmax(a, b);\n}}\n\n", qualifier);
+		source += &format!("{} double expr_minimum(double a, double b) {{\n    return (isnan(a) || isnan(b)) ? NAN : fmin(a, b);\n}}\n\n", qualifier);
+
+		let indent = match self.dialect {
+			Dialect::C => {
+				source += &format!("void {}({}) {{\n", self.name, params.join(", "));
+				source += "    for (size_t idx = 0; idx < n_elements; ++idx) {\n";
+				"        "
+			},
+			Dialect::Cuda => {
+				source += &format!("extern \"C\" __global__ void {}({}) {{\n", self.name, params.join(", "));
+				source += "    size_t idx = (size_t)blockIdx.x * blockDim.x + threadIdx.x;\n";
+				source += "    if (idx >= n_elements) {\n        return;\n    }\n";
+				"    "
+			},
+		};
+		for (var, subexpr) in eliminated.temporaries.iter() {
+			source += &format!("{}const double {} = {};\n", indent, var.get_token(), self.render(subexpr, context)?);
+		}
+		source += &format!("{}result[idx] = {};\n", indent, self.render(&eliminated.root, context)?);
+		if self.dialect == Dialect::C {
+			source += "    }\n";
+		}
+		source += "}\n";
+		return Ok(source);
+	}
+
+	pub fn emit_rpn(&self, rpn: &Vec<Token>, context: &Context) -> anyhow::Result<String> {
+		return self.emit(&Expr::from_rpn(rpn)?, context);
+	}
+
+	fn render(&self, expr: &Expr, context: &Context) -> anyhow::Result<String> {
+		match expr {
+			Expr::Number(num) => {
+				if num.is_complex() {
+					return Err(anyhow::anyhow!("complex number '{}' is not supported in kernels", num.get_token()));
+				}
+				return Ok(c_double(num.get_value().re));
+			},
+			Expr::Constant(constant) => {
+				match constant.get_scalar() {
+					Some(value) if value.im == 0.0 => return Ok(c_double(value.re)),
+					Some(_) => return Err(anyhow::anyhow!("complex constant '{}' is not supported in kernels", constant.get_token())),
+					None => return Err(anyhow::anyhow!("tensor constant '{}' can not be written into a kernel, bind it as a variable", constant.get_token())),
+				}
+			},
+			Expr::Zero => return Ok(c_double(0.0)),
+			Expr::Unity => return Ok(c_double(1.0)),
+			Expr::Variable(var) => {
+				// Anything else is a CSE temporary, unknown variables are rejected by emit
+				if context.get_variable(var.get_token()).is_some() {
+					return Ok(format!("{}[idx]", var.get_token()));
+				}
+				return Ok(var.get_token().to_string());
+			},
+			Expr::UnaryOperator(uop, arg) => {
+				let render = lookup(&self.unary_operators, uop.get_token(), "unary operator")?;
+				return Ok(render(&[self.render(arg, context)?]));
+			},
+			Expr::BinaryOperator(bop, lhs, rhs) => {
+				let render = lookup(&self.binary_operators, bop.get_token(), "binary operator")?;
+				return Ok(render(&[self.render(lhs, context)?, self.render(rhs, context)?]));
+			},
+			Expr::Function(func, args) => {
+				let render = lookup(&self.functions, func.get_token(), "function")?;
+				let args = args.iter()
+					.map(|arg| self.render(arg, context))
+					.collect::<anyhow::Result<Vec<String>>>()?;
+				return Ok(render(&args));
+			},
+		}
+	}
+
+}
+
+fn lookup<'a>(renders: &'a HashMap<String, Render>, token: &str, kind: &str) -> anyhow::Result<&'a Render> {
+	match renders.get(token) {
+		Some(render) => return Ok(render),
+		None => return Err(anyhow::anyhow!("no kernel equivalent for {} '{}'", kind, token)),
+	}
+}
+
+fn fold(call: &str, args: &[String]) -> String {
+	let mut acc = args[0].clone();
+	for arg in args[1..].iter() {
+		acc = format!("{}({}, {})", call, acc, arg);
+	}
+	return acc;
+}
+
+fn c_double(value: f64) -> String {
+	if value.is_nan() {
+		return String::from("NAN");
+	}
+	if value.is_infinite() {
+		return String::from(if value > 0.0 { "INFINITY" } else { "(-INFINITY)" });
+	}
+	// Debug formatting always keeps a '.' or an exponent, so the literal is a double
+	return format!("{:?}", value);
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, ast, varnum::Variable};
+	use super::{KernelEmitter, Dialect};
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_variable(Variable::new("Y")).unwrap();
+		return context;
+	}
+
+	fn emit(dialect: Dialect, expr: &str) -> anyhow::Result<String> {
+		let context = context();
+		let expr = ast::build(expr, &context)?;
+		return KernelEmitter::new(dialect).emit(&expr, &context);
+	}
+
+	#[test]
+	fn golden_c() {
+		assert_eq!(emit(Dialect::C, "max(X, Y)*sin(X) + sin(X)^2").unwrap(), "\
+#include <math.h>
+#include <stddef.h>
+
+static inline double expr_sign(double x) {
+    return (double)((x > 0.0) - (x < 0.0));
+}
+
+static inline double expr_maximum(double a, double b) {
+    return (isnan(a) || isnan(b)) ? NAN : fmax(a, b);
+}
+
+static inline double expr_minimum(double a, double b) {
+    return (isnan(a) || isnan(b)) ? NAN : fmin(a, b);
+}
+
+void kernel(const double* restrict X, const double* restrict Y, double* restrict result, size_t n_elements) {
+    for (size_t idx = 0; idx < n_elements; ++idx) {
+        const double x0 = sin(X[idx]);
+        result[idx] = ((expr_maximum(X[idx], Y[idx]) * x0) + pow(x0, 2.0));
+    }
+}
+");
+	}
+
+	#[test]
+	fn golden_cuda() {
+		let source = emit(Dialect::Cuda, "clamp(X, 0, 1) - Y").unwrap();
+		assert!(source.ends_with("\
+extern \"C\" __global__ void kernel(const double* __restrict__ X, const double* __restrict__ Y, double* __restrict__ result, size_t n_elements) {
+    size_t idx = (size_t)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx >= n_elements) {
+        return;
+    }
+    result[idx] = (expr_minimum(expr_maximum(X[idx], 0.0), 1.0) - Y[idx]);
+}
+"));
+	}
+
+	#[test]
+	fn unbound_variable_is_an_error() {
+		let mut wider = context();
+		wider.add_variable(Variable::new("Z")).unwrap();
+		let expr = ast::build("X + Z", &wider).unwrap();
+		let err = KernelEmitter::new(Dialect::C).emit(&expr, &context()).unwrap_err();
+		assert_eq!(err.to_string(), "variable 'Z' is not part of the context");
+	}
+
+	#[test]
+	fn reserved_names_are_rejected() {
+		for name in ["idx", "result", "y0", "j1", "double", "int", "fabs", "NAN", "kernel"] {
+			let mut context = context();
+			context.add_variable(Variable::new(name)).unwrap();
+			let expr = ast::build(&format!("X + {}", name), &context).unwrap();
+			let err = KernelEmitter::new(Dialect::C).emit(&expr, &context).unwrap_err();
+			assert_eq!(err.to_string(), format!("variable '{}' clashes with a name used by the kernel", name));
+		}
+
+		let mut context = context();
+		context.add_variable(Variable::new("threadIdx")).unwrap();
+		let expr = ast::build("X + threadIdx", &context).unwrap();
+		assert!(KernelEmitter::new(Dialect::C).emit(&expr, &context).is_ok());
+		assert!(KernelEmitter::new(Dialect::Cuda).emit(&expr, &context).is_err());
+	}
+}
@@ -1,94 +1,271 @@
-
+use std::collections::HashMap;
 
 use crate::expression::{
 	Token,
 	Context,
-	operators,
-	functions,
 	varnum,
+	operators::Operator,
+	diagnostics::{Span, SpanError},
 };
 
-	/*
-	pub (super) struct Lexer {
-		pub (super) tokens: Vec<Token>,
+// Every operator, function and variable spelling in the context. A node holds the tokens
+// that are spelled by the path leading to it.
+#[derive(Default)]
+struct Trie {
+	children: HashMap<char, Trie>,
+	tokens: Vec<Token>,
+}
+
+impl Trie {
+
+	fn from_context(context: &Context) -> Self {
+		let mut trie = Trie::default();
+		for uop in context.unary_operators.iter() {
+			trie.insert(Token::Operator(Operator::UnaryOperator(uop.clone())));
+		}
+		for bop in context.binary_operators.iter() {
+			trie.insert(Token::Operator(Operator::BinaryOperator(bop.clone())));
+		}
+		for func in context.functions.iter() {
+			trie.insert(Token::Function(func.clone()));
+		}
+		for var in context.variables.iter() {
+			trie.insert(Token::Variable(var.clone()));
+		}
+		for constant in context.constants.iter() {
+			trie.insert(Token::Constant(constant.clone()));
+		}
+		// An alias spells every token registered under the name it stands for
+		for (spelling, name) in context.aliases.iter() {
+			let mut tokens: Vec<Token> = vec![];
+			tokens.extend(context.get_unary_operator(name).map(|uop| Token::Operator(Operator::UnaryOperator(uop.clone()))));
+			tokens.extend(context.get_binary_operator(name).map(|bop| Token::Operator(Operator::BinaryOperator(bop.clone()))));
+			tokens.extend(context.get_function(name).map(|func| Token::Function(func.clone())));
+			tokens.extend(context.get_variable(name).map(|var| Token::Variable(var.clone())));
+			tokens.extend(context.get_constant(name).map(|constant| Token::Constant(constant.clone())));
+			for token in tokens {
+				trie.insert_as(spelling, token);
+			}
+		}
+		trie
+	}
+
+	fn insert(&mut self, token: Token) {
+		let spelling = token.stringify().to_string();
+		self.insert_as(&spelling, token);
+	}
+
+	fn insert_as(&mut self, spelling: &str, token: Token) {
+		let mut node = self;
+		for c in spelling.chars() {
+			node = node.children.entry(c).or_default();
+		}
+		node.tokens.push(token);
+	}
+
+	// (byte length, tokens) for every spelling that is a prefix of expr, shortest first
+	fn prefixes<'a>(&'a self, expr: &str) -> Vec<(usize, &'a Vec<Token>)> {
+		let mut matches = vec![];
+		let mut node = self;
+		for (i, c) in expr.char_indices() {
+			match node.children.get(&c) {
+				Some(child) => node = child,
+				None => break,
+			}
+			if !node.tokens.is_empty() {
+				matches.push((i + c.len_utf8(), &node.tokens));
+			}
+		}
+		matches
+	}
+
+}
+
+fn is_identifier_char(c: char) -> bool {
+	c.is_alphanumeric() || c == '_'
+}
+
+// An identifier-like spelling must not be directly followed by another identifier char,
+// so a variable X does not match the start of Xb
+fn ends_at_boundary(expr: &str, len: usize) -> bool {
+	let last = expr[..len].chars().last();
+	let next = expr[len..].chars().next();
+	match (last, next) {
+		(Some(last), Some(next)) => return !(is_identifier_char(last) && is_identifier_char(next)),
+		_ => return true,
+	}
+}
+
+pub (super) fn is_operand(token: &Token) -> bool {
+	match token {
+		Token::Number(_) | Token::Constant(_) | Token::Zero | Token::Unity | Token::Variable(_) | Token::RightParen => return true,
+		_ => return false,
 	}
-	*/
+}
 
+pub fn lex<'a>(expr: &str, context: &Context) -> anyhow::Result<Vec<(Token, Span)>> {
+	let trie = Trie::from_context(context);
 
-pub fn lex<'a>(expr: &str, context: &Context) -> anyhow::Result<Vec<Token>> {
 	let mut tokens: Vec<Token> = vec![Token::NoToken];
+	let mut spans: Vec<Span> = vec![Span::default()];
 
-	let mut reststr: &str = &expr;
+	let mut pos = 0;
 	loop {
-		let (next_token, retstr) = lex_token(reststr, &tokens, context);
-		reststr = retstr;
-		//println!("reststr: {}", reststr);
-		//println!("reststr len: {}", reststr.len());
+		pos += expr[pos..].len() - expr[pos..].trim_start().len();
+		if pos == expr.len() {
+			return Ok(tokens.into_iter().zip(spans.into_iter()).collect());
+		}
+
+		let (next_token, len) = lex_token(&expr[pos..], &tokens, &trie, context);
 
 		if next_token.eq(&Token::NoToken) {
-			return Err(anyhow::anyhow!("lex_token() signaled bad expression"));
+			let rest = &expr[pos..];
+			let mut end = rest.char_indices().skip(1).map(|(i, _)| i).next().unwrap_or(rest.len());
+			if rest.starts_with(is_identifier_char) {
+				end = rest.find(|c: char| !is_identifier_char(c)).unwrap_or(rest.len());
+				let message = format!("unrecognized identifier '{}'", &rest[..end]);
+				return Err(SpanError::new(&message, Span::new(pos, pos + end), expr).into());
+			}
+			return Err(SpanError::new("unrecognized token", Span::new(pos, pos + end), expr).into());
 		}
 
 		tokens.push(next_token);
-
-		if reststr.len() == 0 {
-			return Ok(tokens);
-		}
+		spans.push(Span::new(pos, pos + len));
+		pos += len;
 	}
 }
 
-fn lex_token<'a>(expr: &'a str, tokens: &Vec<Token>, context: &Context) -> (Token, &'a str) {
+// Takes the longest spelling that is valid at this point, an equally long operator wins over
+// a function, which wins over a variable, which wins over a number
+fn lex_token(expr: &str, tokens: &Vec<Token>, trie: &Trie, context: &Context) -> (Token, usize) {
 
-	use crate::expression::operators::Op;
-	use crate::expression::operators::Operator;
+	let last = tokens.last().unwrap();
 
 	if let Some(c) = expr.chars().nth(0) {
 		match c {
-			'(' => return (Token::LeftParen, &expr[1..]),
-			')' => return (Token::RightParen, &expr[1..]),
-			',' => return (Token::Comma, &expr[1..]),
+			'(' => return (Token::LeftParen, 1),
+			')' => return (Token::RightParen, 1),
+			',' => return (Token::Comma, 1),
 			_ => {},
 		}
-	} 
+	}
 
-	// Unary operator
-	let uop = operators::begins_with_unary_operator(expr, tokens, context);
-	if uop.is_some() {
-		let uop = uop.unwrap();
-		return (Token::Operator(Operator::UnaryOperator(uop.clone())), &expr[uop.get_token().len()..])
+	let mut best: Option<(Token, usize)> = None;
+	for (len, candidates) in trie.prefixes(expr).into_iter().rev() {
+		if !ends_at_boundary(expr, len) {
+			continue;
+		}
+		if let Some(token) = pick_candidate(candidates, &expr[len..], last) {
+			best = Some((token.clone(), len));
+			break;
+		}
+	}
+
+	// Literals never directly follow an operand
+	if !is_operand(last) {
+		if let Some(num) = varnum::begins_with_number(expr, tokens, context) {
+			let len = num.get_token().len();
+			let longer = best.as_ref().map(|(_, best_len)| len > *best_len).unwrap_or(true);
+			if longer && ends_at_boundary(expr, len) {
+				return (Token::Number(num), len);
+			}
+		}
 	}
 
-	// Binary operator
-	let bop = operators::begins_with_binary_operator(expr, tokens, context);
-	if bop.is_some() {
-		let bop = bop.unwrap();
-		return (Token::Operator(Operator::BinaryOperator(bop.clone())), &expr[bop.get_token().len()..]);
+	best.unwrap_or((Token::default(), 0))
+}
+
+fn pick_candidate<'a>(candidates: &'a Vec<Token>, rest: &str, last: &Token) -> Option<&'a Token> {
+	let mut picked: Option<(u8, &Token)> = None;
+	for candidate in candidates.iter() {
+		let priority = match candidate {
+			Token::Operator(Operator::UnaryOperator(uop)) => {
+				if !uop.get_allowed_left_tokens().contains(last) {
+					continue;
+				}
+				0
+			},
+			Token::Operator(Operator::BinaryOperator(_)) => 1,
+			Token::Function(_) => {
+				if !rest.trim_start().starts_with('(') {
+					continue;
+				}
+				2
+			},
+			Token::Variable(_) | Token::Constant(_) => 3,
+			_ => continue,
+		};
+		if picked.map(|(p, _)| priority < p).unwrap_or(true) {
+			picked = Some((priority, candidate));
+		}
 	}
+	picked.map(|(_, token)| token)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, shunter, scalar::ScalarProgram, varnum::Variable};
 
-	// Function
-	let func = functions::begins_with_function(expr, tokens, context);
-	if func.is_ok() {
-		let func = func.unwrap();
-		if func.is_some() {
-			let func = func.unwrap();
-			return (Token::Function(func.clone()), &expr[func.get_token().len()..]);
+	fn context() -> Context {
+		let mut context = Context::new();
+		for name in ["x", "X", "Xb", "sinus"] {
+			context.add_variable(Variable::new(name)).unwrap();
 		}
+		return context;
 	}
 
-	// Variable
-	let var = varnum::begins_with_variable(expr, tokens, context);
-	if var.is_some() {
-		let var = var.unwrap();
-		return (Token::Variable(var.clone()), &expr[var.get_token().len()..]);
+	fn rpn(expr: &str) -> String {
+		return shunter::stringify_rpn(&shunter::shunt(expr, &context()).unwrap());
 	}
 
-	// Number
-	let num = varnum::begins_with_number(expr, tokens, context);
-	if num.is_some() {
-		let num = num.unwrap();
-		return (Token::Number(num.clone()), &expr[num.get_token().len()..]);
+	fn value(expr: &str, x: f64) -> f64 {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		let rpn = shunter::shunt(expr, &context).unwrap();
+		return ScalarProgram::<f64>::compile(&rpn, &context).unwrap().evaluate(&[x]).unwrap();
 	}
-	
 
-	(Token::default(), expr)
+	#[test]
+	fn sign_is_unary_operator() {
+		assert_eq!(rpn("-2^2"), "2,2,^,-,");
+		assert_eq!(value("-2^2", 0.0), -4.0);
+		assert_eq!(rpn("2*-3^2"), "2,3,2,^,-,*,");
+		assert_eq!(value("2*-3^2", 0.0), -18.0);
+		assert_eq!(rpn("x^-2"), "x,2,-,^,");
+		assert_eq!(value("x^-2", 2.0), 0.25);
+		assert_eq!(rpn("x^-2^2"), "x,2,2,^,-,^,");
+		assert_eq!(value("x^-2^2", 2.0), 1.0 / 16.0);
+	}
+
+	#[test]
+	fn sign_after_operand_is_binary() {
+		assert_eq!(rpn("x-2"), "x,2,-,");
+		assert_eq!(rpn("x - -2"), "x,2,-,-,");
+		assert_eq!(rpn("1e-3-x"), "1e-3,x,-,");
+	}
+
+	#[test]
+	fn longest_match() {
+		assert_eq!(rpn("X*Xb"), "X,Xb,*,");
+		assert_eq!(rpn("Xb*X"), "Xb,X,*,");
+		assert_eq!(rpn("sinh(x) + sin(x)"), "x,sinh,x,sin,+,");
+		assert_eq!(rpn("sin(sinus)"), "sinus,sin,");
+		assert_eq!(rpn("x<=X"), "x,X,<=,");
+	}
+
+	#[test]
+	fn identifier_boundaries() {
+		let context = context();
+		let err = shunter::shunt("X + Xc", &context).unwrap_err();
+		assert_eq!(err.to_string(), "unrecognized identifier 'Xc' at 1:5\nX + Xc\n    ^^");
+		assert!(shunter::shunt("sinx", &context).is_err());
+		assert!(shunter::shunt("Xb2", &context).is_err());
+	}
+
+	#[test]
+	fn whitespace_is_ignored() {
+		assert_eq!(rpn(" sin ( X ) * Xb "), "X,sin,Xb,*,");
+		assert_eq!(rpn("max(\n\tx,\n\tX\n)"), "x,X,max,");
+		assert_eq!(rpn("1e-3 *x\n+ X"), rpn("1e-3*x+X"));
+	}
 }
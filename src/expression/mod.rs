@@ -3,6 +3,22 @@ pub mod shunter;
 pub mod functions;
 pub mod operators;
 pub mod varnum;
+pub mod evaluator;
+pub mod ast;
+pub mod derivative;
+pub mod cse;
+pub mod simplify;
+pub mod diagnostics;
+pub mod definition;
+pub mod program;
+pub mod torchscript;
+pub mod kernel;
+pub mod scalar;
+pub mod fit;
+pub mod serialize;
+pub mod sympy;
+pub mod infix;
+pub mod typeset;
 mod lexer;
 
 
@@ -14,6 +30,8 @@ use crate::expression::{
 	operators::BinaryOperator,
 	varnum::Variable,
 	varnum::Number,
+	varnum::Constant,
+	varnum::Parameter,
 };
 
 use self::operators::Op;
@@ -22,6 +40,7 @@ use self::operators::Op;
 pub enum Token {
 	NoToken,
 	Number(Number),
+	Constant(Constant),
 	Zero,
 	Unity,
 	Variable(Variable),
@@ -38,6 +57,7 @@ impl Token {
 		match self {
 			Token::NoToken => return "",
 			Token::Number(num) => return num.get_token(),
+			Token::Constant(constant) => return constant.get_token(),
 			Token::Zero => return "Zero",
 			Token::Unity => return "Unity",
 			Token::Variable(var) => return var.get_token(),
@@ -53,6 +73,7 @@ impl Token {
 		match self {
 			Token::NoToken => return 0,
 			Token::Number(num) => return num.get_token().chars().count(),
+			Token::Constant(constant) => return constant.get_token().chars().count(),
 			Token::Zero => return 4,
 			Token::Unity => return 5,
 			Token::Variable(var) => return var.get_token().chars().count(),
@@ -73,37 +94,474 @@ impl Default for Token {
 }
 
 
+#[derive(Clone)]
 pub struct Context {
 	unary_operators: Vec<UnaryOperator>,
 	binary_operators: Vec<BinaryOperator>,
 	functions: Vec<Function>,
 	variables: Vec<Variable>,
+	parameters: Vec<Parameter>,
+	constants: Vec<Constant>,
+	aliases: Vec<(String, String)>,
+	imaginary_units: Vec<String>,
+	number_regex: fancy_regex::Regex,
 }
 
 impl Context {
 
-	fn new() -> Self {
+	/// A context with the default operators and functions and no variables.
+	pub fn new() -> Self {
 		Self {..Default::default()}
 	}
 
-	pub fn add_variable(&mut self, var: Variable) {
+	/// A context without any operators, functions or variables, to register from scratch.
+	pub fn empty() -> Self {
+		Self {
+			unary_operators: vec![],
+			binary_operators: vec![],
+			functions: vec![],
+			..Default::default()
+		}
+	}
+
+	/// The default context extended to read the output of sympy's `str`, see `sympy::add_syntax`.
+	pub fn sympy() -> Self {
+		let mut context = Self::new();
+		sympy::add_syntax(&mut context).unwrap();
+		context
+	}
+
+	pub fn add_variable(&mut self, var: Variable) -> anyhow::Result<()> {
+		check_identifier("variable", var.get_token())?;
+		self.check_unused(var.get_token())?;
 		self.variables.push(var);
+		Ok(())
+	}
+
+	/// A parameter is a variable that is marked to be fitted, see `fit::fit`. The other
+	/// variables are the independent variables.
+	pub fn add_parameter(&mut self, param: Parameter) -> anyhow::Result<()> {
+		param.check()?;
+		self.add_variable(param.get_variable().clone())?;
+		self.parameters.push(param);
+		Ok(())
+	}
+
+	pub fn add_constant(&mut self, constant: Constant) -> anyhow::Result<()> {
+		check_identifier("constant", constant.get_token())?;
+		self.check_unused(constant.get_token())?;
+		self.constants.push(constant);
+		Ok(())
+	}
+
+	pub fn add_function(&mut self, func: Function) -> anyhow::Result<()> {
+		check_identifier("function", func.get_token())?;
+		self.check_unused(func.get_token())?;
+		self.functions.push(func);
+		Ok(())
+	}
+
+	/// A unary and a binary operator may share a token, as `-` does, the lexer tells them
+	/// apart by the allowed left tokens of the unary one. These must then not include an
+	/// operand, where the binary operator applies. Their precedences may differ.
+	pub fn add_unary_operator(&mut self, uop: UnaryOperator) -> anyhow::Result<()> {
+		check_operator(uop.get_token())?;
+		if self.get_unary_operator(uop.get_token()).is_some() {
+			return Err(anyhow::anyhow!("unary operator '{}' is already registered", uop.get_token()));
+		}
+		self.check_not_identifier(uop.get_token())?;
+		if self.get_binary_operator(uop.get_token()).is_some() {
+			check_prefix_operator(&uop)?;
+		}
+		self.unary_operators.push(uop);
+		Ok(())
+	}
+
+	/// Unary operators allowed after '(' are prefix operators, they are also allowed after
+	/// the new binary operator.
+	pub fn add_binary_operator(&mut self, bop: BinaryOperator) -> anyhow::Result<()> {
+		check_operator(bop.get_token())?;
+		if let Some(existing) = self.get_binary_operator(bop.get_token()) {
+			if existing.get_precedence() != bop.get_precedence() {
+				return Err(anyhow::anyhow!("binary operator '{}' is already registered with precedence {}, not {}",
+					bop.get_token(), existing.get_precedence(), bop.get_precedence()));
+			}
+			return Err(anyhow::anyhow!("binary operator '{}' is already registered", bop.get_token()));
+		}
+		self.check_not_identifier(bop.get_token())?;
+		if let Some(uop) = self.get_unary_operator(bop.get_token()) {
+			check_prefix_operator(uop)?;
+		}
+		let token = Token::Operator(Operator::BinaryOperator(bop.clone()));
+		for uop in self.unary_operators.iter_mut() {
+			if uop.get_allowed_left_tokens().contains(&Token::LeftParen) {
+				uop.get_allowed_left_tokens_mut().push(token.clone());
+			}
+		}
+		self.binary_operators.push(bop);
+		Ok(())
+	}
+
+	/// Lets `spelling` stand for the registered operator, function, variable or constant `token`,
+	/// e.g. `**` for `^`. The lexer produces the token itself, so shunted output only holds `token`.
+	pub fn add_alias(&mut self, spelling: &str, token: &str) -> anyhow::Result<()> {
+		if spelling.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+			check_identifier("alias", spelling)?;
+		} else {
+			check_operator(spelling)?;
+		}
+		self.check_unused(spelling)?;
+		let registered = self.get_unary_operator(token).is_some() || self.get_binary_operator(token).is_some()
+			|| self.get_function(token).is_some() || self.get_variable(token).is_some() || self.get_constant(token).is_some();
+		if !registered {
+			return Err(anyhow::anyhow!("alias '{}' stands for '{}', which is not registered", spelling, token));
+		}
+		self.aliases.push((spelling.to_string(), token.to_string()));
+		Ok(())
+	}
+
+	/// Parses `name(params) = body` and registers it as a function, see `Definition::parse`.
+	pub fn define_function(&mut self, definition: &str) -> anyhow::Result<()> {
+		let func = definition::Definition::parse(definition, self)?.to_function();
+		self.add_function(func)
+	}
+
+	/// Replaces the function of the same name, or adds it, returning the replaced one.
+	pub fn override_function(&mut self, func: Function) -> anyhow::Result<Option<Function>> {
+		let old = self.remove_function(func.get_token());
+		if let Err(err) = self.add_function(func) {
+			self.functions.extend(old);
+			return Err(err);
+		}
+		Ok(old)
+	}
+
+	pub fn override_unary_operator(&mut self, uop: UnaryOperator) -> anyhow::Result<Option<UnaryOperator>> {
+		let old = self.remove_unary_operator(uop.get_token());
+		if let Err(err) = self.add_unary_operator(uop) {
+			self.unary_operators.extend(old);
+			return Err(err);
+		}
+		Ok(old)
+	}
+
+	/// The unary operators allowed after the replaced operator stay allowed after the new one.
+	pub fn override_binary_operator(&mut self, bop: BinaryOperator) -> anyhow::Result<Option<BinaryOperator>> {
+		let allowed_after: Vec<bool> = match self.get_binary_operator(bop.get_token()) {
+			Some(old) => self.unary_operators.iter().map(|uop| is_allowed_after(uop, old)).collect(),
+			None => vec![false; self.unary_operators.len()],
+		};
+		let old = self.remove_binary_operator(bop.get_token());
+		if let Err(err) = self.add_binary_operator(bop.clone()) {
+			if let Some(old) = old {
+				let token = Token::Operator(Operator::BinaryOperator(old.clone()));
+				for (uop, allowed) in self.unary_operators.iter_mut().zip(allowed_after) {
+					if allowed {
+						uop.get_allowed_left_tokens_mut().push(token.clone());
+					}
+				}
+				self.binary_operators.push(old);
+			}
+			return Err(err);
+		}
+		let token = Token::Operator(Operator::BinaryOperator(bop));
+		for (uop, allowed) in self.unary_operators.iter_mut().zip(allowed_after) {
+			if allowed && !uop.get_allowed_left_tokens().contains(&token) {
+				uop.get_allowed_left_tokens_mut().push(token.clone());
+			}
+		}
+		Ok(old)
+	}
+
+	/// Also removes the parameter marking of the variable.
+	pub fn remove_variable(&mut self, token: &str) -> Option<Variable> {
+		let i = self.variables.iter().position(|var| var.get_token() == token)?;
+		self.parameters.retain(|param| param.get_token() != token);
+		Some(self.variables.remove(i))
+	}
+
+	pub fn remove_constant(&mut self, token: &str) -> Option<Constant> {
+		let i = self.constants.iter().position(|constant| constant.get_token() == token)?;
+		Some(self.constants.remove(i))
+	}
+
+	/// Returns the token the alias stood for.
+	pub fn remove_alias(&mut self, spelling: &str) -> Option<String> {
+		let i = self.aliases.iter().position(|(alias, _)| alias == spelling)?;
+		Some(self.aliases.remove(i).1)
+	}
+
+	pub fn remove_function(&mut self, token: &str) -> Option<Function> {
+		let i = self.functions.iter().position(|func| func.get_token() == token)?;
+		Some(self.functions.remove(i))
+	}
+
+	pub fn remove_unary_operator(&mut self, token: &str) -> Option<UnaryOperator> {
+		let i = self.unary_operators.iter().position(|uop| uop.get_token() == token)?;
+		Some(self.unary_operators.remove(i))
+	}
+
+	/// Also forgets the operator in the allowed left tokens of the unary operators.
+	pub fn remove_binary_operator(&mut self, token: &str) -> Option<BinaryOperator> {
+		let i = self.binary_operators.iter().position(|bop| bop.get_token() == token)?;
+		let bop = self.binary_operators.remove(i);
+		for uop in self.unary_operators.iter_mut() {
+			uop.get_allowed_left_tokens_mut()
+				.retain(|left| !matches!(left, Token::Operator(Operator::BinaryOperator(b)) if b == &bop));
+		}
+		Some(bop)
+	}
+
+	pub fn get_unary_operators(&self) -> &[UnaryOperator] {
+		&self.unary_operators
+	}
+
+	pub fn get_binary_operators(&self) -> &[BinaryOperator] {
+		&self.binary_operators
+	}
+
+	pub fn get_functions(&self) -> &[Function] {
+		&self.functions
+	}
+
+	pub fn get_variables(&self) -> &[Variable] {
+		&self.variables
+	}
+
+	pub fn get_constants(&self) -> &[Constant] {
+		&self.constants
+	}
+
+	/// (spelling, token) pairs, see `add_alias`.
+	pub fn get_aliases(&self) -> &[(String, String)] {
+		&self.aliases
+	}
+
+	pub fn get_parameters(&self) -> &[Parameter] {
+		&self.parameters
+	}
+
+	pub fn get_parameter(&self, token: &str) -> Option<&Parameter> {
+		self.parameters.iter().find(|param| param.get_token() == token)
+	}
+
+	/// The variables that are not parameters, in context order.
+	pub fn get_independent_variables(&self) -> Vec<&Variable> {
+		self.variables.iter().filter(|var| self.get_parameter(var.get_token()).is_none()).collect()
+	}
+
+	pub fn get_unary_operator(&self, token: &str) -> Option<&UnaryOperator> {
+		self.unary_operators.iter().find(|uop| uop.get_token() == token)
+	}
+
+	pub fn get_binary_operator(&self, token: &str) -> Option<&BinaryOperator> {
+		self.binary_operators.iter().find(|bop| bop.get_token() == token)
+	}
+
+	pub fn get_function(&self, token: &str) -> Option<&Function> {
+		self.functions.iter().find(|func| func.get_token() == token)
+	}
+
+	pub fn get_variable(&self, token: &str) -> Option<&Variable> {
+		self.variables.iter().find(|var| var.get_token() == token)
+	}
+
+	pub fn get_constant(&self, token: &str) -> Option<&Constant> {
+		self.constants.iter().find(|constant| constant.get_token() == token)
+	}
+
+	// A name spelled exactly like another function, variable or operator is ambiguous. Other
+	// prefixes lex fine, the lexer takes the longest match ending at an identifier boundary.
+	fn check_unused(&self, token: &str) -> anyhow::Result<()> {
+		if self.get_function(token).is_some() {
+			return Err(anyhow::anyhow!("'{}' is already registered as a function", token));
+		}
+		if self.get_variable(token).is_some() {
+			return Err(anyhow::anyhow!("'{}' is already registered as a variable", token));
+		}
+		if self.get_constant(token).is_some() {
+			return Err(anyhow::anyhow!("'{}' is already registered as a constant", token));
+		}
+		if self.is_alias(token) {
+			return Err(anyhow::anyhow!("'{}' is already registered as an alias", token));
+		}
+		if self.get_unary_operator(token).is_some() || self.get_binary_operator(token).is_some() {
+			return Err(anyhow::anyhow!("'{}' is already registered as an operator", token));
+		}
+		if self.imaginary_units.iter().any(|unit| unit == token) {
+			return Err(anyhow::anyhow!("'{}' is an imaginary unit, see Context::set_imaginary_units", token));
+		}
+		Ok(())
+	}
+
+	fn check_not_identifier(&self, token: &str) -> anyhow::Result<()> {
+		if self.get_function(token).is_some() || self.get_variable(token).is_some() || self.get_constant(token).is_some() {
+			return Err(anyhow::anyhow!("operator '{}' is already registered as a function, variable or constant", token));
+		}
+		if self.is_alias(token) {
+			return Err(anyhow::anyhow!("operator '{}' is already registered as an alias", token));
+		}
+		Ok(())
+	}
+
+	fn is_alias(&self, spelling: &str) -> bool {
+		self.aliases.iter().any(|(alias, _)| alias == spelling)
+	}
+
+	pub fn get_imaginary_units(&self) -> &[String] {
+		&self.imaginary_units
+	}
+
+	/// Sets the spellings accepted as imaginary unit in number literals, e.g. `["j"]`
+	/// to keep `i` free for use as a variable. A unit can not be the name of a variable or
+	/// constant, `2j` would no longer be read as a product.
+	pub fn set_imaginary_units(&mut self, units: &[&str]) -> anyhow::Result<()> {
+		for unit in units.iter() {
+			if self.get_variable(unit).is_some() {
+				return Err(anyhow::anyhow!("imaginary unit '{}' is already registered as a variable", unit));
+			}
+			if self.get_constant(unit).is_some() {
+				return Err(anyhow::anyhow!("imaginary unit '{}' is already registered as a constant", unit));
+			}
+		}
+		let units: Vec<String> = units.iter().map(|unit| unit.to_string()).collect();
+		self.number_regex = varnum::number_regex(&units)?;
+		self.imaginary_units = units;
+		Ok(())
+	}
+
+}
+
+fn check_identifier(kind: &str, token: &str) -> anyhow::Result<()> {
+	let mut chars = token.chars();
+	let valid = match chars.next() {
+		Some(c) => (c.is_alphabetic() || c == '_') && chars.all(|c| c.is_alphanumeric() || c == '_'),
+		None => false,
+	};
+	if !valid {
+		return Err(anyhow::anyhow!("{} name '{}' is not an identifier", kind, token));
+	}
+	Ok(())
+}
+
+fn check_operator(token: &str) -> anyhow::Result<()> {
+	if token.is_empty() || token.chars().any(|c| c.is_whitespace() || c == '(' || c == ')' || c == ',') {
+		return Err(anyhow::anyhow!("operator '{}' must be non-empty without whitespace, parentheses or commas", token));
+	}
+	if token.starts_with(|c: char| c.is_ascii_digit()) {
+		return Err(anyhow::anyhow!("operator '{}' must not start with a digit", token));
+	}
+	Ok(())
+}
+
+// A unary operator sharing its token with a binary one must not follow an operand
+fn check_prefix_operator(uop: &UnaryOperator) -> anyhow::Result<()> {
+	if uop.get_allowed_left_tokens().iter().any(lexer::is_operand) {
+		return Err(anyhow::anyhow!("unary operator '{}' is allowed after an operand, where the binary operator '{}' applies",
+			uop.get_token(), uop.get_token()));
 	}
+	Ok(())
+}
 
+fn is_allowed_after(uop: &UnaryOperator, bop: &BinaryOperator) -> bool {
+	uop.get_allowed_left_tokens().contains(&Token::Operator(Operator::BinaryOperator(bop.clone())))
 }
 
 impl Default for Context {
 
 	fn default() -> Self {
+		let imaginary_units: Vec<String> = varnum::DEFAULT_IMAGINARY_UNITS.iter().map(|unit| unit.to_string()).collect();
 		Context {
 			unary_operators: operators::default_unary_operators(),
 			binary_operators: operators::default_binary_operators(),
 			functions: functions::default_functions(),
 			variables: vec![],
+			parameters: vec![],
+			constants: vec![],
+			aliases: vec![],
+			number_regex: varnum::number_regex(&imaginary_units).unwrap(),
+			imaginary_units: imaginary_units,
 		}
 	}
 
 }
 
+#[cfg(test)]
+mod tests {
+	use crate::expression::{
+		Context,
+		Token,
+		functions::Function,
+		operators::{Op, Operator, UnaryOperator, BinaryOperator},
+		shunter,
+		varnum::{Variable, Constant},
+	};
+
+	#[test]
+	fn only_exact_names_collide() {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("signal")).unwrap();
+		context.add_variable(Variable::new("sx")).unwrap();
+		context.add_function(Function::new("s", 1)).unwrap();
+		assert!(context.add_variable(Variable::new("sin")).is_err());
+		assert!(context.add_function(Function::new("sx", 1)).is_err());
+		assert!(context.define_function("signal(a) = a").is_err());
+	}
+
+	#[test]
+	fn imaginary_units_are_not_names() {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("j")).unwrap();
+		context.add_constant(Constant::from_real("J", 2.0)).unwrap();
+		let err = context.set_imaginary_units(&["i", "j"]).unwrap_err();
+		assert_eq!(err.to_string(), "imaginary unit 'j' is already registered as a variable");
+		let err = context.set_imaginary_units(&["J"]).unwrap_err();
+		assert_eq!(err.to_string(), "imaginary unit 'J' is already registered as a constant");
+		assert_eq!(context.get_imaginary_units(), &["i", "I"]);
 
+		let mut context = Context::new();
+		context.set_imaginary_units(&["j"]).unwrap();
+		context.add_variable(Variable::new("i")).unwrap();
+		assert!(context.add_variable(Variable::new("j")).is_err());
+		assert_eq!(shunter::stringify_rpn(&shunter::shunt("2j*i", &context).unwrap()), "2j,i,*,");
+	}
 
+	// The unit j comes after the function, so re-adding the function fails
+	#[test]
+	fn failed_override_keeps_the_function() {
+		let mut context = Context::new();
+		context.add_function(Function::new("j", 1)).unwrap();
+		context.set_imaginary_units(&["i", "j"]).unwrap();
+		assert!(context.override_function(Function::new_variadic("j", 2)).is_err());
+		assert!(context.get_function("j") == Some(&Function::new("j", 1)));
+		assert_eq!(context.get_function("j").unwrap().get_n_inputs(), 1);
+	}
+
+	// A unary '-' that follows operands can not share its token, made here by hand
+	#[test]
+	fn failed_override_keeps_the_binary_operator() {
+		let mut context = Context::new();
+		let old = context.get_binary_operator("-").unwrap().clone();
+		let neg = context.unary_operators.iter_mut().find(|uop| uop.get_token() == "-").unwrap();
+		neg.get_allowed_left_tokens_mut().push(Token::RightParen);
+		assert!(context.override_binary_operator(BinaryOperator::new(String::from("-"), 4, true)).is_err());
+		assert!(context.get_binary_operator("-") == Some(&old));
+		let token = Token::Operator(Operator::BinaryOperator(old));
+		for uop in context.get_unary_operators() {
+			assert!(uop.get_allowed_left_tokens().contains(&token));
+		}
+	}
+
+	#[test]
+	fn shared_operator_token_must_stay_unambiguous() {
+		let mut context = Context::new();
+		let postfix = UnaryOperator::new(String::from("-"), 10, false, vec![Token::RightParen]);
+		assert!(context.override_unary_operator(postfix).is_err());
+		assert!(context.get_unary_operator("-").is_some());
+
+		let tilde = UnaryOperator::new(String::from("~"), 10, false, vec![Token::NoToken, Token::RightParen]);
+		context.add_unary_operator(tilde).unwrap();
+		assert!(context.add_binary_operator(BinaryOperator::new(String::from("~"), 5, true)).is_err());
+		context.add_binary_operator(BinaryOperator::new(String::from("%"), 5, true)).unwrap();
+	}
+}
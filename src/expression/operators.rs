@@ -1,7 +1,15 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tch::Tensor;
 
 use crate::expression::{
 	Token,
 	Context,
+	ast::Expr,
+	derivative::{sub, mul, div, pow, neg, log},
+	functions::{TensorFn, DerivativeFn},
+	varnum::Number,
 };
 
 pub trait Op {
@@ -14,22 +22,79 @@ pub trait Op {
 
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone)]
 pub struct UnaryOperator {
 	token: String,
 	precedence: u8,
 	is_left_associative: bool,
-	allowed_left_tokens: Vec<Token>
+	allowed_left_tokens: Vec<Token>,
+	eval: Option<TensorFn>,
+	derivative: Option<DerivativeFn>,
+	is_builtin: bool,
 }
 
 impl UnaryOperator {
 	pub fn new(token: String, precedence: u8, is_left_associative: bool, allowed_left_tokens: Vec<Token>) -> Self {
-		Self {token, precedence, is_left_associative, allowed_left_tokens}
+		Self {token, precedence, is_left_associative, allowed_left_tokens, eval: None, derivative: None, is_builtin: false}
+	}
+
+	/// The rule receives the single argument as a slice of length one.
+	pub fn with_eval<F>(mut self, eval: F) -> Self
+		where F: Fn(&[Tensor]) -> anyhow::Result<Tensor> + Send + Sync + 'static
+	{
+		self.eval = Some(Arc::new(eval));
+		self.is_builtin = false;
+		self
+	}
+
+	pub fn with_derivative<F>(mut self, derivative: F) -> Self
+		where F: Fn(&[Expr], usize, &Context) -> anyhow::Result<Expr> + Send + Sync + 'static
+	{
+		self.derivative = Some(Arc::new(derivative));
+		self.is_builtin = false;
+		self
+	}
+
+	/// Whether the rules are the default ones of the token, only then does simplification
+	/// apply the arithmetic identities and folds to the operator.
+	pub fn is_builtin(&self) -> bool {
+		self.is_builtin
+	}
+
+	pub (super) fn as_builtin(mut self) -> Self {
+		self.is_builtin = true;
+		self
 	}
 
 	pub fn get_allowed_left_tokens(&self) -> &[Token] {
 		&self.allowed_left_tokens
 	}
+
+	pub (super) fn get_allowed_left_tokens_mut(&mut self) -> &mut Vec<Token> {
+		&mut self.allowed_left_tokens
+	}
+
+	pub fn get_eval(&self) -> Option<&TensorFn> {
+		self.eval.as_ref()
+	}
+
+	pub fn get_derivative(&self) -> Option<&DerivativeFn> {
+		self.derivative.as_ref()
+	}
+
+	pub fn evaluate(&self, args: &[Tensor]) -> anyhow::Result<Tensor> {
+		match &self.eval {
+			Some(eval) => return eval(args),
+			None => return Err(anyhow::anyhow!("no evaluation rule for unary operator '{}'", self.token)),
+		}
+	}
+
+	pub fn partial_derivative(&self, args: &[Expr], i: usize, context: &Context) -> anyhow::Result<Expr> {
+		match &self.derivative {
+			Some(derivative) => return derivative(args, i, context),
+			None => return Err(anyhow::anyhow!("no derivative rule for unary operator '{}'", self.token)),
+		}
+	}
 }
 
 impl Op for UnaryOperator {
@@ -46,17 +111,90 @@ impl Op for UnaryOperator {
     }
 }
 
+impl fmt::Debug for UnaryOperator {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("UnaryOperator")
+			.field("token", &self.token)
+			.field("precedence", &self.precedence)
+			.field("is_left_associative", &self.is_left_associative)
+			.field("allowed_left_tokens", &self.allowed_left_tokens)
+			.finish()
+	}
+}
+
+// As for functions, the rules an operator carries are not compared
+impl PartialEq for UnaryOperator {
+	fn eq(&self, other: &Self) -> bool {
+		self.token == other.token && self.precedence == other.precedence
+			&& self.is_left_associative == other.is_left_associative
+			&& self.allowed_left_tokens == other.allowed_left_tokens
+	}
+}
 
-#[derive(Debug, Clone, PartialEq)]
+
+#[derive(Clone)]
 pub struct BinaryOperator {
 	token: String,
 	precedence: u8,
 	is_left_associative: bool,
+	eval: Option<TensorFn>,
+	derivative: Option<DerivativeFn>,
+	is_builtin: bool,
 }
 
 impl BinaryOperator {
 	pub fn new(token: String, precedence: u8, is_left_associative: bool) -> Self {
-		Self {token: token, precedence: precedence, is_left_associative: is_left_associative}
+		Self {token: token, precedence: precedence, is_left_associative: is_left_associative, eval: None, derivative: None, is_builtin: false}
+	}
+
+	/// The rule receives the left and right hand sides as a slice of length two.
+	pub fn with_eval<F>(mut self, eval: F) -> Self
+		where F: Fn(&[Tensor]) -> anyhow::Result<Tensor> + Send + Sync + 'static
+	{
+		self.eval = Some(Arc::new(eval));
+		self.is_builtin = false;
+		self
+	}
+
+	pub fn with_derivative<F>(mut self, derivative: F) -> Self
+		where F: Fn(&[Expr], usize, &Context) -> anyhow::Result<Expr> + Send + Sync + 'static
+	{
+		self.derivative = Some(Arc::new(derivative));
+		self.is_builtin = false;
+		self
+	}
+
+	/// Whether the rules are the default ones of the token, only then does simplification
+	/// apply the arithmetic identities and folds to the operator.
+	pub fn is_builtin(&self) -> bool {
+		self.is_builtin
+	}
+
+	pub (super) fn as_builtin(mut self) -> Self {
+		self.is_builtin = true;
+		self
+	}
+
+	pub fn get_eval(&self) -> Option<&TensorFn> {
+		self.eval.as_ref()
+	}
+
+	pub fn get_derivative(&self) -> Option<&DerivativeFn> {
+		self.derivative.as_ref()
+	}
+
+	pub fn evaluate(&self, args: &[Tensor]) -> anyhow::Result<Tensor> {
+		match &self.eval {
+			Some(eval) => return eval(args),
+			None => return Err(anyhow::anyhow!("no evaluation rule for binary operator '{}'", self.token)),
+		}
+	}
+
+	pub fn partial_derivative(&self, args: &[Expr], i: usize, context: &Context) -> anyhow::Result<Expr> {
+		match &self.derivative {
+			Some(derivative) => return derivative(args, i, context),
+			None => return Err(anyhow::anyhow!("no derivative rule for binary operator '{}'", self.token)),
+		}
 	}
 }
 
@@ -74,6 +212,24 @@ impl Op for BinaryOperator {
     }
 }
 
+impl fmt::Debug for BinaryOperator {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BinaryOperator")
+			.field("token", &self.token)
+			.field("precedence", &self.precedence)
+			.field("is_left_associative", &self.is_left_associative)
+			.finish()
+	}
+}
+
+impl PartialEq for BinaryOperator {
+	fn eq(&self, other: &Self) -> bool {
+		self.token == other.token && self.precedence == other.precedence
+			&& self.is_left_associative == other.is_left_associative
+	}
+}
+
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Operator {
@@ -106,87 +262,161 @@ impl Op for Operator {
 
 }
 
-
-pub (super) fn begins_with_unary_operator<'a>(expr: &str, tokens: &Vec<Token>, context: &'a Context) -> Option<&'a UnaryOperator> {
-	for uop in context.unary_operators.iter() {
-		if expr.starts_with(&uop.token) {
-			for allowed_left_tok in uop.get_allowed_left_tokens().iter() {
-				if tokens.last().unwrap().eq(allowed_left_tok) {
-					return Some(uop);
-				}
-			}
-		}
+/// Whether the operand `child` of `parent` has to be written in parentheses for the shunter
+/// to read back the same tree. `is_right` is false for the left hand side of a binary
+/// operator only, unary operators are prefix and take their operand on the right.
+pub fn needs_parens(parent: &Operator, child: &Operator, is_right: bool) -> bool {
+	let (p, q) = (parent.get_precedence(), child.get_precedence());
+	if !is_right {
+		// The child is popped when parent comes in
+		return !(q > p || (q == p && parent.get_is_left_associative()));
 	}
-	None
-}
-
-pub (super) fn begins_with_binary_operator<'a>(expr: &str, _tokens: &Vec<Token>, context: &'a Context) -> Option<&'a BinaryOperator> {
-	for bop in context.binary_operators.iter() {
-		if expr.starts_with(&bop.token) {
-			return Some(bop);
+	if let Operator::UnaryOperator(uop) = child {
+		if !uop.get_allowed_left_tokens().contains(&Token::Operator(parent.clone())) {
+			return true;
 		}
 	}
-	None
+	// Otherwise parent would be popped when the child comes in
+	return p > q || (p == q && child.get_is_left_associative());
 }
 
 pub enum DefaultOperetor {
 	Neg,
+	Not,
 	Pow,
 	Mul,
 	Div,
 	Add,
 	Sub,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+	Eq,
+	Ne,
+	And,
+	Or,
+}
+
+fn num(value: f64) -> Expr {
+	return Expr::Number(Number::from_real(value));
+}
+
+fn elementwise(eval: fn(&Tensor, &Tensor) -> Result<Tensor, tch::TchError>) -> impl Fn(&[Tensor]) -> anyhow::Result<Tensor> {
+	return move |args: &[Tensor]| Ok(eval(&args[0], &args[1])?);
 }
 
 pub fn default_unary_operators() -> Vec<UnaryOperator> {
+	let mut allowed_left_tokens = vec![Token::NoToken, Token::LeftParen, Token::Comma];
+	for bop in default_binary_operators() {
+		allowed_left_tokens.push(Token::Operator(Operator::BinaryOperator(bop)));
+	}
 	return vec![
-		UnaryOperator { 
-			token: String::from("-"), 
-			precedence: default_precedence(DefaultOperetor::Neg), 
-			is_left_associative: false,
-			allowed_left_tokens: vec![Token::NoToken, Token::LeftParen],
-		},
+		UnaryOperator::new(String::from("-"), default_precedence(DefaultOperetor::Neg), false, allowed_left_tokens.clone())
+			.with_eval(|args| Ok(args[0].f_neg()?))
+			.with_derivative(|_, _, _| Ok(num(-1.0)))
+			.as_builtin(),
+		UnaryOperator::new(String::from("!"), default_precedence(DefaultOperetor::Not), false, allowed_left_tokens)
+			.with_eval(|args| Ok(args[0].f_logical_not()?))
+			.with_derivative(|_, _, _| Ok(Expr::Zero))
+			.as_builtin(),
 	];
 }
 
 pub fn default_binary_operators() -> Vec<BinaryOperator> {
+	let binary = |token: &str, op: DefaultOperetor, is_left_associative: bool| {
+		BinaryOperator::new(String::from(token), default_precedence(op), is_left_associative)
+	};
+	// Comparisons and logical operators are piecewise constant
+	let piecewise = |token: &str, op: DefaultOperetor, eval: fn(&Tensor, &Tensor) -> Result<Tensor, tch::TchError>| {
+		binary(token, op, true)
+			.with_eval(elementwise(eval))
+			.with_derivative(|_, _, _| Ok(Expr::Zero))
+	};
 	return vec![
-		BinaryOperator { 
-			token: String::from("^"),
-			precedence: default_precedence(DefaultOperetor::Pow), 
-			is_left_associative: false 
-		},
-		BinaryOperator { 
-			token: String::from("*"),
-			precedence: default_precedence(DefaultOperetor::Mul), 
-			is_left_associative: false 
-		},
-		BinaryOperator { 
-			token: String::from("/"),
-			precedence: default_precedence(DefaultOperetor::Div), 
-			is_left_associative: false 
-		},
-		BinaryOperator { 
-			token: String::from("+"),
-			precedence: default_precedence(DefaultOperetor::Add), 
-			is_left_associative: false 
-		},
-		BinaryOperator { 
-			token: String::from("-"),
-			precedence: default_precedence(DefaultOperetor::Sub), 
-			is_left_associative: false 
-		},
-	];
+		binary("^", DefaultOperetor::Pow, false)
+			.with_eval(elementwise(Tensor::f_pow))
+			.with_derivative(|args, i, context| {
+				// d(a^b) = b*a^(b-1)*da + a^b*log(a)*db
+				let (base, exponent) = (args[0].clone(), args[1].clone());
+				if i == 0 {
+					let lowered = pow(context, base, sub(context, exponent.clone(), Expr::Unity)?)?;
+					return mul(context, exponent, lowered);
+				}
+				let log = log(context, base.clone())?;
+				return mul(context, pow(context, base, exponent)?, log);
+			}),
+		binary("*", DefaultOperetor::Mul, true)
+			.with_eval(elementwise(Tensor::f_mul))
+			.with_derivative(|args, i, _| Ok(args[1 - i].clone())),
+		binary("/", DefaultOperetor::Div, true)
+			.with_eval(elementwise(Tensor::f_div))
+			.with_derivative(|args, i, context| {
+				if i == 0 {
+					return div(context, num(1.0), args[1].clone());
+				}
+				let squared = pow(context, args[1].clone(), num(2.0))?;
+				return neg(context, div(context, args[0].clone(), squared)?);
+			}),
+		binary("+", DefaultOperetor::Add, true)
+			.with_eval(elementwise(Tensor::f_add))
+			.with_derivative(|_, _, _| Ok(Expr::Unity)),
+		binary("-", DefaultOperetor::Sub, true)
+			.with_eval(elementwise(Tensor::f_sub))
+			.with_derivative(|_, i, _| if i == 0 { Ok(Expr::Unity) } else { Ok(num(-1.0)) }),
+		piecewise("<", DefaultOperetor::Lt, Tensor::f_lt_tensor),
+		piecewise("<=", DefaultOperetor::Le, Tensor::f_le_tensor),
+		piecewise(">", DefaultOperetor::Gt, Tensor::f_gt_tensor),
+		piecewise(">=", DefaultOperetor::Ge, Tensor::f_ge_tensor),
+		piecewise("==", DefaultOperetor::Eq, Tensor::f_eq_tensor),
+		piecewise("!=", DefaultOperetor::Ne, Tensor::f_ne_tensor),
+		piecewise("&&", DefaultOperetor::And, Tensor::f_logical_and),
+		piecewise("||", DefaultOperetor::Or, Tensor::f_logical_or),
+	].into_iter().map(BinaryOperator::as_builtin).collect();
 }
 
 pub (super) fn default_precedence(op: DefaultOperetor) -> u8 {
 	match op {
 		DefaultOperetor::Neg => 10,
+		DefaultOperetor::Not => 10,
 		DefaultOperetor::Pow => 10,
 		DefaultOperetor::Mul => 5,
 		DefaultOperetor::Div => 5,
 		DefaultOperetor::Add => 3,
 		DefaultOperetor::Sub => 3,
+		// Below the arithmetic operators, comparisons share one level as in Python
+		DefaultOperetor::Lt => 2,
+		DefaultOperetor::Le => 2,
+		DefaultOperetor::Gt => 2,
+		DefaultOperetor::Ge => 2,
+		DefaultOperetor::Eq => 2,
+		DefaultOperetor::Ne => 2,
+		DefaultOperetor::And => 1,
+		DefaultOperetor::Or => 0,
 		//_ => panic!("Unimplemented DefaultOperator was supplied"),
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, shunter};
+
+	fn rpn(expr: &str) -> String {
+		return shunter::stringify_rpn(&shunter::shunt(expr, &Context::new()).unwrap());
+	}
+
+	#[test]
+	fn arithmetic_is_left_associative() {
+		assert_eq!(rpn("5-3-1"), "5,3,-,1,-,");
+		assert_eq!(rpn("8/4/2"), "8,4,/,2,/,");
+		assert_eq!(rpn("8/4*2"), "8,4,/,2,*,");
+		assert_eq!(rpn("2^3^2"), "2,3,2,^,^,");
+	}
+
+	#[test]
+	fn comparisons_bind_looser_than_arithmetic() {
+		assert_eq!(rpn("1+2<3*4"), "1,2,+,3,4,*,<,");
+		assert_eq!(rpn("1<2==1"), "1,2,<,1,==,");
+		assert_eq!(rpn("1<2||2<1&&1"), "1,2,<,2,1,<,1,&&,||,");
+	}
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	definition,
+	shunter,
+	varnum::Variable,
+	diagnostics::{Span, SpanError},
+};
+
+/// One `name = expression` of a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+	target: Variable,
+	body: Expr,
+	rpn: Vec<Token>,
+	dependencies: Vec<Variable>,
+	span: Span,
+}
+
+impl Statement {
+
+	pub fn get_target(&self) -> &Variable {
+		&self.target
+	}
+
+	pub fn get_body(&self) -> &Expr {
+		&self.body
+	}
+
+	pub fn get_rpn(&self) -> &Vec<Token> {
+		&self.rpn
+	}
+
+	/// The targets of other statements this statement refers to.
+	pub fn get_dependencies(&self) -> &[Variable] {
+		&self.dependencies
+	}
+
+	/// Where the statement is in the program source.
+	pub fn get_span(&self) -> Span {
+		self.span
+	}
+
+}
+
+/// Statements separated by `;` or newlines, such as `a = sin(X); b = a*Y; out = b + cos(a)`.
+/// Inside parentheses neither separates statements, so a call may span several lines.
+/// Every target is a variable of the program scope, statements may refer to each other in any
+/// order as long as the references do not form a cycle.
+pub struct Program {
+	statements: Vec<Statement>,
+	scope: Context,
+}
+
+impl Program {
+
+	pub fn parse(source: &str, context: &Context) -> anyhow::Result<Program> {
+		let mut scope = context.clone();
+		let mut targets: Vec<(Variable, Span, usize)> = vec![];
+		for (start, end) in split_statements(source) {
+			let text = &source[start..end];
+			let eq = match definition::find_assignment(text) {
+				Some(eq) => start + eq,
+				None => return Err(SpanError::new("expected 'name = expression'", Span::new(start, end), source).into()),
+			};
+			let name = source[start..eq].trim();
+			let name_start = start + (source[start..eq].len() - source[start..eq].trim_start().len());
+			let name_span = Span::new(name_start, name_start + name.len().max(1));
+			if targets.iter().any(|(target, _, _)| target.get_token() == name) {
+				return Err(SpanError::new(&format!("'{}' is assigned more than once", name), name_span, source).into());
+			}
+			if let Err(err) = scope.add_variable(Variable::new(name)) {
+				return Err(SpanError::new(&err.to_string(), name_span, source).into());
+			}
+			targets.push((Variable::new(name), Span::new(start, end), eq));
+		}
+
+		let mut statements: Vec<Statement> = vec![];
+		for (target, span, eq) in targets.iter() {
+			// Names that are neither targets nor context variables are reported by the lexer
+			let rpn = shunter::shunt_range(source, eq + 1, span.end, &scope)?;
+			let body = Expr::from_rpn(&rpn)?;
+			let mut dependencies: Vec<Variable> = vec![];
+			for token in rpn.iter() {
+				if let Token::Variable(var) = token {
+					if targets.iter().any(|(t, _, _)| t.eq(var)) && !dependencies.contains(var) {
+						dependencies.push(var.clone());
+					}
+				}
+			}
+			statements.push(Statement {target: target.clone(), body, rpn, dependencies, span: *span});
+		}
+
+		let order = topological_order(&statements, source)?;
+		let mut slots: Vec<Option<Statement>> = statements.into_iter().map(Some).collect();
+		let statements = order.into_iter().map(|i| slots[i].take().unwrap()).collect();
+		return Ok(Program {statements, scope});
+	}
+
+	/// The statements in an order where every statement comes after its dependencies.
+	pub fn get_statements(&self) -> &[Statement] {
+		&self.statements
+	}
+
+	/// The context extended by the targets, to build and differentiate expressions that
+	/// refer to them.
+	pub fn get_scope(&self) -> &Context {
+		&self.scope
+	}
+
+	/// The targets no other statement depends on.
+	pub fn get_outputs(&self) -> Vec<&Variable> {
+		return self.statements.iter()
+			.map(|statement| &statement.target)
+			.filter(|target| !self.statements.iter().any(|statement| statement.dependencies.contains(target)))
+			.collect();
+	}
+
+}
+
+// (start, end) byte ranges of the non-blank statements
+fn split_statements(source: &str) -> Vec<(usize, usize)> {
+	let mut ranges = vec![];
+	let mut start = 0;
+	let mut depth = 0usize;
+	for (i, c) in source.char_indices().chain(std::iter::once((source.len(), ';'))) {
+		match c {
+			'(' => depth += 1,
+			')' => depth = depth.saturating_sub(1),
+			_ => {},
+		}
+		// The end of the source closes the last statement whatever the depth, the shunter
+		// reports the unclosed parenthesis
+		if (c != ';' && c != '\n') || (depth > 0 && i < source.len()) {
+			continue;
+		}
+		if !source[start..i].trim().is_empty() {
+			ranges.push((start, i));
+		}
+		start = i + c.len_utf8();
+	}
+	return ranges;
+}
+
+// Depth-first, a statement that is reached again while its dependencies are still being
+// visited closes a cycle
+fn topological_order(statements: &[Statement], source: &str) -> anyhow::Result<Vec<usize>> {
+	#[derive(Clone, Copy, PartialEq)]
+	enum Mark { New, Visiting, Done }
+
+	let index: HashMap<&str, usize> = statements.iter().enumerate()
+		.map(|(i, statement)| (statement.target.get_token(), i))
+		.collect();
+	let mut marks = vec![Mark::New; statements.len()];
+	let mut order: Vec<usize> = vec![];
+
+	for root in 0..statements.len() {
+		if marks[root] != Mark::New {
+			continue;
+		}
+		// (statement, index of the next dependency to visit)
+		let mut stack: Vec<(usize, usize)> = vec![(root, 0)];
+		marks[root] = Mark::Visiting;
+		while let Some((i, next)) = stack.pop() {
+			let dependencies = &statements[i].dependencies;
+			if next == dependencies.len() {
+				marks[i] = Mark::Done;
+				order.push(i);
+				continue;
+			}
+			stack.push((i, next + 1));
+			let j = index[dependencies[next].get_token()];
+			match marks[j] {
+				Mark::Done => {},
+				Mark::New => {
+					marks[j] = Mark::Visiting;
+					stack.push((j, 0));
+				},
+				Mark::Visiting => {
+					let from = stack.iter().position(|(k, _)| *k == j).unwrap();
+					let mut cycle: Vec<&str> = stack[from..].iter().map(|(k, _)| statements[*k].target.get_token()).collect();
+					cycle.push(statements[j].target.get_token());
+					let message = format!("cyclic dependency {}", cycle.join(" -> "));
+					return Err(SpanError::new(&message, statements[j].span, source).into());
+				},
+			}
+		}
+	}
+	return Ok(order);
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, shunter, varnum::Variable, diagnostics::SpanError};
+	use super::Program;
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_variable(Variable::new("Y")).unwrap();
+		return context;
+	}
+
+	fn targets(program: &Program) -> Vec<&str> {
+		return program.get_statements().iter().map(|statement| statement.get_target().get_token()).collect();
+	}
+
+	#[test]
+	fn dependencies_come_first() {
+		let program = Program::parse("out = b + cos(a); b = a*Y; a = sin(X)", &context()).unwrap();
+		assert_eq!(targets(&program), vec!["a", "b", "out"]);
+		let outputs: Vec<&str> = program.get_outputs().iter().map(|var| var.get_token()).collect();
+		assert_eq!(outputs, vec!["out"]);
+		assert_eq!(shunter::stringify_rpn(program.get_statements()[1].get_rpn()), "a,Y,*,");
+	}
+
+	#[test]
+	fn cycles_are_reported() {
+		let err = Program::parse("a = b + X\nb = c*2\nc = a", &context()).err().unwrap();
+		let err = err.downcast_ref::<SpanError>().unwrap();
+		assert_eq!(err.to_string(), "cyclic dependency a -> b -> c -> a at 1:1\na = b + X\n^^^^^^^^^");
+		assert!(Program::parse("a = a + 1", &context()).is_err());
+	}
+
+	#[test]
+	fn statements_span_lines_inside_parentheses() {
+		let source = "a = max(\n    X,\n    Y\n)\nb = (a +\n    1); c = b";
+		let program = Program::parse(source, &context()).unwrap();
+		assert_eq!(targets(&program), vec!["a", "b", "c"]);
+		assert_eq!(shunter::stringify_rpn(program.get_statements()[0].get_rpn()), "X,Y,max,");
+		assert_eq!(shunter::stringify_rpn(program.get_statements()[1].get_rpn()), "a,1,+,");
+	}
+
+	#[test]
+	fn unclosed_parenthesis() {
+		let err = Program::parse("a = max(X,\nY", &context()).err().unwrap();
+		assert!(err.to_string().starts_with("missmatched parenthesis, '(' is never closed at 1:8"));
+	}
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use num_complex::Complex64;
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	definition,
+	operators::{Op, Operator},
+	varnum::Number,
+};
+
+/// Computes an operator or function from its arguments.
+pub type ScalarOp<T> = Arc<dyn Fn(&[T]) -> T + Send + Sync>;
+
+/// A number type the scalar evaluator computes in, with its rules for the default operators
+/// and functions. Tokens without a rule for the type give None.
+pub trait Scalar: Copy + Send + Sync + 'static {
+
+	fn from_number(num: &Number) -> anyhow::Result<Self>;
+
+	fn zero() -> Self;
+
+	fn one() -> Self;
+
+	fn unary_operator(token: &str) -> Option<ScalarOp<Self>>;
+
+	fn binary_operator(token: &str) -> Option<ScalarOp<Self>>;
+
+	fn function(token: &str) -> Option<ScalarOp<Self>>;
+
+}
+
+enum Instruction<T> {
+	Constant(T),
+	Load(usize),
+	Call(usize, ScalarOp<T>),
+}
+
+/// Shunted RPN compiled to a stack program over scalars, without libtorch. The stack is
+/// allocated once when compiling, evaluating does not allocate.
+pub struct ScalarProgram<T: Scalar> {
+	instructions: Vec<Instruction<T>>,
+	n_variables: usize,
+	stack: Vec<T>,
+}
+
+impl<T: Scalar> ScalarProgram<T> {
+
+	pub fn compile(rpn: &Vec<Token>, context: &Context) -> anyhow::Result<Self> {
+		return Self::compile_with(rpn, context, &HashMap::new());
+	}
+
+	/// `functions` gives rules for functions by name, taking precedence over the defaults.
+	/// Defined functions are inlined.
+	pub fn compile_with(rpn: &Vec<Token>, context: &Context, functions: &HashMap<String, ScalarOp<T>>) -> anyhow::Result<Self> {
+		let rpn = definition::inline(&Expr::from_rpn(rpn)?)?.to_rpn();
+		let variables = context.get_variables();
+
+		let mut instructions: Vec<Instruction<T>> = vec![];
+		let mut depth: usize = 0;
+		let mut max_depth: usize = 0;
+		for token in rpn.iter() {
+			let (instruction, n_args) = match token {
+				Token::Number(num) => (Instruction::Constant(T::from_number(num)?), 0),
+				Token::Constant(constant) => {
+					match constant.get_scalar() {
+						Some(value) => (Instruction::Constant(T::from_number(&Number::new(constant.get_token(), value))?), 0),
+						None => return Err(anyhow::anyhow!("tensor constant '{}' in a scalar expression", constant.get_token())),
+					}
+				},
+				Token::Zero => (Instruction::Constant(T::zero()), 0),
+				Token::Unity => (Instruction::Constant(T::one()), 0),
+				Token::Variable(var) => {
+					match variables.iter().position(|v| v.eq(var)) {
+						Some(i) => (Instruction::Load(i), 0),
+						None => return Err(anyhow::anyhow!("variable '{}' is not part of the context", var.get_token())),
+					}
+				},
+				Token::Operator(Operator::UnaryOperator(uop)) => {
+					match T::unary_operator(uop.get_token()) {
+						Some(op) => (Instruction::Call(1, op), 1),
+						None => return Err(anyhow::anyhow!("no scalar rule for unary operator '{}'", uop.get_token())),
+					}
+				},
+				Token::Operator(Operator::BinaryOperator(bop)) => {
+					match T::binary_operator(bop.get_token()) {
+						Some(op) => (Instruction::Call(2, op), 2),
+						None => return Err(anyhow::anyhow!("no scalar rule for binary operator '{}'", bop.get_token())),
+					}
+				},
+				Token::Function(func) => {
+					let n = func.get_n_inputs() as usize;
+					match functions.get(func.get_token()).cloned().or_else(|| T::function(func.get_token())) {
+						Some(op) => (Instruction::Call(n, op), n),
+						None => return Err(anyhow::anyhow!("no scalar rule for function '{}'", func.get_token())),
+					}
+				},
+				Token::NoToken => continue,
+				Token::LeftParen | Token::RightParen | Token::Comma => {
+					return Err(anyhow::anyhow!("{:?} must not be in a shunted expression", token.stringify()));
+				},
+			};
+			if depth < n_args {
+				return Err(anyhow::anyhow!("stack underflow, '{}' takes {} arguments but only {} were available", token.stringify(), n_args, depth));
+			}
+			depth = depth - n_args + 1;
+			max_depth = max_depth.max(depth);
+			instructions.push(instruction);
+		}
+		if depth != 1 {
+			return Err(anyhow::anyhow!("expression leaves {} values on the stack instead of one", depth));
+		}
+
+		return Ok(ScalarProgram {instructions, n_variables: variables.len(), stack: Vec::with_capacity(max_depth)});
+	}
+
+	/// `values` holds one value per context variable, in context order.
+	pub fn evaluate(&mut self, values: &[T]) -> anyhow::Result<T> {
+		if values.len() != self.n_variables {
+			return Err(anyhow::anyhow!("expected {} variable values but {} were given", self.n_variables, values.len()));
+		}
+		let stack = &mut self.stack;
+		stack.clear();
+		for instruction in self.instructions.iter() {
+			match instruction {
+				Instruction::Constant(value) => stack.push(*value),
+				Instruction::Load(i) => stack.push(values[*i]),
+				Instruction::Call(n, op) => {
+					let start = stack.len() - n;
+					let result = op(&stack[start..]);
+					stack.truncate(start);
+					stack.push(result);
+				},
+			}
+		}
+		return Ok(stack[0]);
+	}
+
+}
+
+fn op1<T: Scalar>(f: fn(T) -> T) -> Option<ScalarOp<T>> {
+	return Some(Arc::new(move |args: &[T]| f(args[0])));
+}
+
+fn op2<T: Scalar>(f: fn(T, T) -> T) -> Option<ScalarOp<T>> {
+	return Some(Arc::new(move |args: &[T]| f(args[0], args[1])));
+}
+
+fn fold<T: Scalar>(f: fn(T, T) -> T) -> Option<ScalarOp<T>> {
+	return Some(Arc::new(move |args: &[T]| args[1..].iter().fold(args[0], |acc, arg| f(acc, *arg))));
+}
+
+fn truth(value: bool) -> f64 {
+	if value { 1.0 } else { 0.0 }
+}
+
+// torch.maximum and torch.minimum propagate NaN, f64::max and f64::min do not
+fn maximum(a: f64, b: f64) -> f64 {
+	if a.is_nan() || b.is_nan() { f64::NAN } else { a.max(b) }
+}
+
+fn minimum(a: f64, b: f64) -> f64 {
+	if a.is_nan() || b.is_nan() { f64::NAN } else { a.min(b) }
+}
+
+// torch.sign is 0 at 0, f64::signum is not
+fn sign(x: f64) -> f64 {
+	if x == 0.0 || x.is_nan() { x } else { x.signum() }
+}
+
+impl Scalar for f64 {
+
+	fn from_number(num: &Number) -> anyhow::Result<Self> {
+		if num.is_complex() {
+			return Err(anyhow::anyhow!("complex number '{}' in a real scalar expression", num.get_token()));
+		}
+		return Ok(num.get_value().re);
+	}
+
+	fn zero() -> Self {
+		0.0
+	}
+
+	fn one() -> Self {
+		1.0
+	}
+
+	fn unary_operator(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"-" => return op1(|x: f64| -x),
+			"!" => return op1(|x: f64| truth(x == 0.0)),
+			_ => return None,
+		}
+	}
+
+	fn binary_operator(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"^" => return op2(f64::powf),
+			"*" => return op2(|a: f64, b: f64| a * b),
+			"/" => return op2(|a: f64, b: f64| a / b),
+			"+" => return op2(|a: f64, b: f64| a + b),
+			"-" => return op2(|a: f64, b: f64| a - b),
+			"<" => return op2(|a: f64, b: f64| truth(a < b)),
+			"<=" => return op2(|a: f64, b: f64| truth(a <= b)),
+			">" => return op2(|a: f64, b: f64| truth(a > b)),
+			">=" => return op2(|a: f64, b: f64| truth(a >= b)),
+			"==" => return op2(|a: f64, b: f64| truth(a == b)),
+			"!=" => return op2(|a: f64, b: f64| truth(a != b)),
+			"&&" => return op2(|a: f64, b: f64| truth(a != 0.0 && b != 0.0)),
+			"||" => return op2(|a: f64, b: f64| truth(a != 0.0 || b != 0.0)),
+			_ => return None,
+		}
+	}
+
+	fn function(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"sin" => return op1(f64::sin),
+			"cos" => return op1(f64::cos),
+			"tan" => return op1(f64::tan),
+			"exp" => return op1(f64::exp),
+			"log" => return op1(f64::ln),
+			"log10" => return op1(f64::log10),
+			"sqrt" => return op1(f64::sqrt),
+			"abs" => return op1(f64::abs),
+			"sinh" => return op1(f64::sinh),
+			"cosh" => return op1(f64::cosh),
+			"tanh" => return op1(f64::tanh),
+			"asin" => return op1(f64::asin),
+			"acos" => return op1(f64::acos),
+			"atan" => return op1(f64::atan),
+			"atan2" => return op2(f64::atan2),
+			"max" => return fold(maximum),
+			"min" => return fold(minimum),
+			"sum" => return fold(|a: f64, b: f64| a + b),
+			"pow" => return op2(f64::powf),
+			"sign" => return op1(sign),
+			"floor" => return op1(f64::floor),
+			"ceil" => return op1(f64::ceil),
+			// torch.round rounds half to even
+			"round" => return op1(f64::round_ties_even),
+			"clamp" => return Some(Arc::new(|args: &[f64]| minimum(maximum(args[0], args[1]), args[2]))),
+			"where" => return Some(Arc::new(|args: &[f64]| if args[0] != 0.0 { args[1] } else { args[2] })),
+			_ => return None,
+		}
+	}
+
+}
+
+fn complex_truth(value: bool) -> Complex64 {
+	Complex64::new(truth(value), 0.0)
+}
+
+fn is_nonzero(z: Complex64) -> bool {
+	z != Complex64::new(0.0, 0.0)
+}
+
+// Ordering, rounding, sign and the extrema are not defined for complex tensors either
+impl Scalar for Complex64 {
+
+	fn from_number(num: &Number) -> anyhow::Result<Self> {
+		return Ok(num.get_value());
+	}
+
+	fn zero() -> Self {
+		Complex64::new(0.0, 0.0)
+	}
+
+	fn one() -> Self {
+		Complex64::new(1.0, 0.0)
+	}
+
+	fn unary_operator(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"-" => return op1(|z: Complex64| -z),
+			"!" => return op1(|z: Complex64| complex_truth(!is_nonzero(z))),
+			_ => return None,
+		}
+	}
+
+	fn binary_operator(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"^" => return op2(Complex64::powc),
+			"*" => return op2(|a: Complex64, b: Complex64| a * b),
+			"/" => return op2(|a: Complex64, b: Complex64| a / b),
+			"+" => return op2(|a: Complex64, b: Complex64| a + b),
+			"-" => return op2(|a: Complex64, b: Complex64| a - b),
+			"==" => return op2(|a: Complex64, b: Complex64| complex_truth(a == b)),
+			"!=" => return op2(|a: Complex64, b: Complex64| complex_truth(a != b)),
+			"&&" => return op2(|a: Complex64, b: Complex64| complex_truth(is_nonzero(a) && is_nonzero(b))),
+			"||" => return op2(|a: Complex64, b: Complex64| complex_truth(is_nonzero(a) || is_nonzero(b))),
+			_ => return None,
+		}
+	}
+
+	fn function(token: &str) -> Option<ScalarOp<Self>> {
+		match token {
+			"sin" => return op1(Complex64::sin),
+			"cos" => return op1(Complex64::cos),
+			"tan" => return op1(Complex64::tan),
+			"exp" => return op1(Complex64::exp),
+			"log" => return op1(Complex64::ln),
+			"log10" => return op1(|z: Complex64| z.ln() / std::f64::consts::LN_10),
+			"sqrt" => return op1(Complex64::sqrt),
+			"abs" => return op1(|z: Complex64| Complex64::new(z.norm(), 0.0)),
+			"sinh" => return op1(Complex64::sinh),
+			"cosh" => return op1(Complex64::cosh),
+			"tanh" => return op1(Complex64::tanh),
+			"asin" => return op1(Complex64::asin),
+			"acos" => return op1(Complex64::acos),
+			"atan" => return op1(Complex64::atan),
+			"sum" => return fold(|a: Complex64, b: Complex64| a + b),
+			"pow" => return op2(Complex64::powc),
+			"where" => return Some(Arc::new(|args: &[Complex64]| if is_nonzero(args[0]) { args[1] } else { args[2] })),
+			_ => return None,
+		}
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use num_complex::Complex64;
+	use tch::Tensor;
+
+	use crate::expression::{Context, shunter, evaluator, varnum::Variable};
+	use super::ScalarProgram;
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		return context;
+	}
+
+	fn compile<T: super::Scalar>(expr: &str) -> ScalarProgram<T> {
+		let context = context();
+		return ScalarProgram::compile(&shunter::shunt(expr, &context).unwrap(), &context).unwrap();
+	}
+
+	fn tensor_evaluate(expr: &str, x: Tensor, y: Tensor) -> Tensor {
+		let context = context();
+		let mut variables = HashMap::new();
+		variables.insert(String::from("x"), x);
+		variables.insert(String::from("y"), y);
+		return evaluator::evaluate(&shunter::shunt(expr, &context).unwrap(), &context, &variables).unwrap();
+	}
+
+	const REAL: [&str; 8] = [
+		"sin(x)*exp(-y/2) + x^2",
+		"atan2(y, x) - log10(abs(x) + 1)",
+		"max(x, y, 0.5) + min(x, -y)",
+		"clamp(x, -0.5, 0.5)*sign(y)",
+		"where(x < y, sqrt(abs(y)), tanh(x))",
+		"!(x >= y) || x == 0",
+		"round(x*3) + floor(y) - ceil(x)",
+		"sum(x, y, 1)/pow(2, y)",
+	];
+
+	#[test]
+	fn real_matches_the_tensor_evaluator() {
+		let points = [(0.3, -1.7), (-2.5, 0.25), (0.0, 4.0), (1.5, 1.5)];
+		for expr in REAL {
+			let mut program = compile::<f64>(expr);
+			for (x, y) in points {
+				let expected = tensor_evaluate(expr, Tensor::from(x), Tensor::from(y)).double_value(&[]);
+				let value = program.evaluate(&[x, y]).unwrap();
+				assert!((value - expected).abs() < 1e-10*(1.0 + expected.abs()), "{} at ({}, {}): {} != {}", expr, x, y, value, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn complex_matches_the_tensor_evaluator() {
+		let complex = |z: Complex64| Tensor::complex(&Tensor::from(z.re), &Tensor::from(z.im));
+		let points = [(Complex64::new(0.3, -1.2), Complex64::new(2.0, 0.5)), (Complex64::new(-1.0, 0.0), Complex64::new(0.0, 1.0))];
+		for expr in ["sin(x)*exp(y) + x^2", "sqrt(x)/(y + 2i) - log(y)", "abs(x) + cosh(y) - atan(x)", "where(x == y, 1, x*y)"] {
+			let mut program = compile::<Complex64>(expr);
+			for (x, y) in points {
+				let expected = tensor_evaluate(expr, complex(x), complex(y));
+				let expected = Complex64::new(expected.real().double_value(&[]), expected.imag().double_value(&[]));
+				let value = program.evaluate(&[x, y]).unwrap();
+				assert!((value - expected).norm() < 1e-10*(1.0 + expected.norm()), "{} at ({}, {}): {} != {}", expr, x, y, value, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn reused_without_allocating() {
+		let mut program = compile::<f64>("max(x, y, x*y, x + y*(x - y*(x + y)))");
+		let stack = (program.stack.as_ptr(), program.stack.capacity());
+		for i in 0..100 {
+			let x = i as f64 / 10.0;
+			let expected = x.max(2.0).max(2.0*x).max(x + 2.0*(x - 2.0*(x + 2.0)));
+			assert_eq!(program.evaluate(&[x, 2.0]).unwrap(), expected);
+			assert_eq!((program.stack.as_ptr(), program.stack.capacity()), stack);
+		}
+	}
+
+	#[test]
+	fn round_ties_to_even() {
+		let mut program = compile::<f64>("round(x)");
+		for (x, rounded) in [(0.5, 0.0), (1.5, 2.0), (2.5, 2.0), (-0.5, -0.0), (-1.5, -2.0), (-2.5, -2.0), (2.4, 2.0), (2.6, 3.0)] {
+			assert_eq!(program.evaluate(&[x, 0.0]).unwrap(), rounded);
+		}
+	}
+
+	#[test]
+	fn errors() {
+		let context = context();
+		let rpn = shunter::shunt("x + 2i", &context).unwrap();
+		assert!(ScalarProgram::<f64>::compile(&rpn, &context).is_err());
+		assert!(ScalarProgram::<Complex64>::compile(&rpn, &context).is_ok());
+		let rpn = shunter::shunt("max(x, y)", &context).unwrap();
+		let err = ScalarProgram::<Complex64>::compile(&rpn, &context).err().unwrap();
+		assert_eq!(err.to_string(), "no scalar rule for function 'max'");
+		let err = compile::<f64>("x").evaluate(&[1.0]).unwrap_err();
+		assert_eq!(err.to_string(), "expected 2 variable values but 1 were given");
+	}
+}
@@ -0,0 +1,557 @@
+use num_complex::Complex64;
+use serde::{Serialize, Deserialize};
+use tch::{Kind, Tensor};
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	definition::Definition,
+	functions::Function,
+	operators::{Op, Operator, UnaryOperator, BinaryOperator},
+	varnum::{Number, Constant, ConstantValue, Variable, Parameter, Transform},
+};
+
+/// Bumped whenever the saved layout changes, loading a different version fails.
+pub const FORMAT_VERSION: u32 = 2;
+
+// Evaluation and derivative rules are closures and can not be saved. Operators and functions
+// are saved by their signature and get their rules back from a base context when loaded,
+// functions defined in the expression language are saved with their body instead.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SavedToken {
+	Number {
+		token: String,
+		#[serde(with = "float")]
+		re: f64,
+		#[serde(with = "float")]
+		im: f64,
+		/// Written with an imaginary unit, such as `0i`.
+		imaginary: bool,
+	},
+	Constant { name: String },
+	Zero,
+	Unity,
+	Variable { name: String },
+	Function { name: String, n_inputs: u8 },
+	UnaryOperator { token: String },
+	BinaryOperator { token: String },
+	LeftParen,
+	RightParen,
+	Comma,
+	NoToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedUnaryOperator {
+	pub token: String,
+	pub precedence: u8,
+	pub is_left_associative: bool,
+	pub allowed_left_tokens: Vec<SavedToken>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedBinaryOperator {
+	pub token: String,
+	pub precedence: u8,
+	pub is_left_associative: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedDefinition {
+	pub params: Vec<String>,
+	pub rpn: Vec<SavedToken>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedFunction {
+	pub name: String,
+	pub n_inputs: u8,
+	pub is_variadic: bool,
+	pub definition: Option<SavedDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedParameter {
+	pub lower: Option<f64>,
+	pub upper: Option<f64>,
+	pub transform: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedVariable {
+	pub name: String,
+	pub parameter: Option<SavedParameter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SavedConstantValue {
+	Scalar {
+		#[serde(with = "float")]
+		re: f64,
+		#[serde(with = "float")]
+		im: f64,
+	},
+	/// Row-major values in double precision, `im` is empty for a real tensor.
+	Tensor {
+		shape: Vec<i64>,
+		#[serde(with = "floats")]
+		re: Vec<f64>,
+		#[serde(with = "floats")]
+		im: Vec<f64>,
+	},
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedConstant {
+	pub name: String,
+	pub value: SavedConstantValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedAlias {
+	pub spelling: String,
+	pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedContext {
+	pub unary_operators: Vec<SavedUnaryOperator>,
+	pub binary_operators: Vec<SavedBinaryOperator>,
+	pub functions: Vec<SavedFunction>,
+	pub variables: Vec<SavedVariable>,
+	pub constants: Vec<SavedConstant>,
+	pub aliases: Vec<SavedAlias>,
+	pub imaginary_units: Vec<String>,
+}
+
+/// A context together with a shunted expression in it, the unit a pipeline stage saves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedExpression {
+	pub version: u32,
+	pub context: SavedContext,
+	pub rpn: Vec<SavedToken>,
+}
+
+impl SavedToken {
+
+	pub fn from_token(token: &Token) -> Self {
+		match token {
+			Token::NoToken => return SavedToken::NoToken,
+			Token::Number(num) => {
+				let value = num.get_value();
+				return SavedToken::Number {token: num.get_token().to_string(), re: value.re, im: value.im, imaginary: num.is_complex()};
+			},
+			Token::Constant(constant) => return SavedToken::Constant {name: constant.get_token().to_string()},
+			Token::Zero => return SavedToken::Zero,
+			Token::Unity => return SavedToken::Unity,
+			Token::Variable(var) => return SavedToken::Variable {name: var.get_token().to_string()},
+			Token::Function(func) => return SavedToken::Function {name: func.get_token().to_string(), n_inputs: func.get_n_inputs()},
+			Token::Operator(Operator::UnaryOperator(uop)) => return SavedToken::UnaryOperator {token: uop.get_token().to_string()},
+			Token::Operator(Operator::BinaryOperator(bop)) => return SavedToken::BinaryOperator {token: bop.get_token().to_string()},
+			Token::LeftParen => return SavedToken::LeftParen,
+			Token::RightParen => return SavedToken::RightParen,
+			Token::Comma => return SavedToken::Comma,
+		}
+	}
+
+	/// Operators and functions are looked up in `context`.
+	pub fn to_token(&self, context: &Context) -> anyhow::Result<Token> {
+		match self {
+			SavedToken::NoToken => return Ok(Token::NoToken),
+			SavedToken::Number {token, re, im, imaginary} => {
+				if *imaginary && *re == 0.0 {
+					return Ok(Token::Number(Number::imaginary(token, *im)));
+				}
+				return Ok(Token::Number(Number::new(token, Complex64::new(*re, *im))));
+			},
+			SavedToken::Constant {name} => {
+				match context.get_constant(name) {
+					Some(constant) => return Ok(Token::Constant(constant.clone())),
+					None => return Err(anyhow::anyhow!("constant '{}' is not part of the context", name)),
+				}
+			},
+			SavedToken::Zero => return Ok(Token::Zero),
+			SavedToken::Unity => return Ok(Token::Unity),
+			SavedToken::Variable {name} => return Ok(Token::Variable(Variable::new(name))),
+			SavedToken::Function {name, n_inputs} => {
+				match context.get_function(name) {
+					Some(func) if func.accepts_n_inputs(*n_inputs as usize) => return Ok(Token::Function(func.called_with(*n_inputs))),
+					Some(_) => return Err(anyhow::anyhow!("function '{}' does not take {} arguments", name, n_inputs)),
+					None => return Err(anyhow::anyhow!("function '{}' is not part of the context", name)),
+				}
+			},
+			SavedToken::UnaryOperator {token} => {
+				match context.get_unary_operator(token) {
+					Some(uop) => return Ok(Token::Operator(Operator::UnaryOperator(uop.clone()))),
+					None => return Err(anyhow::anyhow!("unary operator '{}' is not part of the context", token)),
+				}
+			},
+			SavedToken::BinaryOperator {token} => {
+				match context.get_binary_operator(token) {
+					Some(bop) => return Ok(Token::Operator(Operator::BinaryOperator(bop.clone()))),
+					None => return Err(anyhow::anyhow!("binary operator '{}' is not part of the context", token)),
+				}
+			},
+			SavedToken::LeftParen => return Ok(Token::LeftParen),
+			SavedToken::RightParen => return Ok(Token::RightParen),
+			SavedToken::Comma => return Ok(Token::Comma),
+		}
+	}
+
+}
+
+pub fn save_rpn(rpn: &Vec<Token>) -> Vec<SavedToken> {
+	return rpn.iter().map(SavedToken::from_token).collect();
+}
+
+pub fn load_rpn(saved: &[SavedToken], context: &Context) -> anyhow::Result<Vec<Token>> {
+	return saved.iter().map(|token| token.to_token(context)).collect();
+}
+
+/// Fails only for a tensor constant that can not be copied to double precision.
+pub fn save_context(context: &Context) -> anyhow::Result<SavedContext> {
+	let unary_operators = context.get_unary_operators().iter().map(|uop| SavedUnaryOperator {
+		token: uop.get_token().to_string(),
+		precedence: uop.get_precedence(),
+		is_left_associative: uop.get_is_left_associative(),
+		allowed_left_tokens: uop.get_allowed_left_tokens().iter().map(SavedToken::from_token).collect(),
+	}).collect();
+	let binary_operators = context.get_binary_operators().iter().map(|bop| SavedBinaryOperator {
+		token: bop.get_token().to_string(),
+		precedence: bop.get_precedence(),
+		is_left_associative: bop.get_is_left_associative(),
+	}).collect();
+	let functions = context.get_functions().iter().map(|func| SavedFunction {
+		name: func.get_token().to_string(),
+		n_inputs: func.get_n_inputs(),
+		is_variadic: func.is_variadic(),
+		definition: func.get_definition().map(|definition| SavedDefinition {
+			params: definition.get_params().iter().map(|param| param.get_token().to_string()).collect(),
+			rpn: save_rpn(definition.get_rpn()),
+		}),
+	}).collect();
+	let variables = context.get_variables().iter().map(|var| SavedVariable {
+		name: var.get_token().to_string(),
+		parameter: context.get_parameter(var.get_token()).map(|param| {
+			let (lower, upper) = param.get_bounds();
+			SavedParameter {lower, upper, transform: param.get_transform().get_name().to_string()}
+		}),
+	}).collect();
+	let constants = context.get_constants().iter()
+		.map(|constant| Ok(SavedConstant {name: constant.get_token().to_string(), value: save_constant_value(constant)?}))
+		.collect::<anyhow::Result<Vec<SavedConstant>>>()?;
+	return Ok(SavedContext {
+		unary_operators,
+		binary_operators,
+		functions,
+		variables,
+		constants,
+		aliases: context.get_aliases().iter().map(|(spelling, token)| SavedAlias {spelling: spelling.clone(), token: token.clone()}).collect(),
+		imaginary_units: context.get_imaginary_units().to_vec(),
+	});
+}
+
+/// Rebuilds a saved context, taking the rules of operators and functions from `base`, which
+/// is typically `Context::default()` with the custom functions of the pipeline registered.
+pub fn load_context(saved: &SavedContext, base: &Context) -> anyhow::Result<Context> {
+	let mut context = Context::empty();
+	let units: Vec<&str> = saved.imaginary_units.iter().map(|unit| unit.as_str()).collect();
+	context.set_imaginary_units(&units)?;
+
+	// Binary operators go first, the unary ones refer to them in their allowed left tokens
+	for saved_bop in saved.binary_operators.iter() {
+		let rules = match base.get_binary_operator(&saved_bop.token) {
+			Some(rules) => rules,
+			None => return Err(anyhow::anyhow!("binary operator '{}' is not part of the base context, its rules can not be restored", saved_bop.token)),
+		};
+		let mut bop = BinaryOperator::new(saved_bop.token.clone(), saved_bop.precedence, saved_bop.is_left_associative);
+		if let Some(eval) = rules.get_eval().cloned() {
+			bop = bop.with_eval(move |args| eval(args));
+		}
+		if let Some(derivative) = rules.get_derivative().cloned() {
+			bop = bop.with_derivative(move |args, i, context| derivative(args, i, context));
+		}
+		if rules.is_builtin() {
+			bop = bop.as_builtin();
+		}
+		context.add_binary_operator(bop)?;
+	}
+	for saved_uop in saved.unary_operators.iter() {
+		let rules = match base.get_unary_operator(&saved_uop.token) {
+			Some(rules) => rules,
+			None => return Err(anyhow::anyhow!("unary operator '{}' is not part of the base context, its rules can not be restored", saved_uop.token)),
+		};
+		let allowed_left_tokens = load_rpn(&saved_uop.allowed_left_tokens, &context)?;
+		let mut uop = UnaryOperator::new(saved_uop.token.clone(), saved_uop.precedence, saved_uop.is_left_associative, allowed_left_tokens);
+		if let Some(eval) = rules.get_eval().cloned() {
+			uop = uop.with_eval(move |args| eval(args));
+		}
+		if let Some(derivative) = rules.get_derivative().cloned() {
+			uop = uop.with_derivative(move |args, i, context| derivative(args, i, context));
+		}
+		if rules.is_builtin() {
+			uop = uop.as_builtin();
+		}
+		context.add_unary_operator(uop)?;
+	}
+
+	for saved_constant in saved.constants.iter() {
+		context.add_constant(load_constant(saved_constant)?)?;
+	}
+
+	// Plain functions go first, one overridden after a definition calling it is saved after
+	// the definition. Definitions only call functions registered before them, so the ones
+	// they call are loaded by then when kept in their saved order.
+	for saved_func in saved.functions.iter() {
+		if saved_func.definition.is_some() {
+			continue;
+		}
+		let rules = match base.get_function(&saved_func.name) {
+			Some(rules) => rules,
+			None => return Err(anyhow::anyhow!("function '{}' is not part of the base context, its rules can not be restored", saved_func.name)),
+		};
+		let mut func = if saved_func.is_variadic {
+			Function::new_variadic(&saved_func.name, saved_func.n_inputs)
+		} else {
+			Function::new(&saved_func.name, saved_func.n_inputs)
+		};
+		if let Some(eval) = rules.get_eval().cloned() {
+			func = func.with_eval(move |args| eval(args));
+		}
+		if let Some(derivative) = rules.get_derivative().cloned() {
+			func = func.with_derivative(move |args, i, context| derivative(args, i, context));
+		}
+		context.add_function(func)?;
+	}
+	for saved_func in saved.functions.iter() {
+		if let Some(saved_definition) = saved_func.definition.as_ref() {
+			let params: Vec<Variable> = saved_definition.params.iter().map(|param| Variable::new(param)).collect();
+			let body = Expr::from_rpn(&load_rpn(&saved_definition.rpn, &context)?)?;
+			context.add_function(Definition::from_parts(&saved_func.name, params, body).to_function())?;
+		}
+	}
+
+	for saved_var in saved.variables.iter() {
+		match saved_var.parameter.as_ref() {
+			Some(saved_param) => {
+				let param = Parameter::new(&saved_var.name)
+					.with_bounds(saved_param.lower, saved_param.upper)
+					.with_transform(Transform::from_name(&saved_param.transform)?);
+				context.add_parameter(param)?;
+			},
+			None => context.add_variable(Variable::new(&saved_var.name))?,
+		}
+	}
+
+	for saved_alias in saved.aliases.iter() {
+		context.add_alias(&saved_alias.spelling, &saved_alias.token)?;
+	}
+
+	return Ok(context);
+}
+
+impl SavedExpression {
+
+	pub fn new(rpn: &Vec<Token>, context: &Context) -> anyhow::Result<Self> {
+		return Ok(SavedExpression {version: FORMAT_VERSION, context: save_context(context)?, rpn: save_rpn(rpn)});
+	}
+
+	/// The context and the expression, ready to evaluate without parsing.
+	pub fn load(&self, base: &Context) -> anyhow::Result<(Context, Vec<Token>)> {
+		if self.version != FORMAT_VERSION {
+			return Err(anyhow::anyhow!("saved with format version {}, this build reads version {}", self.version, FORMAT_VERSION));
+		}
+		let context = load_context(&self.context, base)?;
+		let rpn = load_rpn(&self.rpn, &context)?;
+		return Ok((context, rpn));
+	}
+
+	pub fn to_json(&self) -> anyhow::Result<String> {
+		return Ok(serde_json::to_string_pretty(self)?);
+	}
+
+	pub fn from_json(json: &str) -> anyhow::Result<Self> {
+		return Ok(serde_json::from_str(json)?);
+	}
+
+	/// MessagePack, with the same layout as the JSON.
+	pub fn to_binary(&self) -> anyhow::Result<Vec<u8>> {
+		return Ok(rmp_serde::to_vec_named(self)?);
+	}
+
+	pub fn from_binary(bytes: &[u8]) -> anyhow::Result<Self> {
+		return Ok(rmp_serde::from_slice(bytes)?);
+	}
+
+}
+
+fn save_constant_value(constant: &Constant) -> anyhow::Result<SavedConstantValue> {
+	match constant.get_value() {
+		ConstantValue::Scalar(value) => return Ok(SavedConstantValue::Scalar {re: value.re, im: value.im}),
+		ConstantValue::Tensor(_) => {
+			let tensor = constant.get_tensor().unwrap();
+			let (re, im) = if tensor.is_complex() {
+				(tensor_values(&tensor.f_real()?)?, tensor_values(&tensor.f_imag()?)?)
+			} else {
+				(tensor_values(&tensor)?, vec![])
+			};
+			return Ok(SavedConstantValue::Tensor {shape: tensor.size(), re, im});
+		},
+	}
+}
+
+fn tensor_values(tensor: &Tensor) -> anyhow::Result<Vec<f64>> {
+	let tensor = tensor.f_to_kind(Kind::Double)?.f_contiguous()?;
+	let mut values = vec![0.0; tensor.numel()];
+	tensor.f_copy_data(&mut values, tensor.numel())?;
+	return Ok(values);
+}
+
+fn load_constant(saved: &SavedConstant) -> anyhow::Result<Constant> {
+	match &saved.value {
+		SavedConstantValue::Scalar {re, im} => return Ok(Constant::new(&saved.name, Complex64::new(*re, *im))),
+		SavedConstantValue::Tensor {shape, re, im} => {
+			let numel: i64 = shape.iter().product();
+			if re.len() as i64 != numel || !(im.is_empty() || im.len() == re.len()) {
+				return Err(anyhow::anyhow!("tensor constant '{}' does not hold {} values for shape {:?}", saved.name, numel, shape));
+			}
+			let mut tensor = Tensor::f_of_slice(re)?;
+			if !im.is_empty() {
+				tensor = Tensor::f_complex(&tensor, &Tensor::f_of_slice(im)?)?;
+			}
+			return Ok(Constant::from_tensor(&saved.name, tensor.f_reshape(shape)?));
+		},
+	}
+}
+
+// JSON has no literals for infinities and NaN, these are written as strings such as "inf"
+mod float {
+	use serde::{Serializer, Deserializer, Deserialize, de::Error};
+
+	pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+		if value.is_finite() {
+			return serializer.serialize_f64(*value);
+		}
+		return serializer.serialize_str(&value.to_string());
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum Float {
+			Number(f64),
+			Text(String),
+		}
+		match Float::deserialize(deserializer)? {
+			Float::Number(value) => return Ok(value),
+			Float::Text(text) => return text.parse::<f64>().map_err(D::Error::custom),
+		}
+	}
+}
+
+mod floats {
+	use serde::{Serializer, Deserializer, Serialize, Deserialize};
+
+	#[derive(Serialize, Deserialize)]
+	struct Float(#[serde(with = "super::float")] f64);
+
+	pub fn serialize<S: Serializer>(values: &[f64], serializer: S) -> Result<S::Ok, S::Error> {
+		return serializer.collect_seq(values.iter().map(|value| Float(*value)));
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f64>, D::Error> {
+		let values = Vec::<Float>::deserialize(deserializer)?;
+		return Ok(values.into_iter().map(|Float(value)| value).collect());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use num_complex::Complex64;
+	use crate::expression::{Context, shunter, functions::Function, varnum::{Constant, Variable, Parameter, Transform}};
+	use super::{SavedExpression, FORMAT_VERSION};
+
+	fn saved() -> SavedExpression {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_constant(Constant::new("g", Complex64::new(9.81, 0.0))).unwrap();
+		let rpn = shunter::shunt("g*X", &context).unwrap();
+		return SavedExpression::new(&rpn, &context).unwrap();
+	}
+
+	#[test]
+	fn constants_round_trip() {
+		let json = saved().to_json().unwrap();
+		let (context, rpn) = SavedExpression::from_json(&json).unwrap().load(&Context::new()).unwrap();
+		assert_eq!(context.get_constant("g").unwrap().get_scalar(), Some(Complex64::new(9.81, 0.0)));
+		assert_eq!(shunter::stringify_rpn(&rpn), "g,X,*,");
+	}
+
+	// Files from before constants would load with them silently missing
+	#[test]
+	fn other_versions_are_rejected() {
+		let mut old = saved();
+		old.version = FORMAT_VERSION - 1;
+		assert!(old.load(&Context::new()).is_err());
+	}
+
+	fn full() -> SavedExpression {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_parameter(Parameter::new("A").with_bounds(Some(0.0), None).with_transform(Transform::Log)).unwrap();
+		context.add_parameter(Parameter::new("f").with_bounds(Some(-1.0), Some(2.0)).with_transform(Transform::Box)).unwrap();
+		context.add_constant(Constant::new("g", Complex64::new(9.81, -1.0))).unwrap();
+		context.define_function("sq(x) = x*x").unwrap();
+		context.define_function("wave(x, k) = sq(sin(k*x)) + g").unwrap();
+		let rpn = shunter::shunt("A*wave(X, f) + 2i", &context).unwrap();
+		return SavedExpression::new(&rpn, &context).unwrap();
+	}
+
+	fn check_loaded(saved: &SavedExpression) {
+		let (context, rpn) = saved.load(&Context::new()).unwrap();
+		assert_eq!(shunter::stringify_rpn(&rpn), "A,X,f,wave,*,2i,+,");
+		let param = context.get_parameter("f").unwrap();
+		assert_eq!(param.get_bounds(), (Some(-1.0), Some(2.0)));
+		assert_eq!(param.get_transform(), Transform::Box);
+		assert_eq!(context.get_parameter("A").unwrap().get_transform(), Transform::Log);
+		assert!(context.get_parameter("X").is_none());
+		assert_eq!(context.get_constant("g").unwrap().get_scalar(), Some(Complex64::new(9.81, -1.0)));
+		let wave = context.get_function("wave").unwrap().get_definition().unwrap();
+		assert_eq!(shunter::stringify_rpn(wave.get_rpn()), "k,x,*,sin,sq,g,+,");
+		assert_eq!(SavedExpression::new(&rpn, &context).unwrap(), *saved);
+	}
+
+	#[test]
+	fn json_round_trip() {
+		let saved = full();
+		let loaded = SavedExpression::from_json(&saved.to_json().unwrap()).unwrap();
+		assert_eq!(loaded, saved);
+		check_loaded(&loaded);
+	}
+
+	#[test]
+	fn binary_round_trip() {
+		let saved = full();
+		let loaded = SavedExpression::from_binary(&saved.to_binary().unwrap()).unwrap();
+		assert_eq!(loaded, saved);
+		check_loaded(&loaded);
+	}
+
+	#[test]
+	fn function_overridden_after_a_definition_calling_it() {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.define_function("twice(x) = 2*sin(x)").unwrap();
+		context.override_function(Function::new("sin", 1).with_eval(|args| Ok(args[0].shallow_clone()))).unwrap();
+		let rpn = shunter::shunt("twice(X)", &context).unwrap();
+		let saved = SavedExpression::new(&rpn, &context).unwrap();
+		let names: Vec<&str> = saved.context.functions.iter().map(|func| func.name.as_str()).collect();
+		assert!(names.iter().position(|name| *name == "twice") < names.iter().position(|name| *name == "sin"));
+		let (context, _) = saved.load(&Context::new()).unwrap();
+		assert!(context.get_function("twice").unwrap().get_definition().is_some());
+	}
+}
@@ -4,7 +4,8 @@ use crate::expression::{
 	Token,
 	Context,
 	lexer,
-	operators::Operator,
+	operators::{Op, Operator},
+	diagnostics::{Span, SpanError},
 };
 
 /*
@@ -15,6 +16,13 @@ pub struct Shunter {
 }
 */
 
+// One frame per open parenthesis, counting the arguments when it belongs to a function call
+struct CallFrame {
+	is_call: bool,
+	n_commas: usize,
+	has_value: bool,
+}
+
 pub fn shunt(expr: &str, context: &Context) -> anyhow::Result<Vec<Token>> {
 	
 	let tokens = lexer::lex(expr, context);
@@ -24,33 +32,89 @@ pub fn shunt(expr: &str, context: &Context) -> anyhow::Result<Vec<Token>> {
 	let tokens = tokens.unwrap();
 	//println!("Lexed tokens: {:?}", tokens);
 
-	let mut operator_stack: Vec<Token> = vec![];
+	let mut operator_stack: Vec<(Token, Span)> = vec![];
 	let mut output: Vec<Token> = vec![];
+	let mut frames: Vec<CallFrame> = vec![];
+	let mut last_token = Token::NoToken;
+	let mut last_span = Span::default();
+
+	for (ref token, span) in tokens {
+		if let Some((message, bad_span)) = check_order(&last_token, last_span, token, span) {
+			return Err(SpanError::new(&message, bad_span, expr).into());
+		}
+
+		match token {
+			Token::Comma | Token::RightParen | Token::NoToken => {},
+			_ => {
+				if let Some(frame) = frames.last_mut() {
+					frame.has_value = true;
+				}
+			},
+		}
 
-	for ref token in tokens {
 		match token { // check this, unecessary clone
 			Token::NoToken => {},
-			Token::Number(_) | Token::Unity | Token::Zero => output.push(token.clone()),
+			Token::Number(_) | Token::Constant(_) | Token::Unity | Token::Zero => output.push(token.clone()),
 			Token::Variable(_) => output.push(token.clone()),
-			Token::Function(_) => operator_stack.push(token.clone()),
+			Token::Function(_) => operator_stack.push((token.clone(), span)),
 			Token::Operator(op) => {
 				if let Err(res) = handle_operator(&mut operator_stack, &mut output, op) {
-					return anyhow::private::Err(res);
+					return Err(SpanError::new(&res.to_string(), span, expr).into());
 				}
-				operator_stack.push(token.clone());
+				operator_stack.push((token.clone(), span));
+			},
+			Token::LeftParen => {
+				let is_call = matches!(last_token, Token::Function(_));
+				frames.push(CallFrame { is_call: is_call, n_commas: 0, has_value: false });
+				operator_stack.push((token.clone(), span));
 			},
-			Token::LeftParen => operator_stack.push(token.clone()),
 			Token::RightParen => {
+				let frame = frames.pop();
 				if let Err(res) = handle_rparen(&mut operator_stack, &mut output) {
-					return anyhow::private::Err(res);
+					return Err(SpanError::new(&res.to_string(), span, expr).into());
+				}
+				let frame = frame.unwrap();
+				if !frame.is_call {
+					if !frame.has_value {
+						return Err(SpanError::new("empty parentheses", span, expr).into());
+					}
+					continue;
+				}
+				if let Err((res, func_span)) = handle_call(&mut operator_stack, &mut output, &frame) {
+					return Err(SpanError::new(&res.to_string(), Span::new(func_span.start, span.end), expr).into());
+				}
+			},
+			Token::Comma => {
+				match frames.last_mut() {
+					Some(frame) if frame.is_call => {
+						if !frame.has_value {
+							return Err(SpanError::new("missing function argument before ','", span, expr).into());
+						}
+						frame.n_commas += 1;
+						frame.has_value = false;
+					},
+					_ => return Err(SpanError::new("',' outside of a function call", span, expr).into()),
+				}
+				// Finish the previous argument, leaving its '(' on the stack
+				while let Some((top, _)) = operator_stack.last() {
+					if top.eq(&Token::LeftParen) {
+						break;
+					}
+					output.push(operator_stack.pop().unwrap().0);
 				}
 			},
-			Token::Comma => {},
 		}
+
+		last_token = token.clone();
+		last_span = span;
+	}
+
+	if let Token::Operator(op) = &last_token {
+		return Err(SpanError::new(&format!("missing operand after '{}'", op.get_token()), last_span, expr).into());
 	}
 
-	if shift_until(&mut operator_stack, &mut output, &Token::LeftParen) {
-		return Err(anyhow::anyhow!("missmatched parenthesis"));
+	if let Some(lparen_span) = shift_until(&mut operator_stack, &mut output, &Token::LeftParen) {
+		return Err(SpanError::new("missmatched parenthesis, '(' is never closed", lparen_span, expr).into());
 	}
 
 	assert!(operator_stack.is_empty());
@@ -58,6 +122,19 @@ pub fn shunt(expr: &str, context: &Context) -> anyhow::Result<Vec<Token>> {
 	return Ok(output);
 }
 
+/// Shunts `source[start..end]`, the spans of errors point into the whole of `source`.
+pub fn shunt_range(source: &str, start: usize, end: usize, context: &Context) -> anyhow::Result<Vec<Token>> {
+	// Blanking out the rest keeps byte offsets the same
+	let blanked = format!("{}{}", " ".repeat(start), &source[start..end]);
+	match shunt(&blanked, context) {
+		Ok(rpn) => return Ok(rpn),
+		Err(err) => match err.downcast_ref::<SpanError>() {
+			Some(span_err) => return Err(SpanError::new(span_err.get_message(), span_err.get_span(), source).into()),
+			None => return Err(err),
+		},
+	}
+}
+
 pub fn stringify_rpn(postfix: &Vec<Token>) -> String {
 	let mut capacity = 0;
 	for tok in postfix {
@@ -71,17 +148,32 @@ pub fn stringify_rpn(postfix: &Vec<Token>) -> String {
 	return ret;
 }
 
-fn handle_operator(operator_stack: &mut Vec<Token>, output: &mut Vec<Token>, operator: &Operator) -> anyhow::Result<()> {
-	use crate::expression::operators::Op;
+// Operands and prefix operators must not follow an operand, binary operators, ')' and ','
+// must. Returns the message and the span to point at.
+fn check_order(last: &Token, last_span: Span, token: &Token, span: Span) -> Option<(String, Span)> {
+	let follows_operand = lexer::is_operand(last);
+	match token {
+		Token::Operator(Operator::BinaryOperator(bop)) if !follows_operand =>
+			return Some((format!("missing operand before '{}'", bop.get_token()), span)),
+		Token::Operator(Operator::BinaryOperator(_)) | Token::NoToken => return None,
+		Token::RightParen | Token::Comma => match last {
+			Token::Operator(op) => return Some((format!("missing operand after '{}'", op.get_token()), last_span)),
+			_ => return None,
+		},
+		_ if follows_operand => return Some((format!("missing operator before '{}'", token.stringify()), span)),
+		_ => return None,
+	}
+}
 
-	while let Some(top) = operator_stack.last() {
+fn handle_operator(operator_stack: &mut Vec<(Token, Span)>, output: &mut Vec<Token>, operator: &Operator) -> anyhow::Result<()> {
+	while let Some((top, _)) = operator_stack.last() {
 		match top {
 			Token::LeftParen => break,
 			Token::Operator(top_operator) => {
 				let p = top_operator.get_precedence();
 				let q = operator.get_precedence();
 				if (p > q) ||(p == q && operator.get_is_left_associative()) {
-					output.push(operator_stack.pop().unwrap());
+					output.push(operator_stack.pop().unwrap().0);
 				} else {
 					break;
 				}
@@ -92,30 +184,132 @@ fn handle_operator(operator_stack: &mut Vec<Token>, output: &mut Vec<Token>, ope
 	return Ok(());
 }
 
-fn handle_rparen(operator_stack: &mut Vec<Token>, output: &mut Vec<Token>) -> anyhow::Result<()> {
+fn handle_rparen(operator_stack: &mut Vec<(Token, Span)>, output: &mut Vec<Token>) -> anyhow::Result<()> {
 	// Move from operator_stack to output untill we meet a (
-	if !shift_until(operator_stack, output, &Token::LeftParen) {
-		return Err(anyhow::anyhow!("missmatched parenthesis"));
+	if shift_until(operator_stack, output, &Token::LeftParen).is_none() {
+		return Err(anyhow::anyhow!("missmatched parenthesis, ')' has no matching '('"));
 	}
+	return Ok(());
+}
 
-	if let Some(top) = operator_stack.last() {
-		if let Token::Function(_) = top {
-			output.push(top.clone());
-			operator_stack.pop();
-		}
+// Pops the called function and checks the number of arguments counted in its frame. Variadic
+// functions are output with the actual number of arguments they were called with.
+fn handle_call(operator_stack: &mut Vec<(Token, Span)>, output: &mut Vec<Token>, frame: &CallFrame) -> Result<(), (anyhow::Error, Span)> {
+	let (func, func_span) = match operator_stack.pop() {
+		Some((Token::Function(func), span)) => (func, span),
+		_ => panic!("call frame without a function on the operator stack"),
+	};
+
+	if frame.n_commas > 0 && !frame.has_value {
+		return Err((anyhow::anyhow!("missing function argument before ')'"), func_span));
+	}
+	let n_args = if frame.has_value { frame.n_commas + 1 } else { 0 };
+
+	if !func.accepts_n_inputs(n_args) {
+		let expected = if func.is_variadic() {
+			format!("at least {}", func.get_n_inputs())
+		} else {
+			format!("{}", func.get_n_inputs())
+		};
+		return Err((anyhow::anyhow!("function '{}' takes {} arguments but {} were given", func.get_token(), expected, n_args), func_span));
 	}
 
+	output.push(Token::Function(func.called_with(n_args as u8)));
 	return Ok(());
 }
 
-fn shift_until(operator_stack: &mut Vec<Token>, output: &mut Vec<Token>, stop: &Token) -> bool {
-	while let Some(token) = operator_stack.pop() {
+// Returns the span of the stop token if it was found
+fn shift_until(operator_stack: &mut Vec<(Token, Span)>, output: &mut Vec<Token>, stop: &Token) -> Option<Span> {
+	while let Some((token, span)) = operator_stack.pop() {
 		if token.eq(stop) {
-			return true;
+			return Some(span);
 		}
 		output.push(token);
 	}
-	return false;
+	return None;
 }
 
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Token, Context, functions::Function, varnum::Variable, diagnostics::SpanError};
+	use super::{shunt, stringify_rpn};
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		context.add_variable(Variable::new("z")).unwrap();
+		context.add_function(Function::new("f", 2)).unwrap();
+		context.add_function(Function::new("g", 2)).unwrap();
+		context.add_function(Function::new("now", 0)).unwrap();
+		return context;
+	}
+
+	fn rpn(expr: &str) -> String {
+		return stringify_rpn(&shunt(expr, &context()).unwrap());
+	}
+
+	fn n_inputs(expr: &str) -> Vec<u8> {
+		return shunt(expr, &context()).unwrap().iter()
+			.filter_map(|token| match token {
+				Token::Function(func) => Some(func.get_n_inputs()),
+				_ => None,
+			})
+			.collect();
+	}
+
+	fn error(expr: &str) -> String {
+		let err = shunt(expr, &context()).unwrap_err();
+		return err.downcast_ref::<SpanError>().unwrap().to_string();
+	}
+
+	#[test]
+	fn missing_operand() {
+		assert_eq!(error("x+"), "missing operand after '+' at 1:2\nx+\n ^");
+		assert_eq!(error("*x"), "missing operand before '*' at 1:1\n*x\n^");
+		assert_eq!(error("(x *) + y"), "missing operand after '*' at 1:4\n(x *) + y\n   ^");
+		assert_eq!(error("max(x, y -)"), "missing operand after '-' at 1:10\nmax(x, y -)\n         ^");
+	}
 
+	#[test]
+	fn missing_operator() {
+		assert_eq!(error("x y"), "missing operator before 'y' at 1:3\nx y\n  ^");
+		assert_eq!(error("2 x"), "missing operator before 'x' at 1:3\n2 x\n  ^");
+		assert_eq!(error("2x"), "unrecognized identifier '2x' at 1:1\n2x\n^^");
+		assert_eq!(error("(x)(y)"), "missing operator before '(' at 1:4\n(x)(y)\n   ^");
+		assert_eq!(error("x sin(y)"), "missing operator before 'sin' at 1:3\nx sin(y)\n  ^^^");
+	}
+
+	#[test]
+	fn variadic_calls_count_their_arguments() {
+		assert_eq!(rpn("max(x,y,z)"), "x,y,z,max,");
+		assert_eq!(n_inputs("max(x,y,z)"), vec![3]);
+		assert_eq!(n_inputs("max(x,max(y,z,1,2))"), vec![4, 2]);
+		assert_eq!(error("max(x)"), "function 'max' takes at least 2 arguments but 1 were given at 1:1\nmax(x)\n^^^^^^");
+	}
+
+	#[test]
+	fn fixed_arity() {
+		assert_eq!(error("atan2(x)"), "function 'atan2' takes 2 arguments but 1 were given at 1:1\natan2(x)\n^^^^^^^^");
+		assert_eq!(error("atan2(x,y,z)"), "function 'atan2' takes 2 arguments but 3 were given at 1:1\natan2(x,y,z)\n^^^^^^^^^^^^");
+	}
+
+	#[test]
+	fn nested_calls() {
+		assert_eq!(rpn("f(g(x,y),z)"), "x,y,g,z,f,");
+		assert_eq!(rpn("f(x + g(y, 1), -z)"), "x,y,1,g,+,z,-,f,");
+	}
+
+	#[test]
+	fn empty_argument_list() {
+		assert_eq!(rpn("now() + x"), "now,x,+,");
+		assert_eq!(n_inputs("now()"), vec![0]);
+		assert_eq!(error("f()"), "function 'f' takes 2 arguments but 0 were given at 1:1\nf()\n^^^");
+	}
+
+	#[test]
+	fn trailing_comma() {
+		assert_eq!(error("f(x,)"), "missing function argument before ')' at 1:1\nf(x,)\n^^^^^");
+		assert_eq!(error("f(,x)"), "missing function argument before ',' at 1:3\nf(,x)\n  ^");
+	}
+}
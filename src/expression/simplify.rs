@@ -0,0 +1,244 @@
+use num_complex::Complex64;
+use tch::Tensor;
+
+use crate::expression::{
+	Context,
+	ast::Expr,
+	derivative,
+	functions::Function,
+	operators::Op,
+	varnum::Number,
+};
+
+/// Simplifies bottom-up: folds constant subtrees, removes identities and annihilators
+/// (`x*1`, `x+0`, `x*0`, `x^1`, ...), removes double negations and writes floats that
+/// hold an integer as integers. Operators whose rules were replaced are left as they are,
+/// and folding a complex literal gives a complex literal.
+pub fn simplify(expr: &Expr, context: &Context) -> anyhow::Result<Expr> {
+	match expr {
+		Expr::Number(num) => return Ok(Expr::Number(flint(num, context))),
+		Expr::Constant(_) | Expr::Zero | Expr::Unity | Expr::Variable(_) => return Ok(expr.clone()),
+		Expr::UnaryOperator(uop, arg) => {
+			let arg = simplify(arg, context)?;
+			if !uop.is_builtin() {
+				return Ok(Expr::UnaryOperator(uop.clone(), Box::new(arg)));
+			}
+			if let Some(value) = constant(&arg).and_then(|value| fold_unary(uop.get_token(), value)) {
+				return Ok(number(value, is_complex(&arg), context));
+			}
+			match uop.get_token() {
+				"-" => return derivative::neg(context, arg),
+				_ => return Ok(Expr::UnaryOperator(uop.clone(), Box::new(arg))),
+			}
+		},
+		Expr::BinaryOperator(bop, lhs, rhs) => {
+			let lhs = simplify(lhs, context)?;
+			let rhs = simplify(rhs, context)?;
+			if !bop.is_builtin() {
+				return Ok(Expr::BinaryOperator(bop.clone(), Box::new(lhs), Box::new(rhs)));
+			}
+			if let (Some(l), Some(r)) = (constant(&lhs), constant(&rhs)) {
+				if let Some(value) = fold_binary(bop.get_token(), l, r) {
+					return Ok(number(value, is_complex(&lhs) || is_complex(&rhs), context));
+				}
+			}
+			match bop.get_token() {
+				"+" => return derivative::add(context, lhs, rhs),
+				"-" => return derivative::sub(context, lhs, rhs),
+				"*" => return derivative::mul(context, lhs, rhs),
+				"/" => return derivative::div(context, lhs, rhs),
+				"^" => return derivative::pow(context, lhs, rhs),
+				_ => return Ok(Expr::BinaryOperator(bop.clone(), Box::new(lhs), Box::new(rhs))),
+			}
+		},
+		Expr::Function(func, args) => {
+			let args = args.iter()
+				.map(|arg| simplify(arg, context))
+				.collect::<anyhow::Result<Vec<Expr>>>()?;
+			let values: Option<Vec<Complex64>> = args.iter().map(constant).collect();
+			if let Some(value) = values.and_then(|values| fold_function(func, &values)) {
+				return Ok(number(value, args.iter().any(is_complex), context));
+			}
+			return Ok(Expr::Function(func.clone(), args));
+		},
+	}
+}
+
+// As flint() did after the former sympy round-trip, floats holding an integer are written as integers
+fn flint(num: &Number, context: &Context) -> Number {
+	let value = num.get_value();
+	if value.re.fract() == 0.0 && value.im.fract() == 0.0 {
+		return literal(value, num.is_complex(), context);
+	}
+	return num.clone();
+}
+
+fn constant(expr: &Expr) -> Option<Complex64> {
+	match expr {
+		Expr::Number(num) => return Some(num.get_value()),
+		Expr::Constant(constant) => return constant.get_scalar(),
+		Expr::Zero => return Some(Complex64::new(0.0, 0.0)),
+		Expr::Unity => return Some(Complex64::new(1.0, 0.0)),
+		_ => return None,
+	}
+}
+
+fn is_complex(expr: &Expr) -> bool {
+	match expr {
+		Expr::Number(num) => return num.is_complex(),
+		Expr::Constant(constant) => return constant.get_scalar().map_or(false, |value| value.im != 0.0),
+		_ => return false,
+	}
+}
+
+fn literal(value: Complex64, is_complex: bool, context: &Context) -> Number {
+	if is_complex {
+		return Number::from_complex(value, context.get_imaginary_units());
+	}
+	return Number::from_value(value, context.get_imaginary_units());
+}
+
+fn number(value: Complex64, is_complex: bool, context: &Context) -> Expr {
+	return Expr::Number(literal(value, is_complex, context));
+}
+
+// Folds are skipped, by returning None, whenever the result would not be finite
+fn finite(value: Complex64) -> Option<Complex64> {
+	if value.is_finite() {
+		return Some(value);
+	}
+	return None;
+}
+
+fn fold_unary(token: &str, arg: Complex64) -> Option<Complex64> {
+	match token {
+		"-" => return finite(-arg),
+		_ => return None,
+	}
+}
+
+fn fold_binary(token: &str, lhs: Complex64, rhs: Complex64) -> Option<Complex64> {
+	match token {
+		"+" => return finite(lhs + rhs),
+		"-" => return finite(lhs - rhs),
+		"*" => return finite(lhs * rhs),
+		"/" => {
+			if rhs == Complex64::new(0.0, 0.0) {
+				return None;
+			}
+			return finite(lhs / rhs);
+		},
+		"^" => {
+			if lhs.im == 0.0 && rhs.im == 0.0 {
+				// A negative real base with a fractional exponent is NaN for real tensors, leave it be
+				if lhs.re < 0.0 && rhs.re.fract() != 0.0 {
+					return None;
+				}
+				return finite(Complex64::new(lhs.re.powf(rhs.re), 0.0));
+			}
+			return finite(lhs.powc(rhs));
+		},
+		_ => return None,
+	}
+}
+
+// Functions are folded with the tch mapping they carry, on 0-dimensional tensors
+fn fold_function(func: &Function, args: &[Complex64]) -> Option<Complex64> {
+	let args: Vec<Tensor> = args.iter().map(|arg| {
+		if arg.im == 0.0 {
+			return Tensor::from(arg.re);
+		}
+		return Tensor::complex(&Tensor::from(arg.re), &Tensor::from(arg.im));
+	}).collect();
+
+	let result = func.evaluate(&args).ok()?;
+	if result.numel() != 1 {
+		return None;
+	}
+	if result.is_complex() {
+		let parts = result.view_as_real();
+		return finite(Complex64::new(parts.double_value(&[0]), parts.double_value(&[1])));
+	}
+	return finite(Complex64::new(result.double_value(&[]), 0.0));
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, ast::Expr, shunter, operators::BinaryOperator, varnum::Variable};
+	use super::simplify;
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		return context;
+	}
+
+	fn simplified(expr: &str, context: &Context) -> Expr {
+		let expr = Expr::from_rpn(&shunter::shunt(expr, context).unwrap()).unwrap();
+		return simplify(&expr, context).unwrap();
+	}
+
+	fn rpn(expr: &str) -> String {
+		return shunter::stringify_rpn(&simplified(expr, &context()).to_rpn());
+	}
+
+	#[test]
+	fn identities() {
+		assert_eq!(rpn("x*1"), "x,");
+		assert_eq!(rpn("1*x + 0"), "x,");
+		assert_eq!(rpn("x - 0"), "x,");
+		assert_eq!(rpn("0 - x"), "x,-,");
+		assert_eq!(rpn("x/1"), "x,");
+		assert_eq!(rpn("x^1"), "x,");
+		assert_eq!(rpn("x^0"), "Unity,");
+		assert_eq!(rpn("x*0 + y"), "y,");
+		assert_eq!(rpn("-(-x)"), "x,");
+	}
+
+	#[test]
+	fn folding() {
+		assert_eq!(rpn("2*3 + 1"), "7,");
+		assert_eq!(rpn("1/4*x"), "0.25,x,*,");
+		assert_eq!(rpn("2.0*x"), "2,x,*,");
+		assert_eq!(rpn("2^-1"), "0.5,");
+		assert_eq!(rpn("2i*3"), "6i,");
+	}
+
+	#[test]
+	fn folding_keeps_complex_literals() {
+		let context = context();
+		match simplified("2i*2i", &context) {
+			Expr::Number(num) => assert!(num.is_complex() && num.get_value().re == -4.0),
+			other => panic!("{:?} is not a literal", other),
+		}
+		match simplified("0i*1", &context) {
+			Expr::Number(num) => assert!(num.is_complex() && num.get_value().re == 0.0),
+			other => panic!("{:?} is not a literal", other),
+		}
+		assert_eq!(rpn("0i*x"), "0i,x,*,");
+		assert_eq!(rpn("x + 0i"), "x,0i,+,");
+	}
+
+	#[test]
+	fn no_change() {
+		assert_eq!(rpn("x*y + 2"), "x,y,*,2,+,");
+		assert_eq!(rpn("x/0"), "x,0,/,");
+		assert_eq!(rpn("1/0"), "1,0,/,");
+		assert_eq!(rpn("(-8)^(1/3)"), "-8,0.3333333333333333,^,");
+		assert_eq!(rpn("x < 1 + 1"), "x,2,<,");
+	}
+
+	// An operator with its own rules gets none of the arithmetic identities
+	#[test]
+	fn replaced_operators_are_left_alone() {
+		let mut context = context();
+		let plus = context.get_binary_operator("+").unwrap().clone()
+			.with_eval(|args| Ok(args[0].f_mul(&args[1])?));
+		context.override_binary_operator(plus).unwrap();
+		let rpn = |expr: &str| shunter::stringify_rpn(&simplified(expr, &context).to_rpn());
+		assert_eq!(rpn("x + 0"), "x,0,+,");
+		assert_eq!(rpn("1 + 2"), "1,2,+,");
+		assert_eq!(rpn("x*1 + y"), "x,y,+,");
+	}
+}
@@ -0,0 +1,115 @@
+use std::f64::consts::{E, PI};
+
+use num_complex::Complex64;
+
+use crate::expression::{
+	Context,
+	varnum::Constant,
+};
+
+// sympy spelling and the token of the default context it stands for
+const ALIASES: [(&str, &str); 5] = [
+	("**", "^"),
+	("Abs", "abs"),
+	("Max", "max"),
+	("Min", "min"),
+	("ceiling", "ceil"),
+];
+
+/// Extends a context so it reads what sympy's `str` prints, as returned by the Python bridge:
+/// `**` for powers, the constants `E`, `pi`, `oo`, `zoo` and `nan`, and the sympy spellings of
+/// functions such as `Abs`, `Max` and `Heaviside`. `I` already is one of the default imaginary
+/// units. The sympy spellings are aliases, shunted output holds the default tokens.
+pub fn add_syntax(context: &mut Context) -> anyhow::Result<()> {
+	for (spelling, token) in ALIASES.iter() {
+		context.add_alias(spelling, token)?;
+	}
+
+	context.add_constant(Constant::new("E", Complex64::new(E, 0.0)))?;
+	context.add_constant(Constant::new("pi", Complex64::new(PI, 0.0)))?;
+	context.add_constant(Constant::new("oo", Complex64::new(f64::INFINITY, 0.0)))?;
+	// Complex infinity, an infinite modulus without a direction
+	context.add_constant(Constant::new("zoo", Complex64::new(f64::INFINITY, f64::NAN)))?;
+	context.add_constant(Constant::new("nan", Complex64::new(f64::NAN, 0.0)))?;
+
+	// sympy's Heaviside is 1/2 at 0
+	context.define_function("Heaviside(x) = (1 + sign(x))/2")?;
+
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use std::f64::consts::{E, PI};
+
+	use num_complex::Complex64;
+
+	use crate::expression::{Context, shunter, scalar::ScalarProgram, varnum::Variable};
+
+	fn context() -> Context {
+		let mut context = Context::sympy();
+		context.add_variable(Variable::new("x")).unwrap();
+		context.add_variable(Variable::new("y")).unwrap();
+		return context;
+	}
+
+	fn rpn(expr: &str) -> String {
+		return shunter::stringify_rpn(&shunter::shunt(expr, &context()).unwrap());
+	}
+
+	fn evaluate(expr: &str, x: f64, y: f64) -> f64 {
+		let context = context();
+		let rpn = shunter::shunt(expr, &context).unwrap();
+		return ScalarProgram::<f64>::compile(&rpn, &context).unwrap().evaluate(&[x, y]).unwrap();
+	}
+
+	#[test]
+	fn aliases() {
+		assert_eq!(rpn("x**2"), "x,2,^,");
+		assert_eq!(rpn("Abs(x)"), "x,abs,");
+		assert_eq!(rpn("Max(x, y, 1)"), "x,y,1,max,");
+		assert_eq!(rpn("Min(x, y)"), "x,y,min,");
+		assert_eq!(rpn("ceiling(x)"), "x,ceil,");
+		// The default spellings stay
+		assert_eq!(rpn("x^2 + abs(y)"), "x,2,^,y,abs,+,");
+	}
+
+	#[test]
+	fn constants() {
+		let context = context();
+		let value = |name: &str| context.get_constant(name).unwrap().get_scalar().unwrap();
+		assert_eq!(value("E"), Complex64::new(E, 0.0));
+		assert_eq!(value("pi"), Complex64::new(PI, 0.0));
+		assert_eq!(value("oo"), Complex64::new(f64::INFINITY, 0.0));
+		assert!(value("zoo").re.is_infinite() && value("zoo").im.is_nan());
+		assert!(value("nan").re.is_nan() && value("nan").im == 0.0);
+		assert_eq!(evaluate("2*pi + E", 0.0, 0.0), 2.0*PI + E);
+		assert_eq!(evaluate("-oo", 0.0, 0.0), f64::NEG_INFINITY);
+		assert!(evaluate("nan*x", 1.0, 0.0).is_nan());
+	}
+
+	#[test]
+	fn heaviside_is_half_at_zero() {
+		assert_eq!(evaluate("Heaviside(x)", -2.0, 0.0), 0.0);
+		assert_eq!(evaluate("Heaviside(x)", 0.0, 0.0), 0.5);
+		assert_eq!(evaluate("Heaviside(x)", 3.0, 0.0), 1.0);
+		assert_eq!(evaluate("Heaviside(x - y)", 1.0, 1.0), 0.5);
+	}
+
+	// As printed by str() of sympy expressions
+	#[test]
+	fn sympy_output() {
+		assert_eq!(rpn("-x**2 + 2*x*y"), "x,2,^,-,2,x,*,y,*,+,");
+		assert_eq!(rpn("x**(-1/2)"), "x,1,-,2,/,^,");
+		assert_eq!(rpn("Abs(x)*Heaviside(y - 1) + Max(0, x)"), "x,abs,y,1,-,Heaviside,*,0,x,max,+,");
+		assert_eq!(rpn("2*I*pi*x"), "2,I,*,pi,*,x,*,");
+		assert_eq!(evaluate("x**2*exp(-y)/ceiling(y)", 3.0, 1.5), 9.0*(-1.5f64).exp()/2.0);
+		assert_eq!(evaluate("Max(x, y)**2 - Min(x, y)", 3.0, -1.0), 10.0);
+		assert_eq!(evaluate("sqrt(Abs(x))*sin(pi*y)", -4.0, 0.5), 2.0);
+
+		let context = context();
+		let rpn = shunter::shunt("exp(I*pi) + 1", &context).unwrap();
+		let value = ScalarProgram::<Complex64>::compile(&rpn, &context).unwrap().evaluate(&[Complex64::new(0.0, 0.0); 2]).unwrap();
+		assert!(value.norm() < 1e-15);
+	}
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use num_complex::Complex64;
+
+use crate::expression::{
+	Token,
+	Context,
+	ast::Expr,
+	cse,
+	definition,
+	operators::Op,
+};
+
+/// Renders a call from its already rendered arguments.
+pub type Render = Arc<dyn Fn(&[String]) -> String + Send + Sync>;
+
+/// A render that substitutes the arguments for `{0}`, `{1}`, ... in `pattern`.
+pub fn template(pattern: &str) -> Render {
+	let pattern = pattern.to_string();
+	return Arc::new(move |args: &[String]| {
+		let mut rendered = pattern.clone();
+		for (i, arg) in args.iter().enumerate() {
+			rendered = rendered.replace(&format!("{{{}}}", i), arg);
+		}
+		rendered
+	});
+}
+
+/// Generates TorchScript source from parsed expressions. Every operator and function is mapped
+/// to its `torch.` equivalent by a render, the defaults cover the default context.
+pub struct TorchScript {
+	unary_operators: HashMap<String, Render>,
+	binary_operators: HashMap<String, Render>,
+	functions: HashMap<String, Render>,
+}
+
+impl TorchScript {
+
+	pub fn new() -> Self {
+		Self {..Default::default()}
+	}
+
+	pub fn set_unary_operator(&mut self, token: &str, render: Render) {
+		self.unary_operators.insert(token.to_string(), render);
+	}
+
+	pub fn set_binary_operator(&mut self, token: &str, render: Render) {
+		self.binary_operators.insert(token.to_string(), render);
+	}
+
+	pub fn set_function(&mut self, token: &str, render: Render) {
+		self.functions.insert(token.to_string(), render);
+	}
+
+	/// A `def forward(...)` taking every variable of the context, in context order, with
+	/// repeated subexpressions assigned to locals. Defined functions are inlined. Tensor
+	/// constants can not be written as literals, they follow the variables as arguments in
+	/// context order and the caller passes their values. The source can be compiled with
+	/// `torch.jit.script` and saved for `tch::CModule::load`.
+	pub fn generate(&self, expr: &Expr, context: &Context) -> anyhow::Result<String> {
+		let expr = definition::inline(expr)?;
+		let mut unknown: Option<String> = None;
+		expr.walk(&mut |node| {
+			if let Expr::Variable(var) = node {
+				if unknown.is_none() && context.get_variable(var.get_token()).is_none() {
+					unknown = Some(var.get_token().to_string());
+				}
+			}
+		});
+		if let Some(unknown) = unknown {
+			return Err(anyhow::anyhow!("variable '{}' is not part of the context", unknown));
+		}
+
+		let eliminated = cse::cse(&expr, context);
+
+		let params: Vec<String> = context.get_variables().iter()
+			.map(|var| var.get_token())
+			.chain(context.get_constants().iter().filter(|constant| constant.get_scalar().is_none()).map(|constant| constant.get_token()))
+			.map(|name| format!("{}: torch.Tensor", name))
+			.collect();
+		let mut source = String::from("import torch\n\n\n");
+		source += &format!("def forward({}) -> torch.Tensor:\n", params.join(", "));
+		for (var, subexpr) in eliminated.temporaries.iter() {
+			source += &format!("    {} = {}\n", var.get_token(), self.render_tensor(subexpr)?);
+		}
+		source += &format!("    return {}\n", self.render_tensor(&eliminated.root)?);
+		return Ok(source);
+	}
+
+	pub fn generate_rpn(&self, rpn: &Vec<Token>, context: &Context) -> anyhow::Result<String> {
+		return self.generate(&Expr::from_rpn(rpn)?, context);
+	}
+
+	// Operators on literals alone give a Python float or bool, which the `-> torch.Tensor`
+	// signature and most torch functions reject. Temporaries and function arguments are
+	// rendered the same way, so variables are tensors.
+	fn render_tensor(&self, expr: &Expr) -> anyhow::Result<String> {
+		if !expr.is_leaf() && !is_tensor(expr) {
+			return Ok(format!("torch.tensor({})", self.render(expr, false)?));
+		}
+		return self.render(expr, expr.is_leaf());
+	}
+
+	fn render(&self, expr: &Expr, as_tensor: bool) -> anyhow::Result<String> {
+		let literal = |value: Complex64| {
+			if as_tensor {
+				return format!("torch.tensor({})", python_literal(value));
+			}
+			return python_literal(value);
+		};
+		match expr {
+			Expr::Number(num) => return Ok(literal(num.get_value())),
+			Expr::Constant(constant) => {
+				match constant.get_scalar() {
+					Some(value) => return Ok(literal(value)),
+					None => return Ok(constant.get_token().to_string()),
+				}
+			},
+			Expr::Zero => return Ok(literal(Complex64::new(0.0, 0.0))),
+			Expr::Unity => return Ok(literal(Complex64::new(1.0, 0.0))),
+			Expr::Variable(var) => return Ok(var.get_token().to_string()),
+			Expr::UnaryOperator(uop, arg) => {
+				let render = lookup(&self.unary_operators, uop.get_token(), "unary operator")?;
+				return Ok(render(&[self.render(arg, false)?]));
+			},
+			Expr::BinaryOperator(bop, lhs, rhs) => {
+				let render = lookup(&self.binary_operators, bop.get_token(), "binary operator")?;
+				return Ok(render(&[self.render(lhs, false)?, self.render(rhs, false)?]));
+			},
+			Expr::Function(func, args) => {
+				let render = lookup(&self.functions, func.get_token(), "function")?;
+				let args = args.iter()
+					.map(|arg| self.render_tensor(arg))
+					.collect::<anyhow::Result<Vec<String>>>()?;
+				return Ok(render(&args));
+			},
+		}
+	}
+
+}
+
+impl Default for TorchScript {
+
+	fn default() -> Self {
+		let mut generator = TorchScript {
+			unary_operators: HashMap::new(),
+			binary_operators: HashMap::new(),
+			functions: HashMap::new(),
+		};
+
+		generator.set_unary_operator("-", template("(-{0})"));
+		generator.set_unary_operator("!", template("torch.logical_not({0})"));
+
+		generator.set_binary_operator("^", template("torch.pow({0}, {1})"));
+		for token in ["*", "/", "+", "-", "<", "<=", ">", ">=", "==", "!="] {
+			generator.set_binary_operator(token, template(&format!("({{0}} {} {{1}})", token)));
+		}
+		generator.set_binary_operator("&&", template("torch.logical_and({0}, {1})"));
+		generator.set_binary_operator("||", template("torch.logical_or({0}, {1})"));
+
+		for token in ["sin", "cos", "tan", "exp", "log", "log10", "sqrt", "abs", "sinh", "cosh", "tanh",
+			"asin", "acos", "atan", "sign", "floor", "ceil", "round"]
+		{
+			generator.set_function(token, template(&format!("torch.{}({{0}})", token)));
+		}
+		generator.set_function("atan2", template("torch.atan2({0}, {1})"));
+		generator.set_function("pow", template("torch.pow({0}, {1})"));
+		generator.set_function("max", Arc::new(|args: &[String]| fold("torch.maximum", args)));
+		generator.set_function("min", Arc::new(|args: &[String]| fold("torch.minimum", args)));
+		generator.set_function("sum", Arc::new(|args: &[String]| format!("({})", args.join(" + "))));
+		generator.set_function("clamp", template("torch.minimum(torch.maximum({0}, {1}), {2})"));
+		generator.set_function("where", template("torch.where(torch.ne({0}, 0), {1}, {2})"));
+
+		return generator;
+	}
+
+}
+
+fn lookup<'a>(renders: &'a HashMap<String, Render>, token: &str, kind: &str) -> anyhow::Result<&'a Render> {
+	match renders.get(token) {
+		Some(render) => return Ok(render),
+		None => return Err(anyhow::anyhow!("no TorchScript equivalent for {} '{}'", kind, token)),
+	}
+}
+
+fn is_tensor(expr: &Expr) -> bool {
+	match expr {
+		Expr::Variable(_) | Expr::Function(_, _) => return true,
+		Expr::Constant(constant) => return constant.get_scalar().is_none(),
+		_ => return expr.children().into_iter().any(is_tensor),
+	}
+}
+
+// Left to right, max(a,b,c) is torch.maximum(torch.maximum(a, b), c) as in the evaluator
+fn fold(call: &str, args: &[String]) -> String {
+	let mut acc = args[0].clone();
+	for arg in args[1..].iter() {
+		acc = format!("{}({}, {})", call, acc, arg);
+	}
+	return acc;
+}
+
+fn python_float(value: f64) -> String {
+	if value.is_nan() {
+		return String::from("float('nan')");
+	}
+	if value.is_infinite() {
+		return String::from(if value > 0.0 { "float('inf')" } else { "float('-inf')" });
+	}
+	// Debug formatting always keeps a '.' or an exponent, so the literal stays a float
+	return format!("{:?}", value);
+}
+
+fn python_literal(value: Complex64) -> String {
+	if value.im == 0.0 {
+		return python_float(value.re);
+	}
+	if value.re == 0.0 {
+		return format!("{}j", python_float(value.im));
+	}
+	return format!("complex({}, {})", python_float(value.re), python_float(value.im));
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, ast, varnum::Variable};
+	use super::TorchScript;
+
+	fn generate(expr: &str) -> String {
+		let mut context = Context::new();
+		context.add_variable(Variable::new("X")).unwrap();
+		context.add_variable(Variable::new("Y")).unwrap();
+		let expr = ast::build(expr, &context).unwrap();
+		return TorchScript::new().generate(&expr, &context).unwrap();
+	}
+
+	#[test]
+	fn golden_source() {
+		assert_eq!(generate("sin(X)*max(X, Y, 2) + sin(X)^2"), "\
+import torch
+
+
+def forward(X: torch.Tensor, Y: torch.Tensor) -> torch.Tensor:
+    x0 = torch.sin(X)
+    return ((x0 * torch.maximum(torch.maximum(X, Y), torch.tensor(2.0))) + torch.pow(x0, 2.0))
+");
+	}
+
+	#[test]
+	fn literal_root_is_a_tensor() {
+		assert!(generate("2").ends_with("    return torch.tensor(2.0)\n"));
+		assert!(generate("2*3").ends_with("    return torch.tensor((2.0 * 3.0))\n"));
+		assert!(generate("-1 < 2").ends_with("    return torch.tensor(((-1.0) < 2.0))\n"));
+		assert!(generate("X").ends_with("    return X\n"));
+		assert!(generate("exp(1)*2").ends_with("    return (torch.exp(torch.tensor(1.0)) * 2.0)\n"));
+		assert!(generate("max(1+2, X)").ends_with("    return torch.maximum(torch.tensor((1.0 + 2.0)), X)\n"));
+		assert!(generate("sin(-1)").ends_with("    return torch.sin(torch.tensor((-1.0)))\n"));
+		assert!(generate("sin(X+1)").ends_with("    return torch.sin((X + 1.0))\n"));
+	}
+}
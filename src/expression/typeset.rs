@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use num_complex::Complex64;
+
+use crate::expression::{
+	Token,
+	ast::Expr,
+	operators::{Op, Operator, needs_parens},
+	torchscript::{Render, template},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Markup {
+	/// Math mode LaTeX, without the surrounding `$`.
+	Latex,
+	/// A presentation MathML `<math>` element.
+	MathMl,
+}
+
+// Greek letter names with their LaTeX command and code point
+const GREEK: [(&str, &str, &str); 35] = [
+	("alpha", "\\alpha", "\u{3B1}"), ("beta", "\\beta", "\u{3B2}"), ("gamma", "\\gamma", "\u{3B3}"),
+	("delta", "\\delta", "\u{3B4}"), ("epsilon", "\\epsilon", "\u{3B5}"), ("zeta", "\\zeta", "\u{3B6}"),
+	("eta", "\\eta", "\u{3B7}"), ("theta", "\\theta", "\u{3B8}"), ("iota", "\\iota", "\u{3B9}"),
+	("kappa", "\\kappa", "\u{3BA}"), ("lambda", "\\lambda", "\u{3BB}"), ("mu", "\\mu", "\u{3BC}"),
+	("nu", "\\nu", "\u{3BD}"), ("xi", "\\xi", "\u{3BE}"), ("omicron", "o", "\u{3BF}"),
+	("pi", "\\pi", "\u{3C0}"), ("rho", "\\rho", "\u{3C1}"), ("sigma", "\\sigma", "\u{3C3}"),
+	("tau", "\\tau", "\u{3C4}"), ("upsilon", "\\upsilon", "\u{3C5}"), ("phi", "\\phi", "\u{3C6}"),
+	("chi", "\\chi", "\u{3C7}"), ("psi", "\\psi", "\u{3C8}"), ("omega", "\\omega", "\u{3C9}"),
+	("Gamma", "\\Gamma", "\u{393}"), ("Delta", "\\Delta", "\u{394}"), ("Theta", "\\Theta", "\u{398}"),
+	("Lambda", "\\Lambda", "\u{39B}"), ("Xi", "\\Xi", "\u{39E}"), ("Pi", "\\Pi", "\u{3A0}"),
+	("Sigma", "\\Sigma", "\u{3A3}"), ("Upsilon", "\\Upsilon", "\u{3A5}"), ("Phi", "\\Phi", "\u{3A6}"),
+	("Psi", "\\Psi", "\u{3A8}"), ("Omega", "\\Omega", "\u{3A9}"),
+];
+
+// How a typeset operand behaves next to an operator
+enum Shape {
+	Atom,
+	Fraction,
+	/// A negative number, it reads as a unary minus.
+	Signed,
+	/// A complex number with a real part, it reads as a sum.
+	Sum,
+	Operator(Operator),
+}
+
+/// Typesets parsed expressions as LaTeX or MathML for reports. `/` is set as a fraction and
+/// `^` as a superscript, the other operators as symbols. Parentheses are placed from the
+/// precedence and associativity of the operators, and only where they are needed.
+///
+/// Variable and constant names are set from their spelling, `alpha` as the Greek letter and
+/// `T2` or `T_max` with a subscript, unless given a display name in the chosen markup.
+pub struct Typesetter {
+	markup: Markup,
+	display_names: HashMap<String, String>,
+	unary_operators: HashMap<String, String>,
+	binary_operators: HashMap<String, String>,
+	functions: HashMap<String, Render>,
+}
+
+impl Typesetter {
+
+	pub fn latex() -> Self {
+		let mut typesetter = Typesetter::empty(Markup::Latex);
+
+		typesetter.set_unary_operator("-", "-");
+		typesetter.set_unary_operator("!", "\\lnot ");
+
+		for (token, symbol) in [("*", "\\cdot"), ("+", "+"), ("-", "-"), ("<", "<"), ("<=", "\\leq"),
+			(">", ">"), (">=", "\\geq"), ("==", "="), ("!=", "\\neq"), ("&&", "\\land"), ("||", "\\lor")]
+		{
+			typesetter.set_binary_operator(token, symbol);
+		}
+
+		for token in ["sin", "cos", "tan", "exp", "log", "sinh", "cosh", "tanh", "max", "min"] {
+			typesetter.set_function(token, latex_call(&format!("\\{}", token)));
+		}
+		for token in ["asin", "acos", "atan"] {
+			typesetter.set_function(token, latex_call(&format!("\\arc{}", &token[1..])));
+		}
+		typesetter.set_function("log10", latex_call("\\log_{10}"));
+		typesetter.set_function("sqrt", template("\\sqrt{{0}}"));
+		typesetter.set_function("abs", template("\\left|{0}\\right|"));
+		typesetter.set_function("floor", template("\\left\\lfloor {0} \\right\\rfloor"));
+		typesetter.set_function("ceil", template("\\left\\lceil {0} \\right\\rceil"));
+
+		return typesetter;
+	}
+
+	pub fn mathml() -> Self {
+		let mut typesetter = Typesetter::empty(Markup::MathMl);
+
+		typesetter.set_unary_operator("-", "&#x2212;");
+		typesetter.set_unary_operator("!", "&#xAC;");
+
+		for (token, symbol) in [("*", "&#x22C5;"), ("+", "+"), ("-", "&#x2212;"), ("<", "&lt;"), ("<=", "&#x2264;"),
+			(">", "&gt;"), (">=", "&#x2265;"), ("==", "="), ("!=", "&#x2260;"), ("&&", "&#x2227;"), ("||", "&#x2228;")]
+		{
+			typesetter.set_binary_operator(token, symbol);
+		}
+
+		typesetter.set_function("asin", mathml_call("<mi>arcsin</mi>"));
+		typesetter.set_function("acos", mathml_call("<mi>arccos</mi>"));
+		typesetter.set_function("atan", mathml_call("<mi>arctan</mi>"));
+		typesetter.set_function("log10", mathml_call("<msub><mi>log</mi><mn>10</mn></msub>"));
+		typesetter.set_function("sqrt", template("<msqrt>{0}</msqrt>"));
+		typesetter.set_function("abs", template("<mrow><mo>|</mo>{0}<mo>|</mo></mrow>"));
+		typesetter.set_function("floor", template("<mrow><mo>&#x230A;</mo>{0}<mo>&#x230B;</mo></mrow>"));
+		typesetter.set_function("ceil", template("<mrow><mo>&#x2308;</mo>{0}<mo>&#x2309;</mo></mrow>"));
+
+		return typesetter;
+	}
+
+	fn empty(markup: Markup) -> Self {
+		Self {
+			markup: markup,
+			display_names: HashMap::new(),
+			unary_operators: HashMap::new(),
+			binary_operators: HashMap::new(),
+			functions: HashMap::new(),
+		}
+	}
+
+	pub fn get_markup(&self) -> Markup {
+		self.markup
+	}
+
+	/// Sets the variable or constant `token` as `display`, written in the markup of the
+	/// typesetter, e.g. `T_{2}` or `<mi>&#x3C4;</mi>`.
+	pub fn set_display_name(&mut self, token: &str, display: &str) {
+		self.display_names.insert(token.to_string(), display.to_string());
+	}
+
+	pub fn set_unary_operator(&mut self, token: &str, symbol: &str) {
+		self.unary_operators.insert(token.to_string(), symbol.to_string());
+	}
+
+	/// Has no effect for `/` and `^`.
+	pub fn set_binary_operator(&mut self, token: &str, symbol: &str) {
+		self.binary_operators.insert(token.to_string(), symbol.to_string());
+	}
+
+	/// Functions without a render are set upright by name with their arguments in parentheses.
+	pub fn set_function(&mut self, token: &str, render: Render) {
+		self.functions.insert(token.to_string(), render);
+	}
+
+	pub fn typeset(&self, expr: &Expr) -> String {
+		let (body, _) = self.node(expr);
+		match self.markup {
+			Markup::Latex => return body,
+			Markup::MathMl => return format!("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{}</math>", body),
+		}
+	}
+
+	pub fn typeset_rpn(&self, rpn: &Vec<Token>) -> anyhow::Result<String> {
+		return Ok(self.typeset(&Expr::from_rpn(rpn)?));
+	}
+
+	fn node(&self, expr: &Expr) -> (String, Shape) {
+		match expr {
+			Expr::Number(num) => return self.number(num.get_value()),
+			Expr::Constant(constant) => return (self.name(constant.get_token()), Shape::Atom),
+			Expr::Zero => return self.number(Complex64::new(0.0, 0.0)),
+			Expr::Unity => return self.number(Complex64::new(1.0, 0.0)),
+			Expr::Variable(var) => return (self.name(var.get_token()), Shape::Atom),
+			Expr::UnaryOperator(uop, arg) => {
+				let parent = Operator::UnaryOperator(uop.clone());
+				let symbol = self.symbol(&self.unary_operators, uop.get_token());
+				let arg = self.operand(&parent, arg, true);
+				let body = match self.markup {
+					Markup::Latex => format!("{}{}", symbol, arg),
+					Markup::MathMl => format!("<mrow><mo>{}</mo>{}</mrow>", symbol, arg),
+				};
+				return (body, Shape::Operator(parent));
+			},
+			Expr::BinaryOperator(bop, lhs, rhs) => {
+				let parent = Operator::BinaryOperator(bop.clone());
+				if bop.get_token() == "/" {
+					let (numerator, denominator) = (self.node(lhs).0, self.node(rhs).0);
+					let body = match self.markup {
+						Markup::Latex => format!("\\frac{{{}}}{{{}}}", numerator, denominator),
+						Markup::MathMl => format!("<mfrac>{}{}</mfrac>", numerator, denominator),
+					};
+					return (body, Shape::Fraction);
+				}
+				let lhs = self.operand(&parent, lhs, false);
+				if bop.get_token() == "^" {
+					let exponent = self.node(rhs).0;
+					let body = match self.markup {
+						Markup::Latex => format!("{}^{{{}}}", lhs, exponent),
+						Markup::MathMl => format!("<msup>{}{}</msup>", lhs, exponent),
+					};
+					return (body, Shape::Operator(parent));
+				}
+				let symbol = self.symbol(&self.binary_operators, bop.get_token());
+				let rhs = self.operand(&parent, rhs, true);
+				let body = match self.markup {
+					Markup::Latex => format!("{} {} {}", lhs, symbol, rhs),
+					Markup::MathMl => format!("<mrow>{}<mo>{}</mo>{}</mrow>", lhs, symbol, rhs),
+				};
+				return (body, Shape::Operator(parent));
+			},
+			Expr::Function(func, args) => {
+				let args: Vec<String> = args.iter().map(|arg| self.node(arg).0).collect();
+				if let Some(render) = self.functions.get(func.get_token()) {
+					return (render(&args), Shape::Atom);
+				}
+				let render = match self.markup {
+					Markup::Latex => latex_call(&format!("\\operatorname{{{}}}", latex_escape(func.get_token()))),
+					Markup::MathMl => mathml_call(&format!("<mi>{}</mi>", xml_escape(func.get_token()))),
+				};
+				return (render(&args), Shape::Atom);
+			},
+		}
+	}
+
+	fn operand(&self, parent: &Operator, child: &Expr, is_right: bool) -> String {
+		let is_power = matches!(parent, Operator::BinaryOperator(bop) if bop.get_token() == "^");
+		let (body, shape) = self.node(child);
+		let parens = match shape {
+			Shape::Atom => false,
+			Shape::Fraction => is_power,
+			Shape::Signed => is_right || is_power,
+			Shape::Sum => true,
+			// X - (-Y) as for negative numbers, though X - -Y would parse
+			Shape::Operator(Operator::UnaryOperator(_)) if is_right && matches!(parent, Operator::BinaryOperator(_)) => true,
+			Shape::Operator(op) => needs_parens(parent, &op, is_right),
+		};
+		if !parens {
+			return body;
+		}
+		match self.markup {
+			Markup::Latex => return format!("\\left({}\\right)", body),
+			Markup::MathMl => return format!("<mrow><mo>(</mo>{}<mo>)</mo></mrow>", body),
+		}
+	}
+
+	fn symbol(&self, symbols: &HashMap<String, String>, token: &str) -> String {
+		match symbols.get(token) {
+			Some(symbol) => return symbol.clone(),
+			None => match self.markup {
+				Markup::Latex => return latex_escape(token),
+				Markup::MathMl => return xml_escape(token),
+			},
+		}
+	}
+
+	fn name(&self, token: &str) -> String {
+		match self.display_names.get(token) {
+			Some(display) => return display.clone(),
+			None => return self.identifier(token),
+		}
+	}
+
+	fn identifier(&self, token: &str) -> String {
+		let (stem, subscript) = split_name(token);
+		let greek = GREEK.iter().find(|(name, _, _)| *name == stem);
+		match self.markup {
+			Markup::Latex => {
+				let stem = match greek {
+					Some((_, command, _)) => command.to_string(),
+					None if stem.chars().count() == 1 => stem.to_string(),
+					None => format!("\\mathrm{{{}}}", latex_escape(stem)),
+				};
+				match subscript {
+					Some(sub) if sub.chars().count() == 1 || sub.chars().all(|c| c.is_ascii_digit()) =>
+						return format!("{}_{{{}}}", stem, latex_escape(sub)),
+					Some(sub) => return format!("{}_{{\\mathrm{{{}}}}}", stem, latex_escape(sub)),
+					None => return stem,
+				}
+			},
+			Markup::MathMl => {
+				let stem = match greek {
+					Some((_, _, letter)) => format!("<mi>{}</mi>", letter),
+					None => format!("<mi>{}</mi>", xml_escape(stem)),
+				};
+				match subscript {
+					Some(sub) if sub.chars().all(|c| c.is_ascii_digit()) =>
+						return format!("<msub>{}<mn>{}</mn></msub>", stem, sub),
+					Some(sub) => return format!("<msub>{}<mi>{}</mi></msub>", stem, xml_escape(sub)),
+					None => return stem,
+				}
+			},
+		}
+	}
+
+	fn number(&self, value: Complex64) -> (String, Shape) {
+		if value.im == 0.0 {
+			return self.signed(value.re, None);
+		}
+		let (imaginary, shape) = self.signed(value.im, Some(self.imaginary_unit()));
+		if value.re == 0.0 {
+			return (imaginary, shape);
+		}
+		let (real, _) = self.signed(value.re, None);
+		let (sign, imaginary) = if value.im < 0.0 {
+			("-", self.real(-value.im, Some(self.imaginary_unit())))
+		} else {
+			("+", imaginary)
+		};
+		let sign = self.symbol(&self.binary_operators, sign);
+		let body = match self.markup {
+			Markup::Latex => format!("{} {} {}", real, sign, imaginary),
+			Markup::MathMl => format!("<mrow>{}<mo>{}</mo>{}</mrow>", real, sign, imaginary),
+		};
+		return (body, Shape::Sum);
+	}
+
+	fn signed(&self, value: f64, unit: Option<String>) -> (String, Shape) {
+		if value >= 0.0 || value.is_nan() {
+			return (self.real(value, unit), Shape::Atom);
+		}
+		let minus = self.symbol(&self.unary_operators, "-");
+		let body = match self.markup {
+			Markup::Latex => format!("{}{}", minus, self.real(-value, unit)),
+			Markup::MathMl => format!("<mrow><mo>{}</mo>{}</mrow>", minus, self.real(-value, unit)),
+		};
+		return (body, Shape::Signed);
+	}
+
+	fn imaginary_unit(&self) -> String {
+		match self.markup {
+			Markup::Latex => return String::from("\\mathrm{i}"),
+			Markup::MathMl => return String::from("<mi>i</mi>"),
+		}
+	}
+
+	// A non-negative value, times the imaginary unit if there is one
+	fn real(&self, value: f64, unit: Option<String>) -> String {
+		let digits = if value.is_nan() {
+			match self.markup {
+				Markup::Latex => String::from("\\mathrm{NaN}"),
+				Markup::MathMl => String::from("<mi>NaN</mi>"),
+			}
+		} else if value.is_infinite() {
+			match self.markup {
+				Markup::Latex => String::from("\\infty"),
+				Markup::MathMl => String::from("<mi>&#x221E;</mi>"),
+			}
+		} else if value != 0.0 && !(1e-4..1e15).contains(&value) {
+			// 1e-05 is set as 1 x 10^-5
+			let scientific = format!("{:e}", value);
+			let (mantissa, exponent) = scientific.split_once('e').unwrap();
+			match self.markup {
+				Markup::Latex => format!("{} \\times 10^{{{}}}", mantissa, exponent),
+				Markup::MathMl => {
+					let exponent = match exponent.strip_prefix('-') {
+						Some(magnitude) => format!("<mrow><mo>&#x2212;</mo><mn>{}</mn></mrow>", magnitude),
+						None => format!("<mn>{}</mn>", exponent),
+					};
+					format!("<mrow><mn>{}</mn><mo>&#xD7;</mo><msup><mn>10</mn>{}</msup></mrow>", mantissa, exponent)
+				},
+			}
+		} else {
+			match self.markup {
+				Markup::Latex => format!("{}", value),
+				Markup::MathMl => format!("<mn>{}</mn>", value),
+			}
+		};
+		match unit {
+			Some(unit) if value == 1.0 => return unit,
+			Some(unit) => match self.markup {
+				Markup::Latex => return format!("{}{}", digits, unit),
+				Markup::MathMl => return format!("<mrow>{}<mo>&#x2062;</mo>{}</mrow>", digits, unit),
+			},
+			None => return digits,
+		}
+	}
+
+}
+
+fn latex_call(name: &str) -> Render {
+	let name = name.to_string();
+	return Arc::new(move |args: &[String]| format!("{}\\left({}\\right)", name, args.join(", ")));
+}
+
+// `name` is a MathML element, the arguments are separated by commas
+fn mathml_call(name: &str) -> Render {
+	let name = name.to_string();
+	return Arc::new(move |args: &[String]| {
+		format!("<mrow>{}<mo>&#x2061;</mo><mrow><mo>(</mo>{}<mo>)</mo></mrow></mrow>", name, args.join("<mo>,</mo>"))
+	});
+}
+
+// T_max is T with subscript max, T2 is T with subscript 2
+fn split_name(token: &str) -> (&str, Option<&str>) {
+	if let Some((stem, subscript)) = token.split_once('_') {
+		if !stem.is_empty() && !subscript.is_empty() {
+			return (stem, Some(subscript));
+		}
+		return (token, None);
+	}
+	let stem = token.trim_end_matches(|c: char| c.is_ascii_digit());
+	if stem.is_empty() || stem.len() == token.len() {
+		return (token, None);
+	}
+	return (stem, Some(&token[stem.len()..]));
+}
+
+fn latex_escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'_' | '&' | '%' | '$' | '#' | '{' | '}' => {
+				escaped.push('\\');
+				escaped.push(c);
+			},
+			'\\' => escaped += "\\backslash ",
+			'^' => escaped += "\\hat{}",
+			'~' => escaped += "\\sim ",
+			_ => escaped.push(c),
+		}
+	}
+	return escaped;
+}
+
+fn xml_escape(text: &str) -> String {
+	return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::expression::{Context, ast, varnum::Variable};
+	use super::Typesetter;
+
+	fn context() -> Context {
+		let mut context = Context::new();
+		for name in ["a", "b", "c", "x", "alpha_1", "T_max", "T2"] {
+			context.add_variable(Variable::new(name)).unwrap();
+		}
+		return context;
+	}
+
+	fn latex(expr: &str) -> String {
+		return Typesetter::latex().typeset(&ast::build(expr, &context()).unwrap());
+	}
+
+	fn mathml(expr: &str) -> String {
+		return Typesetter::mathml().typeset(&ast::build(expr, &context()).unwrap());
+	}
+
+	// Without the <math> element around it
+	fn mathml_body(expr: &str) -> String {
+		let math = mathml(expr);
+		let body = math.strip_prefix("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">").unwrap();
+		return body.strip_suffix("</math>").unwrap().to_string();
+	}
+
+	#[test]
+	fn division_is_a_fraction() {
+		assert_eq!(latex("(a+b)/c"), "\\frac{a + b}{c}");
+		assert_eq!(mathml_body("a/c"), "<mfrac><mi>a</mi><mi>c</mi></mfrac>");
+	}
+
+	#[test]
+	fn exponents_are_grouped() {
+		assert_eq!(latex("x^(a+1)"), "x^{a + 1}");
+		assert_eq!(latex("(a+b)^2"), "\\left(a + b\\right)^{2}");
+		assert_eq!(latex("(a/b)^2"), "\\left(\\frac{a}{b}\\right)^{2}");
+		assert_eq!(mathml_body("x^a"), "<msup><mi>x</mi><mi>a</mi></msup>");
+	}
+
+	#[test]
+	fn only_the_needed_parentheses() {
+		assert_eq!(latex("(a+b)*c"), "\\left(a + b\\right) \\cdot c");
+		assert_eq!(latex("a+b*c"), "a + b \\cdot c");
+		assert_eq!(latex("-(x+1)"), "-\\left(x + 1\\right)");
+		assert_eq!(latex("a-(b-c)"), "a - \\left(b - c\\right)");
+		assert_eq!(latex("(a-b)-c"), "a - b - c");
+	}
+
+	#[test]
+	fn names() {
+		assert_eq!(latex("alpha_1"), "\\alpha_{1}");
+		assert_eq!(latex("T_max"), "T_{\\mathrm{max}}");
+		assert_eq!(latex("T2"), "T_{2}");
+		assert_eq!(mathml_body("alpha_1"), "<msub><mi>\u{3B1}</mi><mn>1</mn></msub>");
+	}
+
+	#[test]
+	fn display_names() {
+		let mut typesetter = Typesetter::latex();
+		typesetter.set_display_name("T_max", "T_{\\star}");
+		let expr = ast::build("T_max*a", &context()).unwrap();
+		assert_eq!(typesetter.typeset(&expr), "T_{\\star} \\cdot a");
+	}
+
+	#[test]
+	fn mathml_is_escaped() {
+		assert_eq!(mathml_body("a < b && b < c"), "<mrow><mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow><mo>&#x2227;</mo><mrow><mi>b</mi><mo>&lt;</mo><mi>c</mi></mrow></mrow>");
+	}
+}
@@ -1,4 +1,9 @@
 
+use std::sync::{Arc, Mutex};
+
+use num_complex::Complex64;
+use tch::Tensor;
+
 use crate::expression::{
     Context,
     Token,
@@ -21,51 +26,325 @@ impl Variable {
 
 }
 
+/// How a bounded parameter is written in terms of an unconstrained value `u`, with `l` and
+/// `h` the lower and upper bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// x = u, without bounds.
+    Identity,
+    /// x = l + exp(u), `l` defaults to 0.
+    Log,
+    /// x = l + log(1 + exp(u)), `l` defaults to 0.
+    Softplus,
+    /// x = l + (h - l) * sigmoid(u), the bounds default to 0 and 1.
+    Logit,
+    /// x = l + (h - l) * (sin(u) + 1) / 2, the bounds default to 0 and 1.
+    Box,
+}
+
+impl Transform {
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Transform::Identity => "identity",
+            Transform::Log => "log",
+            Transform::Softplus => "softplus",
+            Transform::Logit => "logit",
+            Transform::Box => "box",
+        }
+    }
+
+    pub fn from_name(name: &str) -> anyhow::Result<Transform> {
+        match name {
+            "identity" => Ok(Transform::Identity),
+            "log" => Ok(Transform::Log),
+            "softplus" => Ok(Transform::Softplus),
+            "logit" => Ok(Transform::Logit),
+            "box" => Ok(Transform::Box),
+            _ => Err(anyhow::anyhow!("unknown transform '{}'", name)),
+        }
+    }
+
+}
+
+/// A variable whose value is fitted to data rather than given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    variable: Variable,
+    lower: Option<f64>,
+    upper: Option<f64>,
+    transform: Transform,
+}
+
+impl Parameter {
+
+    pub fn new(token: &str) -> Self {
+        Self {variable: Variable::new(token), lower: None, upper: None, transform: Transform::Identity}
+    }
+
+    pub fn with_bounds(mut self, lower: Option<f64>, upper: Option<f64>) -> Self {
+        self.lower = lower;
+        self.upper = upper;
+        self
+    }
+
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    pub fn get_token(&self) -> &str {
+        self.variable.get_token()
+    }
+
+    pub fn get_variable(&self) -> &Variable {
+        &self.variable
+    }
+
+    pub fn get_transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// The bounds with the defaults of the transform filled in.
+    pub fn get_bounds(&self) -> (Option<f64>, Option<f64>) {
+        match self.transform {
+            Transform::Identity => (self.lower, self.upper),
+            Transform::Log | Transform::Softplus => (Some(self.lower.unwrap_or(0.0)), self.upper),
+            Transform::Logit | Transform::Box => (Some(self.lower.unwrap_or(0.0)), Some(self.upper.unwrap_or(1.0))),
+        }
+    }
+
+    /// Checks that the transform enforces exactly the bounds that were given.
+    pub fn check(&self) -> anyhow::Result<()> {
+        let (lower, upper) = self.get_bounds();
+        match self.transform {
+            Transform::Identity if lower.is_some() || upper.is_some() => {
+                return Err(anyhow::anyhow!("parameter '{}' has bounds but no transform to enforce them", self.get_token()));
+            },
+            Transform::Log | Transform::Softplus if upper.is_some() => {
+                return Err(anyhow::anyhow!("the {:?} transform of parameter '{}' only supports a lower bound", self.transform, self.get_token()));
+            },
+            _ => {},
+        }
+        for bound in [lower, upper].iter().flatten() {
+            if !bound.is_finite() {
+                return Err(anyhow::anyhow!("parameter '{}' has a bound that is not finite", self.get_token()));
+            }
+        }
+        if let (Some(lower), Some(upper)) = (lower, upper) {
+            if lower >= upper {
+                return Err(anyhow::anyhow!("parameter '{}' has lower bound {} not below upper bound {}", self.get_token(), lower, upper));
+            }
+        }
+        Ok(())
+    }
+
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Number {
     token: String,
+    value: Complex64,
+    imaginary: bool,
 }
 
 impl Number {
 
-    pub fn new(token: &str) -> Self {
-        Self {token: token.to_string()}
+    pub fn new(token: &str, value: Complex64) -> Self {
+        Self {token: token.to_string(), value: value, imaginary: value.im != 0.0}
+    }
+
+    pub fn from_real(value: f64) -> Self {
+        Self {token: value.to_string(), value: Complex64::new(value, 0.0), imaginary: false}
+    }
+
+    /// A literal written with an imaginary unit, it is complex even when it is `0i`.
+    pub fn imaginary(token: &str, im: f64) -> Self {
+        Self {token: token.to_string(), value: Complex64::new(0.0, im), imaginary: true}
+    }
+
+    /// Formats `value` as a literal using the first of the context's imaginary units.
+    pub fn from_value(value: Complex64, imaginary_units: &[String]) -> Self {
+        let unit = imaginary_units.first().map(|unit| unit.as_str()).unwrap_or(DEFAULT_IMAGINARY_UNITS[0]);
+        let token = if value.im == 0.0 {
+            value.re.to_string()
+        } else if value.re == 0.0 {
+            format!("{}{}", value.im, unit)
+        } else {
+            format!("{}{:+}{}", value.re, value.im, unit)
+        };
+        Self {token: token, value: value, imaginary: value.im != 0.0}
+    }
+
+    /// As `from_value`, but complex even without an imaginary part, e.g. `0i` or `2+0i`.
+    pub fn from_complex(value: Complex64, imaginary_units: &[String]) -> Self {
+        let mut num = Self::from_value(value, imaginary_units);
+        if value.im == 0.0 {
+            let unit = imaginary_units.first().map(|unit| unit.as_str()).unwrap_or(DEFAULT_IMAGINARY_UNITS[0]);
+            num.token = if value.re == 0.0 { format!("0{}", unit) } else { format!("{}+0{}", value.re, unit) };
+        }
+        num.imaginary = true;
+        num
     }
 
     pub fn get_token(&self) -> &str {
         &self.token
     }
 
+    pub fn get_value(&self) -> Complex64 {
+        self.value
+    }
+
+    pub fn is_complex(&self) -> bool {
+        self.imaginary || self.value.im != 0.0
+    }
+
+}
+
+#[derive(Debug, Clone)]
+pub enum ConstantValue {
+    Scalar(Complex64),
+    /// A Tensor is Send but not Sync, the mutex lets expressions holding it be shared
+    /// between threads like the rest of the context.
+    Tensor(Arc<Mutex<Tensor>>),
+}
+
+/// A name with a fixed value, such as `pi`, a physical constant or a vector of b-values.
+/// Scalar constants are folded and inlined like literals, tensor constants are known to
+/// the tensor evaluator only.
+#[derive(Debug, Clone)]
+pub struct Constant {
+    token: String,
+    value: ConstantValue,
 }
 
-pub (super) fn begins_with_variable<'a>(expr: &str, _tokens: &Vec<Token>, context: &'a Context) -> Option<&'a Variable> {
-    for var in context.variables.iter() {
-        if expr.starts_with(&var.token) {
-            return Some(var);
+impl Constant {
+
+    pub fn new(token: &str, value: Complex64) -> Self {
+        Self {token: token.to_string(), value: ConstantValue::Scalar(value)}
+    }
+
+    pub fn from_real(token: &str, value: f64) -> Self {
+        Self::new(token, Complex64::new(value, 0.0))
+    }
+
+    pub fn from_tensor(token: &str, tensor: Tensor) -> Self {
+        Self {token: token.to_string(), value: ConstantValue::Tensor(Arc::new(Mutex::new(tensor)))}
+    }
+
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn get_value(&self) -> &ConstantValue {
+        &self.value
+    }
+
+    pub fn get_scalar(&self) -> Option<Complex64> {
+        match &self.value {
+            ConstantValue::Scalar(value) => Some(*value),
+            ConstantValue::Tensor(_) => None,
+        }
+    }
+
+    /// A shallow clone of the tensor, sharing its storage.
+    pub fn get_tensor(&self) -> Option<Tensor> {
+        match &self.value {
+            ConstantValue::Scalar(_) => None,
+            ConstantValue::Tensor(tensor) => Some(tensor.lock().unwrap().shallow_clone()),
         }
     }
-    return None;
+
 }
 
+// Tensor constants are identified by their name, scalar ones by name and value
+impl PartialEq for Constant {
+    fn eq(&self, other: &Self) -> bool {
+        if self.token != other.token {
+            return false;
+        }
+        match (&self.value, &other.value) {
+            (ConstantValue::Scalar(a), ConstantValue::Scalar(b)) => a == b,
+            (ConstantValue::Tensor(_), ConstantValue::Tensor(_)) => true,
+            _ => false,
+        }
+    }
+}
 
-extern crate lazy_static;
+pub const DEFAULT_IMAGINARY_UNITS: [&str; 2] = ["i", "I"];
 
-lazy_static::lazy_static! {
-	pub (super) static ref GLOBAL_REGEX: fancy_regex::Regex = {
-		let reg = fancy_regex::Regex::new(
-            r"^(?=[iI.\d+-])([+-]?(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?(?![iI.\d]))?([+-]?(?:(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?)?[iI])?");
-		return reg.unwrap()
-	};
+pub (super) fn number_regex(imaginary_units: &[String]) -> anyhow::Result<fancy_regex::Regex> {
+    if imaginary_units.is_empty() || imaginary_units.iter().any(|unit| unit.is_empty()) {
+        return Err(anyhow::anyhow!("imaginary units must be non-empty strings"));
+    }
+    let unit = imaginary_units.iter()
+        .map(|unit| fancy_regex::escape(unit).into_owned())
+        .collect::<Vec<String>>()
+        .join("|");
+    // Either a real or an imaginary literal, 3+4i is the sum of two literals. Literals are
+    // unsigned, a sign is always an operator so -2^2 is -(2^2).
+    let reg = fancy_regex::Regex::new(&format!(
+        r"^(?=(?:{unit})|[.\d])(?:({real})(?!(?:{unit})|[.\deE])|((?:{real})?(?:{unit})))",
+        real = r"(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?",
+        unit = unit));
+    return Ok(reg?);
 }
 
-pub (super) fn begins_with_number(expr: &str, _tokens: &Vec<Token>, _context: &Context) -> Option<Number> {
-    let result = GLOBAL_REGEX.find(expr);
+pub (super) fn begins_with_number(expr: &str, _tokens: &Vec<Token>, context: &Context) -> Option<Number> {
+    let result = context.number_regex.captures(expr);
     assert!(result.is_ok());
-    let match_option = result.unwrap();
-    if match_option.is_some() {
-        let m = match_option.unwrap();
-        //println!("match: {}", m.as_str());
-        return Some(Number{token: m.as_str().to_string() });
-    }
-    return None;
-}
\ No newline at end of file
+    let caps = result.unwrap()?;
+    let m = caps.get(0)?;
+    if m.as_str().is_empty() {
+        return None;
+    }
+    //println!("match: {}", m.as_str());
+    if let Some(real) = caps.get(1) {
+        let value = Complex64::new(real.as_str().parse::<f64>().ok()?, 0.0);
+        return Some(Number::new(m.as_str(), value));
+    }
+    let imag = caps.get(2)?.as_str();
+    let unit = context.imaginary_units.iter().find(|unit| imag.ends_with(unit.as_str()))?;
+    let im = match &imag[..imag.len() - unit.len()] {
+        "" => 1.0,
+        coeff => coeff.parse::<f64>().ok()?,
+    };
+    return Some(Number::imaginary(m.as_str(), im));
+}
+
+#[cfg(test)]
+mod tests {
+    use num_complex::Complex64;
+
+    use crate::expression::{Token, Context, shunter};
+
+    fn numbers(expr: &str, context: &Context) -> Vec<(String, Complex64, bool)> {
+        let rpn = shunter::shunt(expr, context).unwrap();
+        return rpn.iter().filter_map(|token| match token {
+            Token::Number(num) => Some((num.get_token().to_string(), num.get_value(), num.is_complex())),
+            _ => None,
+        }).collect();
+    }
+
+    #[test]
+    fn imaginary_literals() {
+        let context = Context::new();
+        assert_eq!(numbers("2.5i", &context), vec![(String::from("2.5i"), Complex64::new(0.0, 2.5), true)]);
+        assert_eq!(numbers("1e-3I", &context), vec![(String::from("1e-3I"), Complex64::new(0.0, 1e-3), true)]);
+        assert_eq!(numbers("0i", &context), vec![(String::from("0i"), Complex64::new(0.0, 0.0), true)]);
+    }
+
+    #[test]
+    fn complex_sum_is_not_one_literal() {
+        let context = Context::new();
+        let rpn = shunter::shunt("2*3+4i", &context).unwrap();
+        assert_eq!(shunter::stringify_rpn(&rpn), "2,3,*,4i,+,");
+    }
+
+    #[test]
+    fn configured_imaginary_unit() {
+        let mut context = Context::new();
+        context.set_imaginary_units(&["j"]).unwrap();
+        assert_eq!(numbers("3j", &context), vec![(String::from("3j"), Complex64::new(0.0, 3.0), true)]);
+    }
+}
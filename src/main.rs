@@ -30,142 +30,61 @@ fn main() {
 */
 
 
-mod expression;
-
-use crate::expression::{
+use torch_compute::expression::{
     varnum::Variable,
     Context,
-    shunter, operators::{Operator, Op},
+    shunter,
+    evaluator,
+    ast,
+    cse,
+    simplify,
 };
 
-use std::time::{Duration, Instant};
+use tch::Tensor;
 
-use cpython::{Python, PyDict, PyList, PyString, PyResult, ToPyObject, py_capsule};
+use std::collections::HashMap;
+use std::time::Instant;
 
 fn test_shunting() {
 	
     let expr = "sin(X)*max(X,Y)+cos(sin(X)*cos(Y))+cos(Y)*cos(Y)";
     let mut context: Context = Context::default();
-    context.add_variable(Variable::new("X"));
-	context.add_variable(Variable::new("Y"));
+    context.add_variable(Variable::new("X")).unwrap();
+	context.add_variable(Variable::new("Y")).unwrap();
     let rpn = shunter::shunt(expr, &context);
     println!("full rpn notation: {:?}", rpn);
 	if let Ok(rpn) = rpn {
 		let rpnstr = shunter::stringify_rpn(&rpn);
 		println!("rpn notation: {}", rpnstr);
 
-		let mut pairs: Vec<(u32, String)> = vec![];
-		
-		for i in 0..rpn.len() {
-			if rpn[i].stringify().eq("Y") {
-				if let Some(next) = rpn.iter().nth(i+1) {
-					match next {
-						expression::Token::Operator(op) => {
-							match op {
-								Operator::UnaryOperator(unop) => {
-									pairs.push((i.try_into().unwrap(), unop.get_token().to_owned() + "Y"));
-								}
-								_ => {},
-							}
-						},
-						expression::Token::Function(func) => {
-							if func.get_n_inputs().eq(&1) {
-								pairs.push((i.try_into().unwrap(), func.get_token().to_owned() + "Y"));
-							}
-						}
-						_ => {},
-					}
-				}
-			}
-		}
-
-		println!("Y Leaf pairs: {:?}", pairs);
+		let tree = ast::Expr::from_rpn(&rpn).unwrap();
+		assert!(tree.to_rpn().eq(&rpn));
 
-		let mut pairs: Vec<(u32, String)> = vec![];
+		let simplified = simplify::simplify(&tree, &context).unwrap();
+		println!("simplified rpn: {}", shunter::stringify_rpn(&simplified.to_rpn()));
 
-		for i in 0..rpn.len() {
-			if rpn[i].stringify().eq("X") {
-				if let Some(next) = rpn.iter().nth(i+1) {
-					match next {
-						expression::Token::Operator(op) => {
-							match op {
-								Operator::UnaryOperator(unop) => {
-									pairs.push((i.try_into().unwrap(), unop.get_token().to_owned() + "X"));
-								}
-								_ => {},
-							}
-						},
-						expression::Token::Function(func) => {
-							if func.get_n_inputs().eq(&1) {
-								pairs.push((i.try_into().unwrap(), func.get_token().to_owned() + "X"));
-							}
-						}
-						_ => {},
-					}
-				}
-			}
+		let eliminated = cse::cse(&simplified, &context);
+		for (var, subexpr) in eliminated.temporaries.iter() {
+			println!("{} = {}", var.get_token(), shunter::stringify_rpn(&subexpr.to_rpn()));
+		}
+		println!("root = {}", shunter::stringify_rpn(&eliminated.root.to_rpn()));
+
+		let mut variables: HashMap<String, Tensor> = HashMap::new();
+		variables.insert("X".to_owned(), Tensor::of_slice(&[0.0f64, 0.5, 1.0]));
+		variables.insert("Y".to_owned(), Tensor::of_slice(&[1.0f64, 0.5, 0.0]));
+		match evaluator::evaluate_cse(&eliminated, &context, &variables) {
+			Ok(result) => result.print(),
+			Err(err) => println!("evaluation failed: {}", err),
 		}
-
-		println!("Y Leaf pairs: {:?}", pairs);
-
 
 	}
     
 }
 
 fn main() {
-	/*
-	let gil = Python::acquire_gil();
-    run_expr(gil.python()).unwrap();
-	*/
-
-	
 	let now = Instant::now();
 	test_shunting();
 	println!("Time elapsed: {}", now.elapsed().as_millis());
 	
 
 }
-
-fn run_expr(py: Python) -> PyResult<()> {
-    
-	let now = Instant::now();
-
-	let sys = py.import("sys")?;
-
-	let mut sys_path: Vec<String> = sys.get(py, "path")?.extract(py)?;
-	sys_path.insert(0, "./pyth/".into());
-	sys.add(py, "path", sys_path);
-
-	let module = py.import("expr")?;
-
-	let pyexpr = PyString::new(py, "1.0*sin(x)^2+2*cos(x)^2+1.045e-6+I+exp(I)+tan(sin(x))-exp(tan(sin(x)))+6*tanh(exp(sin(x)))");
-
-	let symbols: PyList = vec!["x", "y"].to_py_object(py);
-
-	let symsdict: PyDict = module.call(py, "get_syms", (symbols,), None)?.extract(py)?;
-
-	let exprout: String = module.call(py, "get_expr", (pyexpr,symsdict,), None, )?.extract(py)?;
-	
-	println!("Time elapsed: {}", now.elapsed().as_millis());
-	println!("Expr: {}", exprout);
-
-
-	// Second time
-	let now = Instant::now();
-
-	let pyexpr = PyString::new(py, "1.0*sin(x)^2+2*cos(x)^2+1.045e-6+I+exp(I)+tan(sin(x))-exp(tan(sin(x)))+6*tanh(exp(sin(x)))");
-
-	let symbols: PyList = vec!["x", "y"].to_py_object(py);
-
-	let symsdict: PyDict = module.call(py, "get_syms", (symbols,), None)?.extract(py)?;
-
-	let exprout: String = module.call(py, "get_expr", (pyexpr,symsdict,), None, )?.extract(py)?;
-	
-	println!("Time elapsed: {}", now.elapsed().as_millis());
-	println!("Expr: {}", exprout);
-	
-
-
-	Ok(())
-}
\ No newline at end of file